@@ -0,0 +1,46 @@
+use chrono::{DateTime, Utc};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use metadata_fix::media::{self, App1Placement, ExifFields, GpsLocation, IptcFields, JpegFields, XmpFields};
+
+const LOCATION: GpsLocation = GpsLocation { latitude: 48.858_370, longitude: 2.294_481, altitude: Some(35.0), accuracy: None, dop: None };
+
+fn datetime() -> DateTime<Utc> {
+    DateTime::from_timestamp(1_600_000_000, 0).unwrap()
+}
+
+/// SOI, a JFIF APP0, an old EXIF APP1 and `scan_len` bytes of scan data. The
+/// rewrite never decodes the image, so the scan can be filler.
+fn synthetic_jpeg(scan_len: usize) -> Vec<u8> {
+    let mut jpeg = vec![0xFF, 0xD8];
+    jpeg.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x10]);
+    jpeg.extend_from_slice(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+    jpeg.extend_from_slice(&[0xFF, 0xE1, 0x00, 0x0A]);
+    jpeg.extend_from_slice(b"Exif\0\0\0\0");
+    jpeg.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02]);
+    jpeg.resize(jpeg.len() + scan_len, 0x55);
+    jpeg.extend_from_slice(&[0xFF, 0xD9]);
+    jpeg
+}
+
+fn bench_create_exif_data(c: &mut Criterion) {
+    c.bench_function("create_exif_data", |b| {
+        b.iter(|| media::create_exif_data(black_box(Some(LOCATION)), black_box(datetime()), &ExifFields::default()).unwrap())
+    });
+}
+
+fn bench_jpeg_rewrite(c: &mut Criterion) {
+    let jpeg = synthetic_jpeg(4 * 1024 * 1024);
+    let xmp = XmpFields::default();
+    c.bench_function("update_jpeg_metadata_bytes 4MiB", |b| {
+        b.iter(|| {
+            media::update_jpeg_metadata_bytes(
+                black_box(&jpeg), Some(LOCATION), datetime(),
+                JpegFields { exif: &ExifFields::default(), xmp: &xmp, iptc: &IptcFields::default(), placement: App1Placement::default() },
+            ).unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, bench_create_exif_data, bench_jpeg_rewrite);
+criterion_main!(benches);
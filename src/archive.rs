@@ -0,0 +1,419 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+
+use flate2::read::GzDecoder;
+
+use crate::control::{RunControl, RunState};
+use crate::gpx::TrackPoint;
+use crate::hash;
+use crate::runlog::Outcome;
+use crate::media::{self, create_xmp_sidecar, read_existing_metadata};
+use crate::resolve;
+use crate::{
+    album_name, apply_metadata_bytes, catch_file_panic, check_not_truncated, claim_flat_output, converts_to_jpeg,
+    describe_changes, jpeg_name, load_finished, log_outcome, merge_existing, parse_sidecar, set_album, skips_trashed,
+    stamp_file_time, strict_violations, xmp_sidecar_path, MediaFormat, MediaMetadata, Note, OutputMode, Phase,
+    ProcessMessage, RunContext, Strategy,
+};
+
+/// Takeout archives we can read directly without extracting them first.
+pub fn is_archive(path: &Path) -> bool {
+    let name = path.to_string_lossy().to_lowercase();
+    path.is_file() && (name.ends_with(".zip") || name.ends_with(".tgz") || name.ends_with(".tar.gz"))
+}
+
+struct PendingMedia {
+    sidecar: PathBuf,
+    media_name: String,
+    metadata: MediaMetadata,
+    notes: Vec<Note>,
+}
+
+/// Processes every sidecar inside a `.zip` or `.tgz` export, writing the fixed
+/// media into `output_dir` at the same relative path it has in the archive.
+///
+/// The archive is read in two sequential passes so tarballs, which can't be
+/// seeked, work the same as zips: the first collects the sidecars and every
+/// entry's name, the second streams the media they point at. Media names are
+/// matched as for a folder, see [`resolve::resolve_among`]. Returns
+/// `(processed, errors, skipped)`.
+pub fn process_archive(
+    ctx: &RunContext,
+    control: &RunControl,
+    sender: &mpsc::Sender<ProcessMessage>,
+) -> Result<(usize, usize, usize), String> {
+    let RunContext { input_dir: archive_path, output_dir, options, track, .. } = ctx;
+
+    let _ = sender.send(ProcessMessage::Phase(Phase::Scanning));
+    let _ = sender.send(ProcessMessage::Status("🔍 Scanning archive...".to_string()));
+
+    let mut parsed = Vec::new();
+    let mut names_by_dir: HashMap<String, Vec<String>> = HashMap::new();
+    let mut sidecars = Vec::new();
+    let mut processed_count = 0;
+    let mut error_count = 0;
+    let mut skipped_count = 0;
+
+    let finished = if options.resume_from_log { load_finished(ctx)? } else { HashSet::new() };
+    let mut resumed_count = 0;
+
+    for_each_entry(archive_path, control, |name, reader| {
+        if !name.to_lowercase().ends_with(".json") {
+            let (dir, file_name) = split_entry(name);
+            names_by_dir.entry(dir.to_string()).or_default().push(file_name.to_string());
+            return Ok(());
+        }
+        if finished.contains(&archive_path.join(name)) {
+            resumed_count += 1;
+            return Ok(());
+        }
+
+        let mut json_bytes = Vec::new();
+        reader.read_to_end(&mut json_bytes)
+            .map_err(|e| format!("Error reading {} from archive: {}", name, e))?;
+
+        let sidecar = archive_path.join(name);
+        sidecars.push(sidecar.clone());
+
+        let mut notes = Vec::new();
+        match parse_sidecar(&json_bytes, options, &*ctx.resolver, None, &mut notes) {
+            Ok((media_name, metadata)) if skips_trashed(&metadata, options, &mut notes) => {
+                skipped_count += 1;
+                for note in &notes {
+                    let _ = sender.send(ProcessMessage::Status(format!("{} {}: {}", note.icon(), media_name, note.text)));
+                }
+                log_outcome(ctx, sender, Outcome::Skipped, &sidecar, None);
+                ctx.count_outcome(Path::new(&media_name), Outcome::Skipped);
+                let _ = sender.send(ProcessMessage::FileSkipped { sidecar, media_name });
+            }
+            Ok((media_name, mut metadata)) => {
+                set_album(&mut metadata, Path::new(name).parent().and_then(album_name), options);
+                parsed.push((name.to_string(), PendingMedia { sidecar, media_name, metadata, notes }));
+            }
+            Err(message) => {
+                error_count += 1;
+                log_outcome(ctx, sender, Outcome::Error, &sidecar, Some(&message));
+                ctx.count_outcome(Path::new(name), Outcome::Error);
+                let _ = sender.send(ProcessMessage::FileFailed { sidecar, message });
+            }
+        }
+        Ok(())
+    })?;
+
+    if options.resume_from_log {
+        let _ = sender.send(ProcessMessage::Status(format!("⏩ Resuming: {} files already finished", resumed_count)));
+    }
+
+    // Keyed by the entry each sidecar resolved to; a second sidecar for the
+    // same entry goes unmatched, as in a folder.
+    let mut pending: HashMap<String, PendingMedia> = HashMap::new();
+    let mut missing = Vec::new();
+    for (json_name, mut item) in parsed {
+        let (dir, json_file) = split_entry(&json_name);
+        let names = names_by_dir.get(dir).map(Vec::as_slice).unwrap_or_default();
+        let found = resolve::resolve_among(names, &item.media_name, &mut item.notes).or_else(|| {
+            // As in a folder: the file may have the truncated name that a
+            // `.supplemental-metadata` sidecar's own name carries.
+            let named = resolve::media_name_from_sidecar(json_file).filter(|named| *named != item.media_name)?;
+            let found = resolve::resolve_among(names, &named, &mut item.notes)?;
+            item.notes.push(Note::warning(format!("matched {} from the sidecar's file name", named)));
+            Some(found)
+        });
+        match found.map(|found| sibling_entry(&json_name, found)) {
+            Some(entry) if !pending.contains_key(&entry) => {
+                pending.insert(entry, item);
+            }
+            _ => missing.push((sibling_entry(&json_name, &item.media_name), item)),
+        }
+    }
+
+    let total_files = sidecars.len();
+    let _ = sender.send(ProcessMessage::Status(format!("📊 Found {} JSON files to process", total_files)));
+    let _ = sender.send(ProcessMessage::FilesDiscovered(sidecars));
+    let _ = sender.send(ProcessMessage::Phase(Phase::Processing));
+
+    for_each_entry(archive_path, control, |name, reader| {
+        let Some(item) = pending.remove(name) else {
+            return Ok(());
+        };
+
+        let mut media_bytes = Vec::new();
+        reader.read_to_end(&mut media_bytes)
+            .map_err(|e| format!("Error reading {} from archive: {}", name, e))?;
+
+        let mut notes = item.notes;
+        // Converted PNGs are written as `.jpg`.
+        let output_name = if converts_to_jpeg(&item.media_name, options) { jpeg_name(name) } else { name.to_string() };
+        let output_path = match options.output_mode {
+            OutputMode::Flatten => {
+                let file_name = output_name.rsplit('/').next().unwrap_or(&output_name);
+                (!matches!(file_name, "" | "." | ".."))
+                    .then(|| claim_flat_output(ctx, file_name, &mut notes))
+            }
+            _ => safe_relative_path(&output_name).map(|relative_path| output_dir.join(relative_path)),
+        };
+        let result = catch_file_panic(|| output_path.clone()
+            .ok_or_else(|| format!("Unsafe path in archive: {}", name))
+            .and_then(|output_path| match Strategy::for_name(&item.media_name) {
+                Strategy::InPlace(format) => {
+                    check_not_truncated(Some(format), &media_bytes)?;
+                    let mut metadata = item.metadata.clone();
+                    if options.needs_existing_metadata() {
+                        let existing = read_existing_metadata(&mut Cursor::new(&media_bytes))
+                            .map_err(|e| format!("Error reading existing EXIF: {}", e))?;
+                        match merge_existing(existing, &metadata, options, &mut notes) {
+                            Some(merged) => metadata = merged,
+                            None => return Ok(false),
+                        }
+                        ctx.count_date_change(&existing, &metadata);
+                        if options.dry_run {
+                            notes.push(Note::info(describe_changes(existing, &metadata)));
+                            return Ok(true);
+                        }
+                    }
+
+                    let output_data = apply_metadata_bytes(format, &media_bytes, &metadata, options)?;
+                    ctx.throttle_write(output_data.len() as u64);
+                    write_output(&output_path, &output_data)?;
+                    stamp_file_time(&output_path, metadata.datetime, options, &mut notes);
+                    Ok(true)
+                }
+                Strategy::Sidecar if options.dry_run => check_not_truncated(None, &media_bytes).map(|_| true),
+                Strategy::Sidecar => {
+                    check_not_truncated(None, &media_bytes)?;
+                    let MediaMetadata { location, datetime, xmp, .. } = &item.metadata;
+                    ctx.throttle_write(media_bytes.len() as u64);
+                    write_output(&output_path, &media_bytes)?;
+                    write_output(
+                        &xmp_sidecar_path(&output_path),
+                        create_xmp_sidecar(*location, *datetime, xmp).as_bytes(),
+                    )?;
+                    stamp_file_time(&output_path, *datetime, options, &mut notes);
+                    Ok(true)
+                }
+                Strategy::Skip => {
+                    notes.push(Note::info("format is skipped, nothing written"));
+                    Ok(false)
+                }
+            }))
+            .unwrap_or_else(Err)
+            .and_then(|written| match strict_violations(&notes, options) {
+                Some(message) if written => Err(message),
+                _ => Ok(written),
+            });
+
+        for note in &notes {
+            let _ = sender.send(ProcessMessage::Status(format!("{} {}: {}", note.icon(), item.media_name, note.text)));
+        }
+
+        match result {
+            Ok(true) => {
+                processed_count += 1;
+                ctx.count_processed(&item.metadata);
+                let hash = options.content_hash
+                    .filter(|_| !options.dry_run)
+                    .map(|algorithm| hash::content_hash(algorithm, MediaFormat::detect(name), &media_bytes));
+                log_outcome(ctx, sender, Outcome::Done, &item.sidecar, hash.as_deref());
+                ctx.count_outcome(Path::new(&item.media_name), Outcome::Done);
+                if options.verify_outputs
+                    && let Some(output_path) = &output_path
+                {
+                    ctx.written.lock().unwrap().push(output_path.clone());
+                }
+                if options.export_gpx
+                    && let Some(location) = item.metadata.location
+                {
+                    track.lock().unwrap().push(TrackPoint {
+                        name: item.media_name.clone(),
+                        datetime: item.metadata.datetime,
+                        location,
+                    });
+                }
+                let _ = sender.send(ProcessMessage::FileProcessed {
+                    sidecar: item.sidecar,
+                    media_name: item.media_name,
+                    input_path: archive_path.join(name),
+                    output_path: output_path.unwrap_or_default(),
+                    metadata: Box::new(item.metadata),
+                });
+            }
+            Ok(false) => {
+                skipped_count += 1;
+                log_outcome(ctx, sender, Outcome::Skipped, &item.sidecar, None);
+                ctx.count_outcome(Path::new(&item.media_name), Outcome::Skipped);
+                let _ = sender.send(ProcessMessage::FileSkipped {
+                    sidecar: item.sidecar,
+                    media_name: item.media_name,
+                });
+            }
+            Err(message) => {
+                error_count += 1;
+                log_outcome(ctx, sender, Outcome::Error, &item.sidecar, Some(&message));
+                ctx.count_outcome(Path::new(&item.media_name), Outcome::Error);
+                let _ = sender.send(ProcessMessage::FileFailed { sidecar: item.sidecar, message });
+
+                if let (Some(folder), Some(relative_path)) = (&options.quarantine_folder, safe_relative_path(name))
+                    && let Err(err) = write_output(&output_dir.join(folder).join(relative_path), &media_bytes)
+                {
+                    let _ = sender.send(ProcessMessage::Status(format!("⚠️ {}: {}", name, err)));
+                }
+            }
+        }
+
+        let done = processed_count + error_count + skipped_count;
+        let _ = sender.send(ProcessMessage::Progress(done as f32 / total_files as f32));
+        Ok(())
+    })?;
+
+    if control.state() == RunState::Cancelled {
+        return Ok((processed_count, error_count, skipped_count));
+    }
+
+    for (name, item) in missing.into_iter().chain(pending) {
+        error_count += 1;
+        let message = format!("Image file not found: {}", name);
+        log_outcome(ctx, sender, Outcome::Error, &item.sidecar, Some(&message));
+        ctx.count_outcome(Path::new(&item.media_name), Outcome::Error);
+        let _ = sender.send(ProcessMessage::FileFailed { sidecar: item.sidecar, message });
+    }
+
+    Ok((processed_count, error_count, skipped_count))
+}
+
+/// Calls `f` with the name and contents of every regular file in the
+/// archive, in archive order, stopping early if the run is cancelled.
+fn for_each_entry(
+    archive_path: &Path,
+    control: &RunControl,
+    mut f: impl FnMut(&str, &mut dyn Read) -> Result<(), String>,
+) -> Result<(), String> {
+    let file = File::open(archive_path)
+        .map_err(|e| format!("Could not open archive: {}", e))?;
+
+    if archive_path.to_string_lossy().to_lowercase().ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(file)
+            .map_err(|e| format!("Could not read zip archive: {}", e))?;
+
+        for i in 0..zip.len() {
+            if !control.wait_while_paused() {
+                break;
+            }
+            let mut entry = zip.by_index(i)
+                .map_err(|e| format!("Could not read zip entry: {}", e))?;
+            if entry.is_file() {
+                let name = entry.name().to_string();
+                f(&name, &mut entry)?;
+            }
+        }
+    } else {
+        let mut tar = tar::Archive::new(GzDecoder::new(file));
+        let entries = tar.entries()
+            .map_err(|e| format!("Could not read tar archive: {}", e))?;
+
+        for entry in entries {
+            if !control.wait_while_paused() {
+                break;
+            }
+            let mut entry = entry
+                .map_err(|e| format!("Could not read tar entry: {}", e))?;
+            if entry.header().entry_type().is_file() {
+                let name = entry.path()
+                    .map_err(|e| format!("Invalid tar entry path: {}", e))?
+                    .to_string_lossy()
+                    .into_owned();
+                f(&name, &mut entry)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Archive entries always use `/`: `a/b/IMG.jpg` -> (`a/b`, `IMG.jpg`)
+fn split_entry(name: &str) -> (&str, &str) {
+    name.rsplit_once('/').unwrap_or(("", name))
+}
+
+/// Archive entries always use `/`: `a/b/IMG.jpg.json` + `IMG.jpg` -> `a/b/IMG.jpg`
+fn sibling_entry(json_name: &str, media_name: &str) -> String {
+    match json_name.rfind('/') {
+        Some(i) => format!("{}/{}", &json_name[..i], media_name),
+        None => media_name.to_string(),
+    }
+}
+
+/// Guards against "zip slip": entries with absolute paths or `..` are refused
+/// so nothing is written outside the output directory.
+fn safe_relative_path(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        .then(|| path.to_path_buf())
+}
+
+fn write_output(path: &Path, data: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Error creating output directory: {}", e))?;
+    }
+    media::write_file(&path.to_string_lossy(), data)
+        .map_err(|e| format!("Error writing output file: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::{process_photos, selftest, ProcessOptions};
+
+    fn sidecar(title: &str) -> String {
+        format!(r#"{{"title": "{}", "photoTakenTime": {{"timestamp": "1563096615"}}}}"#, title)
+    }
+
+    /// Each sidecar names its media the way some export misnames it; all of
+    /// them match in a zip as they would in a folder.
+    #[test]
+    fn archive_media_names_resolve_as_in_a_folder() {
+        let root = std::env::temp_dir().join(format!("metadata_fix_archive_names_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let entries = [
+            ("album/IMG_0001.JPG", None),
+            ("album/IMG_0001.jpg.json", Some(sidecar("IMG_0001.jpg"))),
+            ("album/IMG_0002.jpg.jpg", None),
+            ("album/IMG_0002.jpg.json", Some(sidecar("IMG_0002.jpg"))),
+            ("album/Cafe\u{301}.jpg", None),
+            ("album/Caf\u{e9}.jpg.json", Some(sidecar("Caf\u{e9}.jpg "))),
+            ("album/A_long_title_cut.jpg", None),
+            ("album/A_long_title_cut.jpg.supplemental-metadata.json", Some(sidecar("A_long_title_cut_short_by_takeout.jpg"))),
+        ];
+        let archive = root.join("takeout.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        for (name, json) in &entries {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(json.as_ref().map_or(&selftest::tiny_jpeg()[..], |json| json.as_bytes())).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let summary = process_photos(archive, root.join("output"), ProcessOptions::default(), &RunControl::new(), sender)
+            .unwrap();
+        let failures: Vec<String> = receiver.try_iter()
+            .filter_map(|message| match message {
+                ProcessMessage::FileFailed { message, .. } => Some(message),
+                _ => None,
+            })
+            .collect();
+        assert!(failures.is_empty(), "{:?}", failures);
+        assert_eq!((summary.processed, summary.errors), (4, 0));
+        for (name, json) in &entries {
+            if json.is_none() {
+                assert!(root.join("output").join(name).is_file(), "{}", name);
+            }
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+pub const CHECKPOINT_FILE_NAME: &str = "metadata_fix_checkpoint.json";
+
+/// Saved after this many more files are finished.
+const SAVE_EVERY: usize = 100;
+
+/// How far through the sorted sidecar list a run got: every sidecar before
+/// `finished` is done. `total` guards against resuming into a different
+/// list.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub total: usize,
+    pub finished: usize,
+}
+
+pub fn path(dir: &Path) -> PathBuf {
+    dir.join(CHECKPOINT_FILE_NAME)
+}
+
+/// The checkpoint left by an interrupted run, if any. An unreadable one is
+/// treated as missing.
+pub fn load(path: &Path) -> Option<Checkpoint> {
+    let data = std::fs::read(path).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Deletes the checkpoint after a run that got to the end.
+pub fn remove(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+struct Progress {
+    /// Per sidecar from `start` on, whether it is finished.
+    done: Vec<bool>,
+    /// Finished sidecars before the first unfinished one.
+    contiguous: usize,
+    saved: usize,
+}
+
+/// Records finished sidecars as workers complete them, out of order, and
+/// periodically saves how many are finished without a gap.
+pub struct Tracker {
+    path: PathBuf,
+    total: usize,
+    start: usize,
+    progress: Mutex<Progress>,
+}
+
+impl Tracker {
+    /// Tracks sidecars `start..total` of the sorted list.
+    pub fn new(path: PathBuf, total: usize, start: usize) -> Self {
+        Self {
+            path,
+            total,
+            start,
+            progress: Mutex::new(Progress { done: vec![false; total - start], contiguous: 0, saved: 0 }),
+        }
+    }
+
+    /// Marks the sidecar at `index` (counted from `start`) finished.
+    pub fn finish(&self, index: usize) -> std::io::Result<()> {
+        let mut progress = self.progress.lock().unwrap();
+        progress.done[index] = true;
+        while progress.done.get(progress.contiguous) == Some(&true) {
+            progress.contiguous += 1;
+        }
+        if progress.contiguous - progress.saved < SAVE_EVERY {
+            return Ok(());
+        }
+        progress.saved = progress.contiguous;
+
+        let checkpoint = Checkpoint { total: self.total, finished: self.start + progress.contiguous };
+        let temp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&temp_path, serde_json::to_vec(&checkpoint)?)?;
+        std::fs::rename(&temp_path, &self.path)
+    }
+}
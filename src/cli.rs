@@ -0,0 +1,330 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use metadata_fix::control::{RunControl, RunState};
+use metadata_fix::{compare, find_sidecars, process_photos, read_file_list, readiness, schema, selftest, ProcessMessage, ProcessOptions};
+
+/// Every file succeeded (or was skipped).
+const EXIT_OK: u8 = 0;
+/// Bad command line.
+const EXIT_USAGE: u8 = 1;
+/// The run finished but some files errored.
+const EXIT_FILE_ERRORS: u8 = 2;
+/// The run couldn't start, e.g. the output directory couldn't be created.
+const EXIT_FATAL: u8 = 3;
+
+const USAGE: &str = "usage: metadata_fix --input <DIR|ARCHIVE> --output <DIR> [--file-list <FILE>] [--resume] [--dry-run]
+                     [--resume-checkpoint] [--borrow-sibling-date] [--search-whole-tree]
+                     [--strict] [--size-up-front] [--stream] [--include-trashed]
+                     [--lightroom] [--local-time] [--no-file-dates] [--jobs <N>]
+       metadata_fix --analyze-schema --input <DIR>
+       metadata_fix --validate --input <DIR>
+       metadata_fix --self-test
+       metadata_fix --compare <DIR_A> <DIR_B>
+       metadata_fix --serve <ADDR>
+
+  --file-list <FILE>  process only the sidecars listed in FILE, one per line,
+                      relative to the input directory
+  --resume            skip files the run log in the output directory already
+                      records as finished
+  --resume-checkpoint start after the files an interrupted run's checkpoint
+                      covers
+  --dry-run           write nothing; print each file's current date and GPS
+                      next to what would be written
+  --borrow-sibling-date
+                      when a sidecar has no timestamp, use DateTimeOriginal
+                      from a same-named file in its folder
+  --search-whole-tree when a sidecar's media isn't in its folder, look for it
+                      anywhere in the input directory
+  --strict            count files with any warning (fallback location, fuzzy
+                      name match, dropped altitude...) as errors
+  --size-up-front     find and size every file's media before processing, so
+                      progress follows bytes rather than files
+  --stream            start processing while the input is still being
+                      scanned; memory stays flat on huge trees
+  --include-trashed   also process items the sidecar marks as in the trash
+                      (skipped by default)
+  --lightroom         write dates and GPS the way Lightroom reads them:
+                      sub-seconds, UTC offset and XMP dates included
+  --local-time        write dates as local time, the offset estimated from
+                      the longitude (15° per hour; ignores borders and DST)
+  --jobs <N>, -j <N>  process N files at a time (default: one per CPU core)
+  --no-file-dates     leave written files' modified time as the time of the
+                      run instead of the photo's date
+  --analyze-schema    list every JSON field in the export and how many
+                      sidecars have it, without writing anything
+  --validate          check every sidecar has a usable title and timestamp
+                      (and count those with a location), without needing
+                      the media
+  --self-test         write and read back metadata in generated JPEG, PNG and
+                      MP4 files, printing PASS or FAIL for each check
+  --compare           list files that differ between two output directories,
+                      with where the bytes diverge and any date/GPS change
+  --serve             accept POST /process {\"input\", \"output\"}, GET /status
+                      and POST /cancel on ADDR, e.g. 127.0.0.1:8080. There is
+                      no authentication and any client can name any path, so
+                      keep it on loopback (builds with the `server` feature
+                      only)";
+
+/// Runs a batch without the GUI, streaming status to stdout.
+pub fn run(args: &[String]) -> ExitCode {
+    let mut input_dir = None;
+    let mut output_dir = None;
+    let mut file_list = None;
+    let mut resume_from_log = false;
+    let mut resume_from_checkpoint = false;
+    let mut analyze_schema = false;
+    let mut validate = false;
+    let mut dry_run = false;
+    let mut borrow_sibling_timestamp = false;
+    let mut search_whole_tree = false;
+    let mut strict = false;
+    let mut size_up_front = false;
+    let mut stream_scan = false;
+    let mut include_trashed = false;
+    let mut lightroom = false;
+    let mut local_time_from_longitude = false;
+    let mut photo_date_as_file_time = true;
+    let mut jobs = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" | "-i" => input_dir = args.next().map(PathBuf::from),
+            "--output" | "-o" => output_dir = args.next().map(PathBuf::from),
+            "--file-list" => file_list = args.next().map(PathBuf::from),
+            "--resume" => resume_from_log = true,
+            "--resume-checkpoint" => resume_from_checkpoint = true,
+            "--analyze-schema" => analyze_schema = true,
+            "--validate" => validate = true,
+            "--compare" => {
+                return match (args.next(), args.next()) {
+                    (Some(a), Some(b)) => print_comparison(Path::new(a), Path::new(b)),
+                    _ => {
+                        eprintln!("{}", USAGE);
+                        ExitCode::from(EXIT_USAGE)
+                    }
+                };
+            }
+            #[cfg(feature = "server")]
+            "--serve" => {
+                let Some(addr) = args.next() else {
+                    eprintln!("{}", USAGE);
+                    return ExitCode::from(EXIT_USAGE);
+                };
+                return match crate::server::serve(addr) {
+                    Ok(()) => ExitCode::from(EXIT_OK),
+                    Err(e) => {
+                        eprintln!("💥 Fatal error: {}", e);
+                        ExitCode::from(EXIT_FATAL)
+                    }
+                };
+            }
+            "--self-test" => {
+                return ExitCode::from(if selftest::run() { EXIT_OK } else { EXIT_FILE_ERRORS });
+            }
+            "--dry-run" => dry_run = true,
+            "--borrow-sibling-date" => borrow_sibling_timestamp = true,
+            "--search-whole-tree" => search_whole_tree = true,
+            "--strict" => strict = true,
+            "--size-up-front" => size_up_front = true,
+            "--stream" => stream_scan = true,
+            "--include-trashed" => include_trashed = true,
+            "--lightroom" => lightroom = true,
+            "--local-time" => local_time_from_longitude = true,
+            "--no-file-dates" => photo_date_as_file_time = false,
+            "--jobs" | "-j" => match args.next().and_then(|n| n.parse().ok()).filter(|&n: &usize| n > 0) {
+                Some(n) => jobs = Some(n),
+                None => {
+                    eprintln!("--jobs needs a number of threads, at least 1\n{}", USAGE);
+                    return ExitCode::from(EXIT_USAGE);
+                }
+            },
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return ExitCode::from(EXIT_OK);
+            }
+            other => {
+                eprintln!("unknown argument: {}\n{}", other, USAGE);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        }
+    }
+
+    if analyze_schema || validate {
+        return match input_dir {
+            Some(input_dir) if validate => print_readiness(&input_dir),
+            Some(input_dir) => print_schema(&input_dir),
+            None => {
+                eprintln!("{}", USAGE);
+                ExitCode::from(EXIT_USAGE)
+            }
+        };
+    }
+
+    let (Some(input_dir), Some(output_dir)) = (input_dir, output_dir) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(EXIT_USAGE);
+    };
+
+    let mut options = ProcessOptions {
+        resume_from_log,
+        resume_from_checkpoint,
+        dry_run,
+        borrow_sibling_timestamp,
+        search_whole_tree,
+        strict,
+        size_up_front,
+        stream_scan,
+        include_trashed,
+        local_time_from_longitude,
+        photo_date_as_file_time,
+        jobs,
+        ..ProcessOptions::default()
+    };
+    if lightroom {
+        options.apply_lightroom_preset();
+    }
+    if let Some(path) = file_list {
+        match read_file_list(&path) {
+            Ok(files) => options.file_list = Some(files),
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        }
+    }
+
+    let control = Arc::new(RunControl::new());
+    let handler_control = control.clone();
+    let installed = ctrlc::set_handler(move || {
+        // A second Ctrl+C means the user doesn't want to wait for the
+        // current file.
+        if handler_control.state() == RunState::Cancelled {
+            std::process::exit(i32::from(EXIT_FATAL));
+        }
+        eprintln!("⏹ Stopping after the files in progress; press Ctrl+C again to quit now");
+        handler_control.set(RunState::Cancelled);
+    });
+    if let Err(e) = installed {
+        eprintln!("⚠️ Could not install Ctrl+C handler: {}", e);
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let worker = thread::spawn(move || process_photos(input_dir, output_dir, options, &control, sender));
+
+    for msg in receiver {
+        match msg {
+            ProcessMessage::Phase(phase) => println!("▶ {}", phase.label()),
+            ProcessMessage::Progress(_) => {}
+            ProcessMessage::Status(s) => println!("{}", s),
+            ProcessMessage::FilesDiscovered(_) => {}
+            ProcessMessage::FileProcessed { media_name, input_path, output_path, .. } => {
+                println!("✅ {}: {} → {}", media_name, input_path.display(), output_path.display());
+            }
+            ProcessMessage::FileFailed { sidecar, message } => {
+                println!("❌ {}: {}", sidecar.display(), message);
+            }
+            ProcessMessage::FileSkipped { media_name, .. } => println!("⏭ {}", media_name),
+            ProcessMessage::Completed(..) => break,
+            ProcessMessage::Error(e) => {
+                eprintln!("💥 Fatal error: {}", e);
+                return ExitCode::from(EXIT_FATAL);
+            }
+        }
+    }
+
+    let Ok(Ok(summary)) = worker.join() else {
+        // The worker hung up without reporting an outcome.
+        eprintln!("💥 Fatal error: processing stopped unexpectedly");
+        return ExitCode::from(EXIT_FATAL);
+    };
+
+    for (extension, counts) in &summary.per_format_counts {
+        let extension = if extension.is_empty() { "(no extension)".to_string() } else { format!(".{}", extension) };
+        println!(
+            "  {}: {} ok / {} err / {} skipped",
+            extension, counts.processed, counts.errors, counts.skipped
+        );
+    }
+    println!(
+        "processed={} errors={} skipped={} in {:.1}s",
+        summary.processed, summary.errors, summary.skipped, summary.duration.as_secs_f64()
+    );
+    ExitCode::from(if summary.errors > 0 { EXIT_FILE_ERRORS } else { EXIT_OK })
+}
+
+fn print_schema(input_dir: &Path) -> ExitCode {
+    if !input_dir.is_dir() {
+        eprintln!("💥 Fatal error: {} is not a directory", input_dir.display());
+        return ExitCode::from(EXIT_FATAL);
+    }
+
+    let report = schema::analyze(&find_sidecars(input_dir));
+    println!("{} sidecars", report.files);
+    for (path, count) in &report.fields {
+        println!("{:>8}  {:>5.1}%  {}", count, *count as f64 * 100.0 / report.files as f64, path);
+    }
+    for (path, message) in &report.unreadable {
+        println!("❌ {}: {}", path.display(), message);
+    }
+
+    ExitCode::from(EXIT_OK)
+}
+
+fn print_readiness(input_dir: &Path) -> ExitCode {
+    if !input_dir.is_dir() {
+        eprintln!("💥 Fatal error: {} is not a directory", input_dir.display());
+        return ExitCode::from(EXIT_FATAL);
+    }
+
+    let report = readiness::check(&find_sidecars(input_dir));
+    for (path, message) in &report.unreadable {
+        println!("❌ {}: {}", path.display(), message);
+    }
+    for (path, problems) in &report.not_ready {
+        println!("❌ {}: {}", path.display(), problems.join("; "));
+    }
+    println!(
+        "sidecars={} ready={} with_location={} not_ready={} unreadable={}",
+        report.files, report.ready, report.with_location, report.not_ready.len(), report.unreadable.len()
+    );
+
+    let all_ready = report.not_ready.is_empty() && report.unreadable.is_empty();
+    ExitCode::from(if all_ready { EXIT_OK } else { EXIT_FILE_ERRORS })
+}
+
+fn print_comparison(a: &Path, b: &Path) -> ExitCode {
+    let comparison = match compare::compare_trees(a, b) {
+        Ok(comparison) => comparison,
+        Err(e) => {
+            eprintln!("💥 Fatal error: {}", e);
+            return ExitCode::from(EXIT_FATAL);
+        }
+    };
+
+    for path in &comparison.only_in_a {
+        println!("only in {}: {}", a.display(), path.display());
+    }
+    for path in &comparison.only_in_b {
+        println!("only in {}: {}", b.display(), path.display());
+    }
+    for difference in &comparison.differing {
+        println!(
+            "≠ {}: {} vs {} bytes, first difference at byte {}",
+            difference.path.display(), difference.len_a, difference.len_b, difference.first_difference
+        );
+        for change in &difference.metadata_changes {
+            println!("    {}", change);
+        }
+    }
+    println!(
+        "compared={} differing={} only_in_a={} only_in_b={}",
+        comparison.compared, comparison.differing.len(), comparison.only_in_a.len(), comparison.only_in_b.len()
+    );
+
+    let identical = comparison.differing.is_empty() && comparison.only_in_a.is_empty() && comparison.only_in_b.is_empty();
+    ExitCode::from(if identical { EXIT_OK } else { EXIT_FILE_ERRORS })
+}
@@ -0,0 +1,117 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::media::{self, ExistingMetadata};
+use crate::MediaFormat;
+
+/// How two output trees differ.
+#[derive(Debug, Default)]
+pub struct Comparison {
+    pub compared: usize,
+    pub only_in_a: Vec<PathBuf>,
+    pub only_in_b: Vec<PathBuf>,
+    pub differing: Vec<FileDifference>,
+}
+
+#[derive(Debug)]
+pub struct FileDifference {
+    /// Relative to both roots.
+    pub path: PathBuf,
+    pub len_a: usize,
+    pub len_b: usize,
+    /// Offset of the first byte that differs.
+    pub first_difference: usize,
+    /// Date and GPS on each side, for media we can read EXIF from. Empty
+    /// when they agree.
+    pub metadata_changes: Vec<String>,
+}
+
+/// Compares every file under `a` with the file at the same relative path
+/// under `b`, byte for byte and, for JPEG/PNG, by embedded date and GPS.
+pub fn compare_trees(a: &Path, b: &Path) -> Result<Comparison, String> {
+    let files_a = list_files(a)?;
+    let files_b = list_files(b)?;
+
+    let mut comparison = Comparison {
+        only_in_a: files_a.difference(&files_b).cloned().collect(),
+        only_in_b: files_b.difference(&files_a).cloned().collect(),
+        ..Comparison::default()
+    };
+
+    for path in files_a.intersection(&files_b) {
+        comparison.compared += 1;
+        let read = |root: &Path| std::fs::read(root.join(path))
+            .map_err(|e| format!("Could not read {}: {}", root.join(path).display(), e));
+        let (data_a, data_b) = (read(a)?, read(b)?);
+        if data_a == data_b {
+            continue;
+        }
+
+        let first_difference = data_a.iter().zip(&data_b)
+            .position(|(x, y)| x != y)
+            .unwrap_or(data_a.len().min(data_b.len()));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let metadata_changes = match MediaFormat::detect(&name) {
+            Some(_) => metadata_changes(&a.join(path), &b.join(path)),
+            None => Vec::new(),
+        };
+
+        comparison.differing.push(FileDifference {
+            path: path.clone(),
+            len_a: data_a.len(),
+            len_b: data_b.len(),
+            first_difference,
+            metadata_changes,
+        });
+    }
+
+    Ok(comparison)
+}
+
+fn metadata_changes(a: &Path, b: &Path) -> Vec<String> {
+    let read = |path: &Path| -> Result<ExistingMetadata, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        media::read_existing_metadata(&mut BufReader::new(file)).map_err(|e| e.to_string())
+    };
+
+    let (existing_a, existing_b) = match (read(a), read(b)) {
+        (Ok(existing_a), Ok(existing_b)) => (existing_a, existing_b),
+        (Err(e), _) | (_, Err(e)) => return vec![format!("EXIF unreadable: {}", e)],
+    };
+
+    let mut changes = Vec::new();
+    if existing_a.datetime != existing_b.datetime {
+        changes.push(format!("date: {:?} → {:?}", existing_a.datetime, existing_b.datetime));
+    }
+    let coordinates = |existing: &ExistingMetadata| existing.location
+        .map(|location| (location.latitude, location.longitude, location.altitude));
+    if coordinates(&existing_a) != coordinates(&existing_b) {
+        changes.push(format!("GPS: {:?} → {:?}", coordinates(&existing_a), coordinates(&existing_b)));
+    }
+    changes
+}
+
+/// Every file under `root`, relative to it.
+fn list_files(root: &Path) -> Result<BTreeSet<PathBuf>, String> {
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", root.display()));
+    }
+
+    let mut files = BTreeSet::new();
+    let mut dirs_to_check = vec![root.to_path_buf()];
+    while let Some(dir) = dirs_to_check.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|e| format!("Could not read {}: {}", dir.display(), e))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs_to_check.push(path);
+            } else if let Ok(relative) = path.strip_prefix(root) {
+                files.insert(relative.to_path_buf());
+            }
+        }
+    }
+    Ok(files)
+}
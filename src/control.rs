@@ -0,0 +1,52 @@
+use std::sync::{Condvar, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunState {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+/// Run state shared between the GUI and the worker threads. Workers call
+/// [`RunControl::wait_while_paused`] before each file.
+pub struct RunControl {
+    state: Mutex<RunState>,
+    changed: Condvar,
+}
+
+impl Default for RunControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunControl {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(RunState::Running),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn state(&self) -> RunState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn set(&self, state: RunState) {
+        let mut current = self.state.lock().unwrap();
+        // A cancelled run stays cancelled.
+        if *current != RunState::Cancelled {
+            *current = state;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Blocks while the run is paused. Returns `false` once it is cancelled.
+    pub fn wait_while_paused(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while *state == RunState::Paused {
+            state = self.changed.wait(state).unwrap();
+        }
+        *state == RunState::Running
+    }
+}
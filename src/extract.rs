@@ -0,0 +1,278 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::media::GpsLocation;
+
+/// Where the Takeout layouts we know keep the capture time, most trusted
+/// first: the name used in notes, and a JSON pointer to the block holding a
+/// Unix `timestamp` string (or a `timestampMs`). A new layout is supported
+/// by adding a row here.
+pub const TIMESTAMP_PATHS: [(&str, &str); 3] = [
+    ("photoTakenTime", "/photoTakenTime"),
+    ("creationTime", "/creationTime"),
+    // Some exports have neither; the last edit in Google Photos is the best
+    // we can do there.
+    ("photoLastModifiedTime", "/photoLastModifiedTime"),
+];
+
+/// A `timestamp` this large can't be seconds (it would be past the year
+/// 5000), so it is taken as milliseconds, i.e. after 1973.
+const MILLISECONDS_FROM: i64 = 100_000_000_000;
+
+/// Where the layouts we know keep a location block (an object with
+/// `latitude`, `longitude` and optionally `altitude`), most trusted first.
+pub const GEO_PATHS: [(&str, &str); 3] = [
+    ("geoData", "/geoData"),
+    ("geoDataExif", "/geoDataExif"),
+    // Older exports list locations in an array; the first is the capture
+    // location.
+    ("locations", "/locations/0"),
+];
+
+/// Keys some exports use for the horizontal accuracy radius, in meters.
+const ACCURACY_KEYS: [&str; 2] = ["accuracy", "horizontalAccuracy"];
+
+/// Keys some exports use for the receiver's dilution of precision.
+const DOP_KEYS: [&str; 2] = ["dop", "hdop"];
+
+/// Where exports and user-edited sidecars keep lens details, most trusted
+/// first: JSON pointers to the lens make and model strings.
+pub const LENS_PATHS: [(&str, &str); 2] = [
+    ("/device/lensMake", "/device/lensModel"),
+    ("/lensMake", "/lensModel"),
+];
+
+/// Lens make and model from the first place that has either.
+pub fn read_lens(json_data: &Value) -> (Option<String>, Option<String>) {
+    let text = |pointer: &str| json_data.pointer(pointer)?.as_str()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string);
+    LENS_PATHS.iter()
+        .map(|(make, model)| (text(make), text(model)))
+        .find(|(make, model)| make.is_some() || model.is_some())
+        .unwrap_or_default()
+}
+
+/// Capture time from the first layout that has one, with the name of the
+/// layout it came from.
+pub fn read_timestamp(json_data: &Value) -> Result<(&'static str, DateTime<Utc>), String> {
+    let (name, block) = TIMESTAMP_PATHS.iter()
+        .find_map(|(name, pointer)| {
+            let block = json_data.pointer(pointer)?;
+            (block.get("timestampMs").is_some() || block["timestamp"].is_string()).then_some((*name, block))
+        })
+        .ok_or("No timestamp found in JSON")?;
+
+    let datetime = match &block["timestampMs"] {
+        Value::Null => parse_timestamp(block["timestamp"].as_str().unwrap_or_default())?,
+        milliseconds => milliseconds.as_i64()
+            .or_else(|| milliseconds.as_str()?.trim().parse().ok())
+            .and_then(DateTime::<Utc>::from_timestamp_millis)
+            .ok_or("Invalid timestampMs value")?,
+    };
+    Ok((name, datetime))
+}
+
+/// Unix seconds, or milliseconds when the value is too large to be seconds.
+fn parse_timestamp(timestamp: &str) -> Result<DateTime<Utc>, String> {
+    let timestamp: i64 = timestamp.trim().parse()
+        .map_err(|_| "Invalid timestamp format")?;
+
+    let datetime = if timestamp.abs() >= MILLISECONDS_FROM {
+        DateTime::<Utc>::from_timestamp_millis(timestamp)
+    } else {
+        DateTime::<Utc>::from_timestamp(timestamp, 0)
+    };
+    datetime.ok_or_else(|| "Invalid timestamp value".to_string())
+}
+
+/// Location from the first block that has one, with the name of the layout
+/// it came from. Takeout writes a block of zeros when it has no location, so
+/// those don't count. Altitude, accuracy and DOP fall back to a later block
+/// if the chosen one lacks them.
+pub fn read_geo(json_data: &Value) -> Option<(&'static str, GpsLocation)> {
+    let blocks: Vec<(&'static str, &Value, f64, f64)> = GEO_PATHS.iter()
+        .filter_map(|(name, pointer)| {
+            let block = json_data.pointer(pointer)?;
+            let latitude = block["latitude"].as_f64()?;
+            let longitude = block["longitude"].as_f64()?;
+            (latitude != 0.0 || longitude != 0.0).then_some((*name, block, latitude, longitude))
+        })
+        .collect();
+
+    let &(name, _, latitude, longitude) = blocks.first()?;
+    let altitude = blocks.iter().find_map(|(_, block, _, _)| block["altitude"].as_f64());
+    let first_of = |keys: &[&str]| blocks.iter().find_map(|(_, block, _, _)| {
+        keys.iter().find_map(|key| block[*key].as_f64())
+    });
+    let accuracy = first_of(&ACCURACY_KEYS);
+    let dop = first_of(&DOP_KEYS);
+    Some((name, GpsLocation { latitude, longitude, altitude, accuracy, dop }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// 2019-07-14 09:30:15 UTC.
+    const SECONDS: i64 = 1_563_096_615;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn padded_timestamps_are_trimmed() {
+        for padded in [" 1563096615", "1563096615 ", "\t1563096615\n", "  1563096615000  "] {
+            assert_eq!(parse_timestamp(padded), Ok(at(SECONDS)), "{:?}", padded);
+        }
+        let json = json!({"photoTakenTime": {"timestamp": " 1563096615 "}});
+        assert_eq!(read_timestamp(&json), Ok(("photoTakenTime", at(SECONDS))));
+        let json = json!({"photoTakenTime": {"timestampMs": " 1563096615000 "}});
+        assert_eq!(read_timestamp(&json), Ok(("photoTakenTime", at(SECONDS))));
+    }
+
+    #[test]
+    fn unparsable_timestamps_are_errors() {
+        for timestamp in ["", "   ", "1563096615.5", "1.5e9", "15630 96615", "yesterday"] {
+            assert_eq!(parse_timestamp(timestamp), Err("Invalid timestamp format".to_string()), "{:?}", timestamp);
+        }
+        assert!(read_timestamp(&json!({"photoTakenTime": {"timestampMs": "soon"}})).is_err());
+        assert!(read_timestamp(&json!({"title": "IMG_0001.jpg"})).is_err());
+    }
+
+    #[test]
+    fn large_timestamps_are_taken_as_milliseconds() {
+        assert_eq!(parse_timestamp(&SECONDS.to_string()), Ok(at(SECONDS)));
+        assert_eq!(parse_timestamp(&(SECONDS * 1000).to_string()), Ok(at(SECONDS)));
+        assert_eq!(parse_timestamp("1563096615123"), Ok(DateTime::from_timestamp_millis(1_563_096_615_123).unwrap()));
+        assert_eq!(parse_timestamp("-1563096615000"), Ok(at(-SECONDS)));
+    }
+
+    #[test]
+    fn milliseconds_start_at_the_threshold() {
+        let below = MILLISECONDS_FROM - 1;
+        assert_eq!(parse_timestamp(&below.to_string()), Ok(at(below)));
+        assert_eq!(parse_timestamp(&MILLISECONDS_FROM.to_string()), Ok(at(MILLISECONDS_FROM / 1000)));
+        assert_eq!(parse_timestamp(&(-MILLISECONDS_FROM).to_string()), Ok(at(-MILLISECONDS_FROM / 1000)));
+    }
+
+    #[test]
+    fn timestamp_ms_is_always_milliseconds() {
+        // Even a value small enough to pass for seconds.
+        let json = json!({"photoTakenTime": {"timestampMs": SECONDS}});
+        assert_eq!(read_timestamp(&json), Ok(("photoTakenTime", DateTime::from_timestamp_millis(SECONDS).unwrap())));
+        let json = json!({"photoTakenTime": {"timestampMs": SECONDS * 1000, "timestamp": "0"}});
+        assert_eq!(read_timestamp(&json), Ok(("photoTakenTime", at(SECONDS))));
+    }
+
+    /// `json` with `value` placed at a JSON pointer, building objects on the
+    /// way and a one-element array for a `/0` step.
+    fn with_pointer(json: Value, pointer: &str, value: Value) -> Value {
+        let Some(path) = pointer.strip_prefix('/') else {
+            return value;
+        };
+        let (key, rest) = path.find('/').map_or((path, ""), |i| path.split_at(i));
+        if key == "0" {
+            return Value::Array(vec![with_pointer(Value::Null, rest, value)]);
+        }
+        let mut object = match json {
+            Value::Object(object) => object,
+            _ => serde_json::Map::new(),
+        };
+        let child = object.remove(key).unwrap_or(Value::Null);
+        object.insert(key.to_string(), with_pointer(child, rest, value));
+        Value::Object(object)
+    }
+
+    #[test]
+    fn every_timestamp_layout_is_read() {
+        for (name, pointer) in TIMESTAMP_PATHS {
+            let json = with_pointer(Value::Null, pointer, json!({"timestamp": SECONDS.to_string()}));
+            assert_eq!(read_timestamp(&json), Ok((name, at(SECONDS))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn timestamp_layouts_are_tried_in_order() {
+        let json = json!({
+            "photoLastModifiedTime": {"timestamp": "3"},
+            "creationTime": {"timestamp": "2"},
+            "photoTakenTime": {"timestamp": "1"},
+        });
+        assert_eq!(read_timestamp(&json), Ok(("photoTakenTime", at(1))));
+        // A block without a timestamp doesn't stop the search.
+        let json = json!({"photoTakenTime": {"formatted": "Jul 14, 2019"}, "creationTime": {"timestamp": "2"}});
+        assert_eq!(read_timestamp(&json), Ok(("creationTime", at(2))));
+    }
+
+    #[test]
+    fn every_geo_layout_is_read() {
+        for (name, pointer) in GEO_PATHS {
+            let json = with_pointer(Value::Null, pointer, json!({"latitude": 48.85837, "longitude": 2.294481, "altitude": 35.0}));
+            let (found, location) = read_geo(&json).unwrap_or_else(|| panic!("{} not read", name));
+            assert_eq!(found, name);
+            assert_eq!((location.latitude, location.longitude, location.altitude), (48.85837, 2.294481, Some(35.0)));
+        }
+    }
+
+    #[test]
+    fn zero_geo_blocks_fall_through_to_the_next_layout() {
+        let json = json!({
+            "geoData": {"latitude": 0.0, "longitude": 0.0, "altitude": 0.0},
+            "geoDataExif": {"latitude": 1.5, "longitude": 2.5},
+            "locations": [{"latitude": 3.5, "longitude": 4.5, "altitude": 12.0}],
+        });
+        let (name, location) = read_geo(&json).unwrap();
+        assert_eq!((name, location.latitude, location.longitude), ("geoDataExif", 1.5, 2.5));
+        // The zero block's altitude is no location's; the next one with any is.
+        assert_eq!(location.altitude, Some(12.0));
+
+        assert!(read_geo(&json!({"geoData": {"latitude": 0.0, "longitude": 0.0}})).is_none());
+    }
+
+    #[test]
+    fn every_accuracy_and_dop_key_is_read() {
+        for key in ACCURACY_KEYS {
+            let json = json!({"geoData": {"latitude": 1.0, "longitude": 2.0, key: 7.5}});
+            assert_eq!(read_geo(&json).unwrap().1.accuracy, Some(7.5), "{}", key);
+        }
+        for key in DOP_KEYS {
+            let json = json!({"geoData": {"latitude": 1.0, "longitude": 2.0, key: 1.2}});
+            assert_eq!(read_geo(&json).unwrap().1.dop, Some(1.2), "{}", key);
+        }
+    }
+
+    #[test]
+    fn every_lens_layout_is_read() {
+        for (make, model) in LENS_PATHS {
+            let json = with_pointer(with_pointer(Value::Null, make, json!("Example")), model, json!(" 24-70mm "));
+            assert_eq!(read_lens(&json), (Some("Example".to_string()), Some("24-70mm".to_string())), "{}", make);
+        }
+        assert_eq!(read_lens(&json!({"lensMake": "  ", "lensModel": ""})), (None, None));
+    }
+
+    #[test]
+    fn altitude_falls_back_to_a_later_block() {
+        let json = json!({
+            "geoData": {"latitude": 1.5, "longitude": 2.5},
+            "geoDataExif": {"latitude": 1.5, "longitude": 2.5, "altitude": 42.0},
+        });
+        assert_eq!(read_geo(&json).map(|(name, location)| (name, location.altitude)), Some(("geoData", Some(42.0))));
+
+        // An empty geoData, as exports write when they have nothing.
+        let json = json!({"geoData": {}, "geoDataExif": {"latitude": 1.5, "longitude": 2.5, "altitude": -3.0}});
+        assert_eq!(read_geo(&json).map(|(name, location)| (name, location.altitude)), Some(("geoDataExif", Some(-3.0))));
+    }
+
+    #[test]
+    fn altitude_is_omitted_when_no_block_has_one() {
+        let json = json!({
+            "geoData": {"latitude": 1.5, "longitude": 2.5},
+            "geoDataExif": {"latitude": 1.5, "longitude": 2.5},
+        });
+        assert_eq!(read_geo(&json).unwrap().1.altitude, None);
+    }
+}
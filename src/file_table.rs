@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use eframe::egui;
+
+use metadata_fix::{MediaFormat, MediaMetadata};
+
+enum RowStatus {
+    Pending,
+    Done,
+    Skipped,
+    Error(String),
+}
+
+struct FileRow {
+    sidecar: PathBuf,
+    media_name: Option<String>,
+    has_location: Option<bool>,
+    fallback_location: bool,
+    datetime: Option<DateTime<Utc>>,
+    status: RowStatus,
+}
+
+/// Per-file view of a run: one row per discovered sidecar, updated in place
+/// as results arrive.
+#[derive(Default)]
+pub struct FileTable {
+    rows: Vec<FileRow>,
+    index: HashMap<PathBuf, usize>,
+    selected: Option<usize>,
+}
+
+impl FileTable {
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn add_pending(&mut self, sidecars: Vec<PathBuf>) {
+        for sidecar in sidecars {
+            self.row_mut(&sidecar);
+        }
+    }
+
+    pub fn set_done(&mut self, sidecar: &Path, media_name: String, metadata: &MediaMetadata) {
+        let row = self.row_mut(sidecar);
+        row.media_name = Some(media_name);
+        row.has_location = Some(metadata.location.is_some());
+        row.fallback_location = metadata.fallback_location;
+        row.datetime = Some(metadata.datetime);
+        row.status = RowStatus::Done;
+    }
+
+    pub fn set_skipped(&mut self, sidecar: &Path, media_name: String) {
+        let row = self.row_mut(sidecar);
+        row.media_name = Some(media_name);
+        row.status = RowStatus::Skipped;
+    }
+
+    pub fn set_error(&mut self, sidecar: &Path, message: String) {
+        self.row_mut(sidecar).status = RowStatus::Error(message);
+    }
+
+    fn row_mut(&mut self, sidecar: &Path) -> &mut FileRow {
+        let index = *self.index.entry(sidecar.to_path_buf()).or_insert_with(|| {
+            self.rows.push(FileRow {
+                sidecar: sidecar.to_path_buf(),
+                media_name: None,
+                has_location: None,
+                fallback_location: false,
+                datetime: None,
+                status: RowStatus::Pending,
+            });
+            self.rows.len() - 1
+        });
+        &mut self.rows[index]
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        let row_height = ui.text_style_height(&egui::TextStyle::Body) + ui.spacing().item_spacing.y;
+
+        egui::ScrollArea::vertical()
+            .max_height(200.0)
+            .auto_shrink([false, true])
+            .show_rows(ui, row_height, self.rows.len(), |ui, range| {
+                egui::Grid::new("file_table")
+                    .num_columns(5)
+                    .striped(true)
+                    .min_col_width(60.0)
+                    .show(ui, |ui| {
+                        ui.strong("Name");
+                        ui.strong("Format");
+                        ui.strong("GPS");
+                        ui.strong("Date");
+                        ui.strong("Status");
+                        ui.end_row();
+
+                        for index in range {
+                            let row = &self.rows[index];
+                            let name = row.media_name.clone().unwrap_or_else(|| {
+                                row.sidecar.file_name().unwrap_or_default().to_string_lossy().into_owned()
+                            });
+                            let format = row.media_name.as_deref()
+                                .and_then(MediaFormat::detect)
+                                .map_or("?", MediaFormat::label);
+
+                            ui.label(name);
+                            ui.label(format);
+                            ui.label(match row.has_location {
+                                Some(true) if row.fallback_location => "fallback",
+                                Some(true) => "✔",
+                                Some(false) => "—",
+                                None => "",
+                            });
+                            ui.label(row.datetime.map(|dt| dt.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_default());
+                            match &row.status {
+                                RowStatus::Pending => {
+                                    ui.label("pending");
+                                }
+                                RowStatus::Done => {
+                                    ui.label("✅ done");
+                                }
+                                RowStatus::Skipped => {
+                                    ui.label("⏭ skipped");
+                                }
+                                RowStatus::Error(_) => {
+                                    if ui.selectable_label(self.selected == Some(index), "❌ error").clicked() {
+                                        self.selected = Some(index);
+                                    }
+                                }
+                            }
+                            ui.end_row();
+                        }
+                    });
+            });
+
+        if let Some(row) = self.selected.and_then(|index| self.rows.get(index))
+            && let RowStatus::Error(message) = &row.status
+        {
+            ui.label(format!("❌ {}: {}", row.sidecar.display(), message));
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+
+use crate::media::{escape_xml, GpsLocation};
+
+pub const GPX_FILE_NAME: &str = "photos.gpx";
+
+pub struct TrackPoint {
+    pub name: String,
+    pub datetime: DateTime<Utc>,
+    pub location: GpsLocation,
+}
+
+/// Writes every point as a waypoint, plus a single track through all of them
+/// in time order.
+pub fn write_gpx(path: &Path, points: &mut [TrackPoint]) -> std::io::Result<()> {
+    points.sort_by_key(|point| point.datetime);
+
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(w, r#"<gpx version="1.1" creator="Metadata Fix" xmlns="http://www.topografix.com/GPX/1/1">"#)?;
+
+    for point in points.iter() {
+        writeln!(w, r#"  <wpt lat="{}" lon="{}">"#, point.location.latitude, point.location.longitude)?;
+        write_point_body(&mut w, point, "    ")?;
+        writeln!(w, "    <name>{}</name>", escape_xml(&point.name))?;
+        writeln!(w, "  </wpt>")?;
+    }
+
+    writeln!(w, "  <trk>")?;
+    writeln!(w, "    <name>Photos</name>")?;
+    writeln!(w, "    <trkseg>")?;
+    for point in points.iter() {
+        writeln!(w, r#"      <trkpt lat="{}" lon="{}">"#, point.location.latitude, point.location.longitude)?;
+        write_point_body(&mut w, point, "        ")?;
+        writeln!(w, "      </trkpt>")?;
+    }
+    writeln!(w, "    </trkseg>")?;
+    writeln!(w, "  </trk>")?;
+    writeln!(w, "</gpx>")?;
+
+    w.flush()
+}
+
+fn write_point_body(w: &mut impl Write, point: &TrackPoint, indent: &str) -> std::io::Result<()> {
+    if let Some(altitude) = point.location.altitude {
+        writeln!(w, "{}<ele>{}</ele>", indent, altitude)?;
+    }
+    writeln!(w, "{}<time>{}</time>", indent, point.datetime.format("%Y-%m-%dT%H:%M:%SZ"))
+}
@@ -0,0 +1,114 @@
+use std::io::Cursor;
+
+use sha2::{Digest, Sha256};
+
+use crate::MediaFormat;
+
+/// How a processed image is fingerprinted in the run log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    /// SHA-256 of the decoded pixels. Unchanged by any metadata rewrite, so
+    /// copies of a photo in different albums hash the same.
+    PixelSha256,
+    /// 64-bit average hash of an 8x8 greyscale thumbnail. Also matches
+    /// re-encoded or resized copies; compare by Hamming distance.
+    Average,
+}
+
+impl HashAlgorithm {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::PixelSha256 => "SHA-256 of pixels",
+            Self::Average => "Perceptual (average hash)",
+        }
+    }
+}
+
+const THUMBNAIL_SIDE: usize = 8;
+
+/// Decoded 8-bit samples, `channels` per pixel. The first `colour_channels`
+/// of each pixel are colour; any after that is alpha.
+struct Pixels {
+    width: usize,
+    height: usize,
+    channels: usize,
+    colour_channels: usize,
+    data: Vec<u8>,
+}
+
+/// Hashes the image in `data`, prefixed with the algorithm so entries from
+/// runs with different settings can't be confused. Files we can't decode
+/// fall back to a SHA-256 of their bytes.
+pub fn content_hash(algorithm: HashAlgorithm, format: Option<MediaFormat>, data: &[u8]) -> String {
+    let Some(pixels) = format.and_then(|format| decode(format, data).ok()) else {
+        return format!("sha256:{:x}", Sha256::digest(data));
+    };
+    match algorithm {
+        HashAlgorithm::PixelSha256 => format!("pixel-sha256:{:x}", Sha256::digest(&pixels.data)),
+        HashAlgorithm::Average => format!("ahash:{:016x}", average_hash(&pixels)),
+    }
+}
+
+fn decode(format: MediaFormat, data: &[u8]) -> Result<Pixels, String> {
+    match format {
+        MediaFormat::Jpeg => {
+            let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(data));
+            let mut data = decoder.decode().map_err(|e| e.to_string())?;
+            let info = decoder.info().ok_or("JPEG has no frame")?;
+            let channels = match info.pixel_format {
+                jpeg_decoder::PixelFormat::L8 => 1,
+                jpeg_decoder::PixelFormat::L16 => {
+                    // Keep the high byte of each big-endian sample.
+                    data = data.chunks_exact(2).map(|sample| sample[0]).collect();
+                    1
+                }
+                jpeg_decoder::PixelFormat::RGB24 => 3,
+                jpeg_decoder::PixelFormat::CMYK32 => 4,
+            };
+            Ok(Pixels { width: info.width.into(), height: info.height.into(), channels, colour_channels: channels, data })
+        }
+        MediaFormat::Png => {
+            let mut decoder = png::Decoder::new(Cursor::new(data));
+            decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+            let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+            let mut data = vec![0; reader.output_buffer_size()];
+            let frame = reader.next_frame(&mut data).map_err(|e| e.to_string())?;
+            data.truncate(frame.buffer_size());
+            let colour_channels = match frame.color_type {
+                png::ColorType::GrayscaleAlpha => 1,
+                png::ColorType::Rgba => 3,
+                other => other.samples(),
+            };
+            Ok(Pixels {
+                width: frame.width as usize,
+                height: frame.height as usize,
+                channels: frame.color_type.samples(),
+                colour_channels,
+                data,
+            })
+        }
+        MediaFormat::Heif | MediaFormat::Video => Err(format!("{} can't be decoded", format.label())),
+    }
+}
+
+/// Shrinks the image to 8x8 by averaging, then sets one bit per cell that
+/// is brighter than the mean of all cells.
+fn average_hash(pixels: &Pixels) -> u64 {
+    let mut sums = [0u64; THUMBNAIL_SIDE * THUMBNAIL_SIDE];
+    let mut counts = [0u64; THUMBNAIL_SIDE * THUMBNAIL_SIDE];
+    for (i, pixel) in pixels.data.chunks_exact(pixels.channels).enumerate() {
+        let (x, y) = (i % pixels.width, i / pixels.width);
+        let cell = (y * THUMBNAIL_SIDE / pixels.height) * THUMBNAIL_SIDE + x * THUMBNAIL_SIDE / pixels.width;
+        let colour = &pixel[..pixels.colour_channels];
+        sums[cell] += colour.iter().map(|&sample| u64::from(sample)).sum::<u64>() / colour.len() as u64;
+        counts[cell] += 1;
+    }
+
+    let cells: Vec<u64> = sums.iter().zip(&counts)
+        .map(|(sum, count)| if *count == 0 { 0 } else { sum / count })
+        .collect();
+    let mean = cells.iter().sum::<u64>() / cells.len() as u64;
+    cells.iter().enumerate()
+        .filter(|(_, cell)| **cell > mean)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit)
+}
@@ -0,0 +1,2160 @@
+//! Restores the dates and locations of a Google Photos Takeout export from
+//! its JSON sidecars. The GUI, the command line and the HTTP server in the
+//! binary all drive [`process_photos`].
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use chrono::{DateTime, Datelike, Utc};
+use rayon::prelude::*;
+
+mod archive;
+pub mod checkpoint;
+pub mod compare;
+pub mod control;
+mod extract;
+pub mod gpx;
+pub mod hash;
+pub mod limiter;
+mod location;
+pub mod media;
+pub mod readiness;
+pub mod resolve;
+pub mod runlog;
+pub mod schema;
+pub mod selftest;
+mod verify;
+
+use control::{RunControl, RunState};
+use gpx::TrackPoint;
+use hash::HashAlgorithm;
+use limiter::{FileLimiter, WriteLimiter};
+use location::LocationResolver;
+use runlog::{Outcome, RunLog};
+use media::{App1Placement, ExifFields, ExistingMetadata, GpsLocation, IptcFields, ModifyDate, XmpFields};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    /// Mirror the input tree under the output directory.
+    MirrorTree,
+    /// Write `name_fixed.ext` next to the original.
+    FixedSuffix,
+    /// Write every file straight into the output directory, numbering
+    /// names that repeat across albums.
+    Flatten,
+}
+
+pub const DEFAULT_QUARANTINE_FOLDER: &str = "_unprocessed";
+
+/// What to do when the file already has GPS in its EXIF.
+#[derive(Clone, Copy, PartialEq)]
+pub enum GpsPolicy {
+    /// The sidecar's coordinates replace whatever the file has.
+    PreferJson,
+    /// The file's own coordinates are kept; the sidecar only fills a gap.
+    PreferExisting,
+    /// Files that already have GPS are left untouched entirely.
+    OnlyFillIfMissing,
+}
+
+#[derive(Clone)]
+pub struct ProcessOptions {
+    pub max_open_files: usize,
+    /// Number of worker threads. `None` uses one per CPU core.
+    pub jobs: Option<usize>,
+    /// Before processing, find and size every sidecar's media so progress
+    /// follows bytes written rather than files. Slower to start on big
+    /// trees.
+    pub size_up_front: bool,
+    /// Start processing while the input is still being scanned instead of
+    /// listing every sidecar first, so memory stays flat on huge trees.
+    pub stream_scan: bool,
+    /// Cap on write throughput in MB/s, shared by all workers. Helps
+    /// spinning disks that thrash under parallel writes.
+    pub write_limit_mb_per_sec: Option<f64>,
+    pub output_mode: OutputMode,
+    pub overwrite_existing: bool,
+    /// Failed media are copied untouched into this folder under the output
+    /// directory when set.
+    pub quarantine_folder: Option<String>,
+    /// Only fill in a missing date and/or location, keeping whatever the
+    /// file already has and leaving complete files untouched.
+    pub fill_missing_only: bool,
+    /// Also process items the sidecar marks `trashed`. Off by default so
+    /// photos deleted in Google Photos don't come back.
+    pub include_trashed: bool,
+    /// Keep the file's own DateTimeOriginal unless it is missing or more
+    /// than this many hours from the sidecar's.
+    pub date_change_threshold_hours: Option<f64>,
+    pub gps_policy: GpsPolicy,
+    /// Write a GPX file of every geotagged photo into the output directory.
+    pub export_gpx: bool,
+    /// Write an XMP packet next to the EXIF, with the date in RFC 3339 and
+    /// the caption in full UTF-8.
+    pub write_xmp: bool,
+    /// Also write the sidecar's Google Photos link to XMP as `dc:source`.
+    pub embed_source_url: bool,
+    /// Also write the name of the folder holding each file, i.e. its album,
+    /// to XMP as a `dc:subject` keyword.
+    pub album_keyword: bool,
+    /// Also add the album to the IPTC Keywords of JPEGs, keeping the
+    /// keywords they already have.
+    pub iptc_album_keyword: bool,
+    /// Process exactly these sidecars instead of scanning the input
+    /// directory. Relative paths are taken from the input directory.
+    pub file_list: Option<Vec<PathBuf>>,
+    /// After the run, re-open and fully decode every written file.
+    pub verify_outputs: bool,
+    /// Record a hash of each processed image in the run log, for finding
+    /// duplicates across albums later.
+    pub content_hash: Option<HashAlgorithm>,
+    /// Written to files whose sidecar has no location at all.
+    pub fallback_location: Option<GpsLocation>,
+    /// Leave out sidecars the run log already records as done or skipped.
+    pub resume_from_log: bool,
+    /// Start after the sidecars an interrupted run's checkpoint covers,
+    /// errors included; those are in the run log.
+    pub resume_from_checkpoint: bool,
+    /// Also write the date as a PNG `Creation Time` text chunk, for
+    /// viewers that don't read eXIf. Off by default, like the other extra
+    /// metadata.
+    pub png_creation_time: bool,
+    /// Set each written file's modified and accessed times to the photo's
+    /// date, so file managers sort by when it was taken.
+    pub photo_date_as_file_time: bool,
+    /// Convert PNGs to JPEG at this quality (1-100) instead of rewriting
+    /// them. Lossy.
+    pub png_to_jpeg_quality: Option<u8>,
+    pub app1_placement: App1Placement,
+    /// Write Orientation = 1: the pixels are known to be upright already.
+    pub force_upright: bool,
+    /// What to write as EXIF DateTime (IFD0).
+    pub modify_date: ModifyDate,
+    /// Also write SubSecTime* and OffsetTime* with the dates.
+    pub subsec_and_offset: bool,
+    /// Write dates as local time, taking the offset from the longitude;
+    /// see [`location::NauticalTimezone`].
+    pub local_time_from_longitude: bool,
+    /// Stamped into every file as EXIF Artist; blank writes nothing.
+    pub artist: String,
+    /// Stamped into every file as EXIF Copyright, and XMP `dc:rights` when
+    /// writing XMP; blank writes nothing.
+    pub copyright: String,
+    /// Write nothing; log what each file has now next to what would be
+    /// written instead.
+    pub dry_run: bool,
+    /// Count a processed file with any note (fallback location, fuzzy
+    /// match, dropped altitude...) as failed, for pipelines that review
+    /// every anomaly.
+    pub strict: bool,
+    /// When a sidecar's media isn't in its folder, look for it anywhere in
+    /// the input directory (not in archives).
+    pub search_whole_tree: bool,
+    /// When a sidecar has no timestamp, take DateTimeOriginal from a
+    /// same-named file in its folder (e.g. a burst's first frame).
+    pub borrow_sibling_timestamp: bool,
+}
+
+impl ProcessOptions {
+    /// The tags Lightroom reads for capture time and location, where it
+    /// looks for them: DateTimeOriginal with SubSecTimeOriginal and
+    /// OffsetTimeOriginal in the Exif IFD (so UTC isn't shown as local
+    /// time), DateTime matching it, and the GPS IFD after a JFIF header. XMP
+    /// dates are written too, since Lightroom prefers XMP when both exist.
+    pub fn apply_lightroom_preset(&mut self) {
+        self.modify_date = ModifyDate::SameAsOriginal;
+        self.subsec_and_offset = true;
+        self.app1_placement = App1Placement::AfterJfif;
+        self.write_xmp = true;
+    }
+
+    fn needs_existing_metadata(&self) -> bool {
+        // The fallback location must never replace real coordinates.
+        self.fill_missing_only
+            || self.date_change_threshold_hours.is_some()
+            || self.gps_policy != GpsPolicy::PreferJson
+            || self.fallback_location.is_some()
+            || self.dry_run
+    }
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            max_open_files: limiter::DEFAULT_MAX_OPEN_FILES,
+            jobs: None,
+            size_up_front: false,
+            stream_scan: false,
+            write_limit_mb_per_sec: None,
+            output_mode: OutputMode::MirrorTree,
+            overwrite_existing: false,
+            quarantine_folder: None,
+            fill_missing_only: false,
+            include_trashed: false,
+            date_change_threshold_hours: None,
+            gps_policy: GpsPolicy::PreferJson,
+            export_gpx: false,
+            write_xmp: false,
+            embed_source_url: false,
+            album_keyword: false,
+            iptc_album_keyword: false,
+            file_list: None,
+            verify_outputs: false,
+            content_hash: None,
+            fallback_location: None,
+            resume_from_log: false,
+            resume_from_checkpoint: false,
+            png_creation_time: false,
+            photo_date_as_file_time: true,
+            png_to_jpeg_quality: None,
+            app1_placement: App1Placement::default(),
+            force_upright: false,
+            modify_date: ModifyDate::default(),
+            subsec_and_offset: false,
+            local_time_from_longitude: false,
+            artist: String::new(),
+            copyright: String::new(),
+            dry_run: false,
+            strict: false,
+            search_whole_tree: false,
+            borrow_sibling_timestamp: false,
+        }
+    }
+}
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+/// Stage a run is in. Each phase reports its own progress from 0 to 1
+/// where that can be measured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// Finding sidecars; the total isn't known yet.
+    Scanning,
+    Processing,
+    /// Re-decoding every written file.
+    Verifying,
+    /// Writing run-level outputs such as the GPX track.
+    Finalizing,
+}
+
+impl Phase {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Scanning => "Scanning",
+            Self::Processing => "Processing",
+            Self::Verifying => "Verifying",
+            Self::Finalizing => "Finalizing",
+        }
+    }
+
+    pub fn is_measurable(self) -> bool {
+        matches!(self, Self::Processing | Self::Verifying)
+    }
+}
+
+#[derive(Debug)]
+pub enum ProcessMessage {
+    /// A new phase started; progress restarts from 0.
+    Phase(Phase),
+    /// Progress within the current phase.
+    Progress(f32),
+    Status(String),
+    /// Sidecars found by the scan, before any of them are processed.
+    FilesDiscovered(Vec<PathBuf>),
+    FileProcessed {
+        sidecar: PathBuf,
+        media_name: String,
+        input_path: PathBuf,
+        output_path: PathBuf,
+        /// Boxed so the other messages don't all carry its size.
+        metadata: Box<MediaMetadata>,
+    },
+    FileFailed {
+        sidecar: PathBuf,
+        message: String,
+    },
+    FileSkipped {
+        sidecar: PathBuf,
+        media_name: String,
+    },
+    Completed(ProcessSummary),
+    Error(String),
+}
+
+/// Width of the longest bar in the per-year histogram.
+const YEAR_BAR_WIDTH: usize = 30;
+
+/// Outcome of a whole run, also reported piecemeal through the messages.
+#[derive(Clone, Debug)]
+pub struct ProcessSummary {
+    pub processed: usize,
+    pub errors: usize,
+    pub skipped: usize,
+    pub duration: Duration,
+    /// Outcomes by lowercase media extension; empty when it isn't known.
+    pub per_format_counts: BTreeMap<String, FormatCounts>,
+    /// Processed files by the year they were taken. A spike in an odd year
+    /// (1970...) points at bad timestamps.
+    pub per_year_counts: BTreeMap<i32, usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FormatCounts {
+    pub processed: usize,
+    pub errors: usize,
+    pub skipped: usize,
+}
+
+/// Runs a whole batch, streaming progress to `sender` and ending with
+/// either `Completed` or `Error`. The same outcome is returned for callers
+/// that want it without reading the channel.
+pub fn process_photos(
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    options: ProcessOptions,
+    control: &RunControl,
+    sender: mpsc::Sender<ProcessMessage>,
+) -> Result<ProcessSummary, String> {
+    let result = match options.jobs {
+        Some(jobs) => rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs.max(1))
+            .build()
+            .map_err(|e| format!("Could not start {} worker threads: {}", jobs, e))
+            .and_then(|pool| pool.install(|| run_batch(input_dir, output_dir, options, control, &sender))),
+        None => run_batch(input_dir, output_dir, options, control, &sender),
+    };
+    match &result {
+        Ok(summary) => {
+            let _ = sender.send(ProcessMessage::Completed(summary.clone()));
+        }
+        Err(e) => {
+            let _ = sender.send(ProcessMessage::Error(e.clone()));
+        }
+    }
+    result
+}
+
+fn run_batch(
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    options: ProcessOptions,
+    control: &RunControl,
+    sender: &mpsc::Sender<ProcessMessage>,
+) -> Result<ProcessSummary, String> {
+    let started = Instant::now();
+
+    // The log lives wherever the outputs go.
+    let log_dir = if options.output_mode == OutputMode::FixedSuffix { &input_dir } else { &output_dir };
+    let run_log = RunLog::new(log_dir);
+    let checkpoint_path = checkpoint::path(log_dir);
+
+    let mut options = options;
+    if options.dry_run {
+        // Nothing is written, so there is nothing to quarantine, verify or
+        // export either.
+        options.quarantine_folder = None;
+        options.verify_outputs = false;
+        options.export_gpx = false;
+    }
+
+    let write_limiter = options.write_limit_mb_per_sec.map(WriteLimiter::new);
+    let resolver: Box<dyn LocationResolver> = if options.local_time_from_longitude {
+        Box::new(location::NauticalTimezone)
+    } else {
+        Box::new(location::NoResolver)
+    };
+    let ctx = RunContext {
+        input_dir,
+        output_dir,
+        options,
+        track: Mutex::new(Vec::new()),
+        claimed_outputs: Mutex::new(HashSet::new()),
+        written: Mutex::new(Vec::new()),
+        run_log,
+        checkpoint_path,
+        media_index: OnceLock::new(),
+        write_limiter,
+        resolver,
+        dates_changed: AtomicUsize::new(0),
+        dates_kept: AtomicUsize::new(0),
+        per_format_counts: Mutex::new(BTreeMap::new()),
+        per_year_counts: Mutex::new(BTreeMap::new()),
+    };
+    let RunContext { input_dir, output_dir, options, .. } = &ctx;
+
+    let is_archive = archive::is_archive(input_dir);
+    if is_archive && options.output_mode == OutputMode::FixedSuffix {
+        return Err("Archives can only be processed into an output directory".to_string());
+    }
+    if is_archive && options.file_list.is_some() {
+        return Err("A file list can't be used with an archive".to_string());
+    }
+    if options.dry_run {
+        let _ = sender.send(ProcessMessage::Status("🧪 Dry run: nothing will be written".to_string()));
+    } else {
+        prepare_output_dir(&ctx, sender)?;
+    }
+
+    let (processed, errors, skipped) = if is_archive {
+        archive::process_archive(&ctx, control, sender)?
+    } else {
+        process_directory(&ctx, control, sender)?
+    };
+    let summary = || ProcessSummary {
+        processed,
+        errors,
+        skipped,
+        duration: started.elapsed(),
+        per_format_counts: ctx.per_format_counts.lock().unwrap().clone(),
+        per_year_counts: ctx.per_year_counts.lock().unwrap().clone(),
+    };
+
+    if control.state() == RunState::Cancelled {
+        let _ = sender.send(ProcessMessage::Status("⏹ Processing cancelled".to_string()));
+        return Ok(summary());
+    }
+
+    if !options.dry_run
+        && let Err(e) = checkpoint::remove(&ctx.checkpoint_path)
+    {
+        let _ = sender.send(ProcessMessage::Status(format!("⚠️ Could not remove checkpoint: {}", e)));
+    }
+
+    if options.verify_outputs {
+        verify_written(&ctx, control, sender);
+        if control.state() == RunState::Cancelled {
+            let _ = sender.send(ProcessMessage::Status("⏹ Processing cancelled".to_string()));
+            return Ok(summary());
+        }
+    }
+
+    let _ = sender.send(ProcessMessage::Phase(Phase::Finalizing));
+
+    let finished = summary();
+    if let Some(&most) = finished.per_year_counts.values().max() {
+        let _ = sender.send(ProcessMessage::Status("📅 Photos per year:".to_string()));
+        for (year, count) in &finished.per_year_counts {
+            let bar = "█".repeat((count * YEAR_BAR_WIDTH).div_ceil(most));
+            let _ = sender.send(ProcessMessage::Status(format!("{} {:>6} {}", year, count, bar)));
+        }
+    }
+
+    if options.date_change_threshold_hours.is_some() {
+        let _ = sender.send(ProcessMessage::Status(format!(
+            "🕒 Dates changed: {}, left alone: {}",
+            ctx.dates_changed.load(Ordering::Relaxed),
+            ctx.dates_kept.load(Ordering::Relaxed),
+        )));
+    }
+
+    if options.export_gpx {
+        let mut points = std::mem::take(&mut *ctx.track.lock().unwrap());
+        let gpx_path = output_dir.join(gpx::GPX_FILE_NAME);
+        match gpx::write_gpx(&gpx_path, &mut points) {
+            Ok(()) => {
+                let _ = sender.send(ProcessMessage::Status(format!(
+                    "🗺 Wrote {} geotagged photos to {}", points.len(), gpx_path.display()
+                )));
+            }
+            Err(e) => {
+                let _ = sender.send(ProcessMessage::Status(format!("⚠️ Could not write GPX file: {}", e)));
+            }
+        }
+    }
+
+    let _ = sender.send(ProcessMessage::Progress(1.0));
+    Ok(ProcessSummary { duration: started.elapsed(), ..finished })
+}
+
+/// Runs one file's work, turning a panic into an error message so a single
+/// bad file can't take down the whole batch.
+fn catch_file_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).map_err(|payload| {
+        let message = payload.downcast_ref::<&str>().copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        format!("Internal error: {}", message)
+    })
+}
+
+/// Sidecars the run log says an earlier run already finished.
+fn load_finished(ctx: &RunContext) -> Result<HashSet<PathBuf>, String> {
+    ctx.run_log.load_finished()
+        .map_err(|e| format!("Could not read run log {}: {}", ctx.run_log.path().display(), e))
+}
+
+/// Appends to the run log, warning in the log view if that fails.
+fn log_outcome(
+    ctx: &RunContext,
+    sender: &mpsc::Sender<ProcessMessage>,
+    outcome: Outcome,
+    sidecar: &Path,
+    detail: Option<&str>,
+) {
+    if ctx.options.dry_run {
+        return;
+    }
+    if let Err(e) = ctx.run_log.record(outcome, sidecar, detail) {
+        let _ = sender.send(ProcessMessage::Status(format!("⚠️ Could not write run log: {}", e)));
+    }
+}
+
+/// Decodes every file written this run and lists the ones that fail.
+fn verify_written(ctx: &RunContext, control: &RunControl, sender: &mpsc::Sender<ProcessMessage>) {
+    let written = std::mem::take(&mut *ctx.written.lock().unwrap());
+    let _ = sender.send(ProcessMessage::Phase(Phase::Verifying));
+
+    let done_count = AtomicUsize::new(0);
+    let failed: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+    written.par_iter().for_each_with(sender.clone(), |sender, path| {
+        if !control.wait_while_paused() {
+            return;
+        }
+        if let Err(e) = verify::verify_output(path) {
+            let _ = sender.send(ProcessMessage::Status(format!("❌ Corrupt output {}: {}", path.display(), e)));
+            failed.lock().unwrap().push(path.clone());
+        }
+        let done = done_count.fetch_add(1, Ordering::Relaxed) + 1;
+        let _ = sender.send(ProcessMessage::Progress(done as f32 / written.len() as f32));
+    });
+
+    let failed = failed.into_inner().unwrap();
+    if failed.is_empty() {
+        let _ = sender.send(ProcessMessage::Status(format!("🔎 All {} outputs decoded cleanly", written.len())));
+    } else {
+        let _ = sender.send(ProcessMessage::Status(format!(
+            "⚠️ {} of {} outputs failed to decode:", failed.len(), written.len()
+        )));
+        for path in failed {
+            let _ = sender.send(ProcessMessage::Status(format!("   {}", path.display())));
+        }
+    }
+}
+
+/// Every `.json` file under `input_dir`. Unreadable directories are skipped.
+pub fn find_sidecars(input_dir: &Path) -> Vec<PathBuf> {
+    scan_input(input_dir, None)
+}
+
+/// Lists the sidecars under `input_dir`, adding every other file to
+/// `media_index` when given.
+fn scan_input(input_dir: &Path, mut media_index: Option<&mut resolve::MediaIndex>) -> Vec<PathBuf> {
+    let mut json_files = Vec::new();
+    walk_input(input_dir, |path| {
+        if is_sidecar(&path) {
+            json_files.push(path);
+        } else if let Some(media_index) = media_index.as_deref_mut() {
+            media_index.insert(path);
+        }
+        true
+    });
+    json_files
+}
+
+fn is_sidecar(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Calls `visit` with every file under `input_dir` until it returns false.
+/// Unreadable directories are skipped.
+fn walk_input(input_dir: &Path, mut visit: impl FnMut(PathBuf) -> bool) {
+    let mut dirs_to_check = vec![input_dir.to_path_buf()];
+
+    while let Some(dir) = dirs_to_check.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs_to_check.push(path);
+            } else if !visit(path) {
+                return;
+            }
+        }
+    }
+}
+
+/// Reads a list of sidecar paths, one per line. Blank lines and lines
+/// starting with `#` are ignored.
+pub fn read_file_list(path: &Path) -> Result<Vec<PathBuf>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read file list {}: {}", path.display(), e))?;
+    Ok(text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Starts the name of the empty file created, then removed, to check that
+/// the output directory is writable.
+const WRITE_PROBE_PREFIX: &str = ".metadata_fix_write_test_";
+
+/// Creates the output directory and makes sure it can be written to, so an
+/// unwritable destination is one clear error instead of one per file.
+fn prepare_output_dir(ctx: &RunContext, sender: &mpsc::Sender<ProcessMessage>) -> Result<(), String> {
+    let RunContext { input_dir, output_dir, options, .. } = ctx;
+
+    // Fixed-suffix copies land next to the originals.
+    let target = if options.output_mode == OutputMode::FixedSuffix {
+        input_dir
+    } else {
+        std::fs::create_dir_all(output_dir)
+            .map_err(|e| format!("Could not create output directory: {}", e))?;
+        output_dir
+    };
+
+    // A probe left by a killed run, or one from another run into the same
+    // directory, is not a reason to fail: try the next name.
+    static PROBES: AtomicUsize = AtomicUsize::new(0);
+    let probe = loop {
+        let probe = target.join(format!(
+            "{}{}_{}", WRITE_PROBE_PREFIX, std::process::id(), PROBES.fetch_add(1, Ordering::Relaxed),
+        ));
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&probe) {
+            Ok(_) => break probe,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("Cannot write to {}: {}", target.display(), e)),
+        }
+    };
+    let _ = std::fs::remove_file(&probe);
+
+    if options.output_mode != OutputMode::FixedSuffix
+        && let (Ok(input), Ok(output)) = (input_dir.canonicalize(), output_dir.canonicalize())
+        && input == output
+    {
+        let _ = sender.send(ProcessMessage::Status(
+            "⚠️ Output directory is the input directory: files will be rewritten in place".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Scans `input_dir` for sidecars and processes them in parallel. Returns
+/// `(processed, errors, skipped)`.
+fn process_directory(
+    ctx: &RunContext,
+    control: &RunControl,
+    sender: &mpsc::Sender<ProcessMessage>,
+) -> Result<(usize, usize, usize), String> {
+    let RunContext { input_dir, options, .. } = ctx;
+
+    if options.stream_scan {
+        if options.file_list.is_none()
+            && !options.search_whole_tree
+            && !options.size_up_front
+            && !options.resume_from_checkpoint
+        {
+            return process_directory_streaming(ctx, control, sender);
+        }
+        let _ = sender.send(ProcessMessage::Status(
+            "⚠️ Streaming can't be combined with a file list, whole-tree search, sizing up front or checkpoint resume; scanning first".to_string(),
+        ));
+    }
+
+    let _ = sender.send(ProcessMessage::Phase(Phase::Scanning));
+
+    let mut media_index = options.search_whole_tree.then(resolve::MediaIndex::default);
+    let mut json_files = match &options.file_list {
+        Some(files) => {
+            if let Some(media_index) = &mut media_index {
+                scan_input(input_dir, Some(media_index));
+            }
+            files.iter().map(|path| input_dir.join(path)).collect()
+        }
+        None => {
+            let _ = sender.send(ProcessMessage::Status("🔍 Scanning directories...".to_string()));
+            scan_input(input_dir, media_index.as_mut())
+        }
+    };
+    if let Some(media_index) = media_index {
+        let _ = ctx.media_index.set(media_index);
+    }
+
+    // Sorted so a checkpoint's position means the same files next time.
+    json_files.sort();
+    let listed = json_files.len();
+    let mut start = 0;
+    match checkpoint::load(&ctx.checkpoint_path) {
+        Some(saved) if saved.total == listed && options.resume_from_checkpoint => {
+            start = saved.finished.min(listed);
+            json_files.drain(..start);
+            let _ = sender.send(ProcessMessage::Status(format!(
+                "⏩ Resuming from checkpoint: {} of {} files already finished", start, listed
+            )));
+        }
+        Some(_) if options.resume_from_checkpoint => {
+            let _ = sender.send(ProcessMessage::Status(
+                "⚠️ The checkpoint is for a different set of files; starting from the beginning".to_string(),
+            ));
+        }
+        Some(saved) if !options.dry_run => {
+            let _ = sender.send(ProcessMessage::Status(format!(
+                "💾 An interrupted run left a checkpoint ({} of {} files finished); tick \"Resume after a crash\" to continue from it",
+                saved.finished, saved.total
+            )));
+        }
+        _ => {}
+    }
+    let tracker = (!options.dry_run).then(|| checkpoint::Tracker::new(ctx.checkpoint_path.clone(), listed, start));
+
+    // Each sidecar's position for the checkpoint, counted from `start`.
+    let mut indices: Vec<usize> = (0..json_files.len()).collect();
+
+    if options.resume_from_log {
+        let finished = load_finished(ctx)?;
+        let before = json_files.len();
+        (json_files, indices) = json_files.into_iter().zip(indices)
+            .filter(|(path, index)| {
+                let unfinished = !finished.contains(path);
+                if !unfinished && let Some(tracker) = &tracker {
+                    let _ = tracker.finish(*index);
+                }
+                unfinished
+            })
+            .unzip();
+        let _ = sender.send(ProcessMessage::Status(format!(
+            "⏩ Resuming: {} files already finished", before - json_files.len()
+        )));
+    }
+
+    let total_files = json_files.len();
+    let _ = sender.send(ProcessMessage::Status(format!("📊 Found {} JSON files to process", total_files)));
+
+    // Each file's share of the progress bar: its media size when sized up
+    // front (at least 1, so missing media still move it), else 1.
+    let weights: Vec<u64> = if options.size_up_front {
+        let _ = sender.send(ProcessMessage::Status("📏 Sizing media files...".to_string()));
+        let weights: Vec<u64> = json_files.par_iter().map(|json_file| sidecar_media_size(json_file).max(1)).collect();
+        let _ = sender.send(ProcessMessage::Status(format!(
+            "📊 {:.1} MB of media to process", weights.iter().sum::<u64>() as f64 / 1_000_000.0
+        )));
+        weights
+    } else {
+        vec![1; total_files]
+    };
+    let total_weight: u64 = weights.iter().sum();
+    let _ = sender.send(ProcessMessage::FilesDiscovered(json_files.clone()));
+    let _ = sender.send(ProcessMessage::Phase(Phase::Processing));
+
+    let counts = OutcomeCounts::default();
+    let done_weight = AtomicU64::new(0);
+    let limiter = FileLimiter::new(options.max_open_files);
+
+    let files = json_files.par_iter().zip(&indices).zip(&weights);
+    files.for_each_with(sender.clone(), |sender, ((json_file, &index), &weight)| {
+        if !control.wait_while_paused() {
+            return;
+        }
+
+        process_and_report(json_file, ctx, &limiter, &counts, sender);
+
+        if let Some(tracker) = &tracker
+            && let Err(e) = tracker.finish(index)
+        {
+            let _ = sender.send(ProcessMessage::Status(format!("⚠️ Could not save checkpoint: {}", e)));
+        }
+
+        let done = done_weight.fetch_add(weight, Ordering::Relaxed) + weight;
+        let _ = sender.send(ProcessMessage::Progress(done as f32 / total_weight as f32));
+    });
+
+    Ok(counts.into_inner())
+}
+
+/// Sidecars the scan may get ahead of the workers by.
+const STREAM_BUFFER: usize = 1024;
+
+/// Like [`process_directory`], but a scanner thread feeds sidecars to the
+/// workers through a bounded channel as it finds them, so the full list is
+/// never held. The progress total is what has been found so far and grows
+/// until the scan ends. No checkpoint is kept: without the sorted list a
+/// position in it means nothing.
+fn process_directory_streaming(
+    ctx: &RunContext,
+    control: &RunControl,
+    sender: &mpsc::Sender<ProcessMessage>,
+) -> Result<(usize, usize, usize), String> {
+    let RunContext { input_dir, options, .. } = ctx;
+
+    let finished = if options.resume_from_log { load_finished(ctx)? } else { HashSet::new() };
+    let _ = sender.send(ProcessMessage::Status("🔍 Scanning and processing as files are found...".to_string()));
+    let _ = sender.send(ProcessMessage::Phase(Phase::Processing));
+
+    let counts = OutcomeCounts::default();
+    let discovered = AtomicUsize::new(0);
+    let resumed = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let limiter = FileLimiter::new(options.max_open_files);
+    let (path_sender, path_receiver) = mpsc::sync_channel(STREAM_BUFFER);
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut batch = Vec::new();
+            walk_input(input_dir, |path| {
+                if !is_sidecar(&path) {
+                    return true;
+                }
+                if finished.contains(&path) {
+                    resumed.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+                discovered.fetch_add(1, Ordering::Relaxed);
+                batch.push(path.clone());
+                if batch.len() == STREAM_BUFFER {
+                    let _ = sender.send(ProcessMessage::FilesDiscovered(std::mem::take(&mut batch)));
+                }
+                // Blocks while the workers are a full buffer behind.
+                path_sender.send(path).is_ok() && control.state() != RunState::Cancelled
+            });
+            let _ = sender.send(ProcessMessage::FilesDiscovered(batch));
+            drop(path_sender);
+        });
+
+        path_receiver.into_iter().par_bridge().for_each_with(sender.clone(), |sender, json_file| {
+            if !control.wait_while_paused() {
+                return;
+            }
+
+            process_and_report(&json_file, ctx, &limiter, &counts, sender);
+
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            let total = discovered.load(Ordering::Relaxed).max(done);
+            let _ = sender.send(ProcessMessage::Progress(done as f32 / total as f32));
+        });
+    });
+
+    if options.resume_from_log {
+        let _ = sender.send(ProcessMessage::Status(format!(
+            "⏩ Resuming: {} files already finished", resumed.into_inner()
+        )));
+    }
+    let _ = sender.send(ProcessMessage::Status(format!("📊 Found {} JSON files to process", discovered.into_inner())));
+
+    Ok(counts.into_inner())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MediaFormat {
+    Jpeg,
+    Png,
+    Heif,
+    Video,
+}
+
+impl MediaFormat {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Jpeg => "JPEG",
+            Self::Png => "PNG",
+            Self::Heif => "HEIF",
+            Self::Video => "MP4/QuickTime",
+        }
+    }
+
+    pub fn detect(media_name: &str) -> Option<Self> {
+        match Strategy::for_name(media_name) {
+            Strategy::InPlace(format) => Some(format),
+            Strategy::Sidecar | Strategy::Skip => None,
+        }
+    }
+
+    /// Bytes before any image data can start: the SOI and one marker, the
+    /// signature and IHDR chunk, the `ftyp` box, or one box header.
+    fn min_size(self) -> usize {
+        match self {
+            Self::Jpeg => 4,
+            Self::Png => 33,
+            Self::Heif => 16,
+            Self::Video => 8,
+        }
+    }
+}
+
+/// Fails empty files, and files that end inside the format's header, as
+/// the failed downloads they are rather than as malformed images. Only the
+/// first [`MediaFormat::min_size`] bytes of `head` are looked at.
+fn check_not_truncated(format: Option<MediaFormat>, head: &[u8]) -> Result<(), String> {
+    if head.is_empty() {
+        return Err("Empty file (0 bytes), probably a failed download".to_string());
+    }
+    match format {
+        Some(format) if head.len() < format.min_size() => Err(format!(
+            "Truncated file ({} bytes), too short for a {}; probably a failed download",
+            head.len(), format.label()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reads just enough of a file for [`check_not_truncated`].
+fn read_head(path: &Path) -> Result<Vec<u8>, String> {
+    let mut head = Vec::new();
+    std::fs::File::open(path)
+        .and_then(|file| file.take(MediaFormat::Png.min_size() as u64).read_to_end(&mut head))
+        .map_err(|e| format!("Error reading image: {}", e))?;
+    Ok(head)
+}
+
+/// How a media file gets its metadata.
+#[derive(Clone, Copy)]
+enum Strategy {
+    /// Rewritten with the metadata embedded.
+    InPlace(MediaFormat),
+    /// Copied untouched, with an `.xmp` sidecar written next to it.
+    Sidecar,
+    /// Left alone entirely.
+    Skip,
+}
+
+/// Extension to strategy. Anything not listed gets an XMP sidecar, so no
+/// file is ever rejected just for its format.
+const FORMAT_REGISTRY: &[(&str, Strategy)] = &[
+    // Formats we can rewrite ourselves.
+    ("jpg", Strategy::InPlace(MediaFormat::Jpeg)),
+    ("jpeg", Strategy::InPlace(MediaFormat::Jpeg)),
+    ("png", Strategy::InPlace(MediaFormat::Png)),
+    // iPhone photos. Only the EXIF item is rewritten; XMP isn't written.
+    ("heic", Strategy::InPlace(MediaFormat::Heif)),
+    ("heif", Strategy::InPlace(MediaFormat::Heif)),
+    // Videos: only the date and location atoms of `moov` are patched.
+    ("mp4", Strategy::InPlace(MediaFormat::Video)),
+    ("mov", Strategy::InPlace(MediaFormat::Video)),
+    ("m4v", Strategy::InPlace(MediaFormat::Video)),
+    // Insta360 stills and video: vendor containers we don't parse.
+    ("insp", Strategy::Sidecar),
+    ("insv", Strategy::Sidecar),
+    // Already a sidecar; another one next to it would be meaningless.
+    ("xmp", Strategy::Skip),
+];
+
+impl Strategy {
+    fn for_name(media_name: &str) -> Self {
+        let extension = Path::new(media_name)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        FORMAT_REGISTRY.iter()
+            .find(|(known, _)| *known == extension)
+            .map_or(Strategy::Sidecar, |(_, strategy)| *strategy)
+    }
+}
+
+/// Whether `media_name` is a PNG that [`ProcessOptions::png_to_jpeg_quality`]
+/// turns into a JPEG.
+fn converts_to_jpeg(media_name: &str, options: &ProcessOptions) -> bool {
+    options.png_to_jpeg_quality.is_some() && MediaFormat::detect(media_name) == Some(MediaFormat::Png)
+}
+
+/// `dir/IMG.png` -> `dir/IMG.jpg`
+fn jpeg_name(name: &str) -> String {
+    Path::new(name).with_extension("jpg").to_string_lossy().into_owned()
+}
+
+/// `IMG.insp` -> `IMG.insp.xmp`, keeping the full name so `IMG.insp` and
+/// `IMG.insv` don't share a sidecar.
+fn xmp_sidecar_path(media_path: &Path) -> PathBuf {
+    let mut name = media_path.as_os_str().to_os_string();
+    name.push(".xmp");
+    PathBuf::from(name)
+}
+
+#[derive(Clone, Debug)]
+pub struct MediaMetadata {
+    pub location: Option<GpsLocation>,
+    /// `location` is the user's fallback, not real coordinates.
+    pub fallback_location: bool,
+    pub datetime: DateTime<Utc>,
+    pub exif: ExifFields,
+    pub xmp: XmpFields,
+    pub iptc: IptcFields,
+    /// The sidecar says the item is in the Google Photos trash.
+    pub trashed: bool,
+}
+
+struct FileError {
+    message: String,
+    /// The media file the sidecar resolved to, if processing got that far.
+    media_path: Option<PathBuf>,
+}
+
+impl From<String> for FileError {
+    fn from(message: String) -> Self {
+        Self { message, media_path: None }
+    }
+}
+
+impl From<&str> for FileError {
+    fn from(message: &str) -> Self {
+        Self::from(message.to_string())
+    }
+}
+
+/// Everything a run's workers share.
+struct RunContext {
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    options: ProcessOptions,
+    /// Geotagged photos collected for the GPX export.
+    track: Mutex<Vec<TrackPoint>>,
+    /// Output paths already handed out, so flattened names never collide.
+    claimed_outputs: Mutex<HashSet<PathBuf>>,
+    /// Files written this run, for the verification pass.
+    written: Mutex<Vec<PathBuf>>,
+    run_log: RunLog,
+    /// Where directory runs save their progress; see [`checkpoint`].
+    checkpoint_path: PathBuf,
+    /// Filled during the scan when [`ProcessOptions::search_whole_tree`] is
+    /// on.
+    media_index: OnceLock<resolve::MediaIndex>,
+    write_limiter: Option<WriteLimiter>,
+    /// Local time and place names for the sidecars' coordinates.
+    resolver: Box<dyn LocationResolver>,
+    /// Files whose embedded date was replaced, and files whose date was
+    /// left as it was, for the date threshold report.
+    dates_changed: AtomicUsize,
+    dates_kept: AtomicUsize,
+    per_format_counts: Mutex<BTreeMap<String, FormatCounts>>,
+    per_year_counts: Mutex<BTreeMap<i32, usize>>,
+}
+
+impl RunContext {
+    /// Tallies a file's outcome by its extension. `name` is the media file
+    /// name, or the sidecar's when the media is unknown.
+    fn count_outcome(&self, name: &Path, outcome: Outcome) {
+        let name = match name.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Path::new(name.file_stem().unwrap_or_default()),
+            _ => name,
+        };
+        let extension = name.extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let mut per_format_counts = self.per_format_counts.lock().unwrap();
+        let counts = per_format_counts.entry(extension).or_default();
+        match outcome {
+            Outcome::Done => counts.processed += 1,
+            Outcome::Error => counts.errors += 1,
+            Outcome::Skipped => counts.skipped += 1,
+        }
+    }
+
+    /// Tallies a processed file by the year it was taken.
+    fn count_processed(&self, metadata: &MediaMetadata) {
+        *self.per_year_counts.lock().unwrap().entry(metadata.datetime.year()).or_default() += 1;
+    }
+
+    fn count_date_change(&self, existing: &ExistingMetadata, merged: &MediaMetadata) {
+        if existing.datetime == Some(merged.datetime) {
+            self.dates_kept.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.dates_changed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Waits for the write limit, if any, to allow `bytes` more.
+    fn throttle_write(&self, bytes: u64) {
+        if let Some(limiter) = &self.write_limiter {
+            limiter.acquire(bytes);
+        }
+    }
+}
+
+struct ProcessedFile {
+    media_name: String,
+    input_path: PathBuf,
+    output_path: PathBuf,
+    metadata: MediaMetadata,
+    notes: Vec<Note>,
+    skipped: bool,
+    /// Per [`ProcessOptions::content_hash`].
+    hash: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// What the options asked for, e.g. a file left alone because it
+    /// already has GPS.
+    Info,
+    /// Something off about the input that was worked around: a fallback
+    /// location, a borrowed date, a loose file name match, dropped GPS.
+    /// Strict mode fails the file.
+    Warning,
+}
+
+/// One remark about how a file was handled, shown in the log.
+#[derive(Clone, Debug)]
+pub struct Note {
+    severity: Severity,
+    text: String,
+}
+
+impl Note {
+    fn info(text: impl Into<String>) -> Self {
+        Self { severity: Severity::Info, text: text.into() }
+    }
+
+    fn warning(text: impl Into<String>) -> Self {
+        Self { severity: Severity::Warning, text: text.into() }
+    }
+
+    fn icon(&self) -> &'static str {
+        match self.severity {
+            Severity::Info => "ℹ️",
+            Severity::Warning => "⚠️",
+        }
+    }
+}
+
+/// Decodes sidecar bytes to text, honouring a UTF-8 BOM or a UTF-16 (LE/BE)
+/// BOM left behind by tools that re-saved the export.
+fn decode_json_text(bytes: &[u8]) -> Result<String, String> {
+    let utf16 = |data: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = data.chunks_exact(2)
+            .map(|pair| from_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16(&units).map_err(|e| format!("Invalid UTF-16 in JSON: {}", e))
+    };
+
+    match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8(rest.to_vec())
+            .map_err(|e| format!("Invalid UTF-8 in JSON: {}", e)),
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        _ => String::from_utf8(bytes.to_vec())
+            .map_err(|e| format!("Invalid UTF-8 in JSON: {}", e)),
+    }
+}
+
+/// Reserves `output_dir/<file_name>` for this run, falling back to
+/// `name (1).ext`, `name (2).ext`... when another album already claimed it.
+fn claim_flat_output(ctx: &RunContext, file_name: &str, notes: &mut Vec<Note>) -> PathBuf {
+    let mut claimed = ctx.claimed_outputs.lock().unwrap();
+    let mut candidate = ctx.output_dir.join(file_name);
+
+    let path = Path::new(file_name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| ext.to_string_lossy());
+
+    let mut n = 1;
+    while claimed.contains(&candidate) {
+        let numbered = match &extension {
+            Some(ext) => format!("{} ({}).{}", stem, n, ext),
+            None => format!("{} ({})", stem, n),
+        };
+        candidate = ctx.output_dir.join(numbered);
+        n += 1;
+    }
+
+    if n > 1 {
+        notes.push(Note::info(format!(
+            "renamed to {} to avoid a name collision",
+            candidate.file_name().unwrap_or_default().to_string_lossy()
+        )));
+    }
+    claimed.insert(candidate.clone());
+    candidate
+}
+
+/// `dir/IMG_1234.jpg` -> `dir/IMG_1234_fixed.jpg`
+fn fixed_sibling_path(image_path: &Path) -> PathBuf {
+    let stem = image_path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match image_path.extension() {
+        Some(ext) => format!("{}_fixed.{}", stem, ext.to_string_lossy()),
+        None => format!("{}_fixed", stem),
+    };
+    image_path.with_file_name(file_name)
+}
+
+/// Reduces a JSON `title` to a bare file name. Titles carrying a relative
+/// path keep only their last segment; absolute paths and `..` are rejected so
+/// a malformed sidecar can't point outside its own directory.
+fn sanitize_media_name(title: &str) -> Result<&str, String> {
+    let segments: Vec<&str> = title.split(['/', '\\']).collect();
+
+    let is_absolute = title.starts_with(['/', '\\'])
+        || (segments[0].len() == 2 && segments[0].ends_with(':'));
+    if is_absolute || segments.contains(&"..") {
+        return Err(format!("Unsafe path in JSON title: {}", title));
+    }
+
+    segments.into_iter()
+        .rev()
+        .find(|segment| !segment.is_empty() && *segment != ".")
+        .ok_or_else(|| format!("Invalid title in JSON: {}", title))
+}
+
+/// Processes one sidecar and reports its outcome: messages, run log,
+/// tallies, quarantine.
+fn process_and_report(
+    json_file: &PathBuf,
+    ctx: &RunContext,
+    limiter: &FileLimiter,
+    counts: &OutcomeCounts,
+    sender: &mpsc::Sender<ProcessMessage>,
+) {
+    let RunContext { input_dir, output_dir, options, .. } = ctx;
+
+    let result = {
+        let _permit = limiter.acquire();
+        catch_file_panic(|| process_single_file(json_file, ctx))
+            .unwrap_or_else(|message| Err(FileError::from(message)))
+    };
+    let result = result.and_then(|processed| match strict_violations(&processed.notes, options) {
+        Some(message) if !processed.skipped => {
+            Err(FileError { message, media_path: Some(processed.input_path) })
+        }
+        _ => Ok(processed),
+    });
+
+    match result {
+        Ok(processed) => {
+            for note in &processed.notes {
+                let _ = sender.send(ProcessMessage::Status(format!("{} {}: {}", note.icon(), processed.media_name, note.text)));
+            }
+            if processed.skipped {
+                counts.skipped.fetch_add(1, Ordering::Relaxed);
+                log_outcome(ctx, sender, Outcome::Skipped, json_file, None);
+                ctx.count_outcome(Path::new(&processed.media_name), Outcome::Skipped);
+                let _ = sender.send(ProcessMessage::FileSkipped {
+                    sidecar: json_file.clone(),
+                    media_name: processed.media_name,
+                });
+            } else {
+                counts.processed.fetch_add(1, Ordering::Relaxed);
+                ctx.count_processed(&processed.metadata);
+                log_outcome(ctx, sender, Outcome::Done, json_file, processed.hash.as_deref());
+                ctx.count_outcome(Path::new(&processed.media_name), Outcome::Done);
+                if options.verify_outputs {
+                    ctx.written.lock().unwrap().push(processed.output_path.clone());
+                }
+                if options.export_gpx
+                    && let Some(location) = processed.metadata.location
+                {
+                    ctx.track.lock().unwrap().push(TrackPoint {
+                        name: processed.media_name.clone(),
+                        datetime: processed.metadata.datetime,
+                        location,
+                    });
+                }
+                let _ = sender.send(ProcessMessage::FileProcessed {
+                    sidecar: json_file.clone(),
+                    media_name: processed.media_name,
+                    input_path: processed.input_path,
+                    output_path: processed.output_path,
+                    metadata: Box::new(processed.metadata),
+                });
+            }
+        }
+        Err(e) => {
+            counts.errors.fetch_add(1, Ordering::Relaxed);
+            log_outcome(ctx, sender, Outcome::Error, json_file, Some(&e.message));
+            ctx.count_outcome(e.media_path.as_deref().unwrap_or(json_file), Outcome::Error);
+            let _ = sender.send(ProcessMessage::FileFailed {
+                sidecar: json_file.clone(),
+                message: e.message,
+            });
+
+            if let Some(folder) = &options.quarantine_folder {
+                for path in std::iter::once(json_file).chain(e.media_path.as_ref()) {
+                    if let Err(err) = quarantine_file(path, input_dir, output_dir, folder) {
+                        let _ = sender.send(ProcessMessage::Status(format!(
+                            "⚠️ {}: {}", path.file_name().unwrap_or_default().to_string_lossy(), err
+                        )));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Files finished so far in a run, by outcome.
+#[derive(Default)]
+struct OutcomeCounts {
+    processed: AtomicUsize,
+    errors: AtomicUsize,
+    skipped: AtomicUsize,
+}
+
+impl OutcomeCounts {
+    /// `(processed, errors, skipped)`
+    fn into_inner(self) -> (usize, usize, usize) {
+        (self.processed.into_inner(), self.errors.into_inner(), self.skipped.into_inner())
+    }
+}
+
+/// Size of the media a sidecar describes, or 0 if it can't be found.
+fn sidecar_media_size(json_file: &Path) -> u64 {
+    let size = || -> Option<u64> {
+        let json_data = schema::read_json(json_file).ok()?;
+        let media_name = sanitize_media_name(json_data["title"].as_str()?).ok()?;
+        let media_path = resolve::resolve_media_path(json_file.parent()?, media_name, &mut Vec::new())?;
+        Some(std::fs::metadata(media_path).ok()?.len())
+    };
+    size().unwrap_or(0)
+}
+
+fn process_single_file(json_file: &Path, ctx: &RunContext) -> Result<ProcessedFile, FileError> {
+    let mut notes = Vec::new();
+
+    let json_bytes = std::fs::read(json_file)
+        .map_err(|e| format!("Error reading JSON: {}", e))?;
+    let sidecar_dir = json_file.parent().ok_or("Sidecar has no parent directory")?;
+    let (media_name, mut metadata) =
+        parse_sidecar(&json_bytes, &ctx.options, &*ctx.resolver, Some(sidecar_dir), &mut notes)?;
+    let album = sidecar_dir.strip_prefix(&ctx.input_dir).ok().and_then(album_name);
+    set_album(&mut metadata, album, &ctx.options);
+
+    if skips_trashed(&metadata, &ctx.options, &mut notes) {
+        return Ok(ProcessedFile {
+            input_path: sidecar_dir.join(&media_name),
+            output_path: PathBuf::new(),
+            media_name,
+            metadata,
+            notes,
+            skipped: true,
+            hash: None,
+        });
+    }
+
+    let image_path = resolve::resolve_media_path(sidecar_dir, &media_name, &mut notes)
+        .or_else(|| {
+            // Titles keep the full original name while the file on disk may
+            // be truncated; a `.supplemental-metadata` sidecar's own name
+            // carries the truncated one.
+            let sidecar_name = json_file.file_name()?.to_string_lossy();
+            let named = resolve::media_name_from_sidecar(&sidecar_name).filter(|named| *named != media_name)?;
+            let found = resolve::resolve_media_path(sidecar_dir, &named, &mut notes)?;
+            notes.push(Note::warning(format!("matched {} from the sidecar's file name", named)));
+            Some(found)
+        })
+        .or_else(|| ctx.media_index.get()?.find(&media_name, &mut notes))
+        .ok_or("Image file not found")?;
+
+    let (output_path, skipped) = write_media(&image_path, &media_name, &metadata, ctx, &mut notes)
+        .map_err(|message| FileError { message, media_path: Some(image_path.clone()) })?;
+
+    let hash = match ctx.options.content_hash {
+        Some(algorithm) if !skipped && !ctx.options.dry_run => match std::fs::read(&image_path) {
+            Ok(data) => Some(hash::content_hash(algorithm, MediaFormat::detect(&media_name), &data)),
+            Err(e) => {
+                notes.push(Note::warning(format!("could not hash: {}", e)));
+                None
+            }
+        },
+        _ => None,
+    };
+
+    Ok(ProcessedFile {
+        media_name,
+        input_path: image_path,
+        output_path,
+        metadata,
+        notes,
+        skipped,
+        hash,
+    })
+}
+
+/// Whether to leave a trashed item alone, noting why.
+fn skips_trashed(metadata: &MediaMetadata, options: &ProcessOptions, notes: &mut Vec<Note>) -> bool {
+    let skip = metadata.trashed && !options.include_trashed;
+    if skip {
+        notes.push(Note::info("in the Google Photos trash, skipped"));
+    }
+    skip
+}
+
+/// Records `album` wherever the options ask for it.
+fn set_album(metadata: &mut MediaMetadata, album: Option<String>, options: &ProcessOptions) {
+    if options.iptc_album_keyword {
+        metadata.iptc.keywords.extend(album.clone());
+    }
+    if options.write_xmp && options.album_keyword {
+        metadata.xmp.album = album;
+    }
+}
+
+/// The album a file belongs to: the last folder of its path relative to the
+/// input root. Files directly in the root have none.
+fn album_name(relative_dir: &Path) -> Option<String> {
+    relative_dir.file_name().map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Extracts the media file name and the metadata to write from a sidecar.
+///
+/// `sidecar_dir` is where the media lives on disk, if it does; it is needed
+/// to borrow a timestamp from a sibling file.
+fn parse_sidecar(
+    json_bytes: &[u8],
+    options: &ProcessOptions,
+    resolver: &dyn LocationResolver,
+    sidecar_dir: Option<&Path>,
+    notes: &mut Vec<Note>,
+) -> Result<(String, MediaMetadata), String> {
+    let json_string = decode_json_text(json_bytes)?;
+
+    let json_data: Value = serde_json::from_str(&json_string)
+        .map_err(|e| format!("Error parsing JSON: {}", e))?;
+
+    let title = json_data["title"].as_str()
+        .ok_or("No title found in JSON")?;
+    let media_name = sanitize_media_name(title)?.to_string();
+    if media_name != title {
+        notes.push(Note::warning(format!("title \"{}\" reduced to file name", title)));
+    }
+
+    let geo = extract::read_geo(&json_data);
+    let (preferred_geo, _) = extract::GEO_PATHS[0];
+    match geo {
+        Some((field, ..)) if field != preferred_geo => {
+            notes.push(Note::info(format!("no location in {}, used {}", preferred_geo, field)));
+        }
+        Some(_) => {}
+        None if options.fallback_location.is_some() => {
+            notes.push(Note::warning("no location in JSON, used fallback location"));
+        }
+        None => notes.push(Note::warning("no location in JSON, writing date only")),
+    }
+
+    let borrowed = match extract::read_timestamp(&json_data) {
+        Err(_) if options.borrow_sibling_timestamp => sidecar_dir
+            .and_then(|dir| resolve::find_sibling_timestamp(dir, &media_name)),
+        _ => None,
+    };
+
+    let datetime = match borrowed {
+        Some((sibling, datetime)) => {
+            notes.push(Note::warning(format!("no timestamp in JSON, borrowed {} from {}", datetime, sibling)));
+            datetime
+        }
+        None => {
+            let (timestamp_field, datetime) = extract::read_timestamp(&json_data)?;
+            let (preferred_timestamp, _) = extract::TIMESTAMP_PATHS[0];
+            if timestamp_field != preferred_timestamp {
+                notes.push(Note::info(format!("no {} in JSON, used {}", preferred_timestamp, timestamp_field)));
+            }
+            datetime
+        }
+    };
+
+    let fallback_location = geo.is_none() && options.fallback_location.is_some();
+    let location = geo.and_then(|(_, mut location)| {
+        location.altitude = location.altitude.filter(|altitude| {
+            let valid = GpsLocation::is_valid_altitude(*altitude);
+            if !valid {
+                notes.push(Note::warning(format!("altitude {} out of range, omitting it", altitude)));
+            }
+            valid
+        });
+        location.accuracy = location.accuracy.filter(|&accuracy| GpsLocation::is_valid_error_estimate(accuracy));
+        location.dop = location.dop.filter(|&dop| GpsLocation::is_valid_error_estimate(dop));
+
+        if location.is_valid() {
+            Some(location)
+        } else {
+            notes.push(Note::warning(format!(
+                "coordinates ({}, {}) out of range, writing date only",
+                location.latitude, location.longitude
+            )));
+            None
+        }
+    });
+    // The fallback says nothing about where the photo was really taken.
+    let looked_up = location.filter(|_| !fallback_location);
+    let utc_offset = looked_up.and_then(|location| resolver.timezone(location.latitude, location.longitude, datetime));
+    let place = looked_up.and_then(|location| resolver.place_name(location.latitude, location.longitude));
+    let location = if fallback_location { options.fallback_location } else { location };
+
+    let non_blank = |text: &str| Some(text.trim()).filter(|text| !text.is_empty()).map(str::to_string);
+
+    let description = json_data["description"].as_str().and_then(non_blank);
+    let mut xmp = XmpFields { place, ..XmpFields::default() };
+    if options.write_xmp {
+        xmp.dates = true;
+        xmp.rights = non_blank(&options.copyright);
+        xmp.description = description.clone();
+        if options.embed_source_url {
+            xmp.source_url = json_data["url"].as_str().map(str::to_string);
+        }
+    }
+
+    let (lens_make, lens_model) = extract::read_lens(&json_data);
+    let exif = ExifFields {
+        description,
+        force_upright: options.force_upright,
+        modify_date: options.modify_date,
+        subsec_and_offset: options.subsec_and_offset,
+        utc_offset,
+        lens_make,
+        lens_model,
+        artist: non_blank(&options.artist),
+        copyright: non_blank(&options.copyright),
+    };
+
+    let trashed = json_data["trashed"].as_bool().unwrap_or(false);
+
+    Ok((media_name, MediaMetadata {
+        location,
+        fallback_location,
+        datetime,
+        exif,
+        xmp,
+        iptc: IptcFields::default(),
+        trashed,
+    }))
+}
+
+/// Writes the fixed copy of `image_path`. Returns the output path and whether
+/// the file was skipped rather than written.
+fn write_media(
+    image_path: &Path,
+    media_name: &str,
+    metadata: &MediaMetadata,
+    ctx: &RunContext,
+    notes: &mut Vec<Note>,
+) -> Result<(PathBuf, bool), String> {
+    let RunContext { input_dir, output_dir, options, .. } = ctx;
+
+    let to_jpeg = converts_to_jpeg(media_name, options);
+    let output_path = match options.output_mode {
+        OutputMode::MirrorTree => {
+            let relative_path = image_path.strip_prefix(input_dir)
+                .map_err(|_| "Could not determine relative path")?;
+            output_dir.join(relative_path)
+        }
+        OutputMode::FixedSuffix => fixed_sibling_path(image_path),
+        OutputMode::Flatten => {
+            let file_name = image_path.file_name().unwrap_or_default().to_string_lossy();
+            let file_name = if to_jpeg { jpeg_name(&file_name) } else { file_name.into_owned() };
+            claim_flat_output(ctx, &file_name, notes)
+        }
+    };
+    let output_path = if to_jpeg { output_path.with_extension("jpg") } else { output_path };
+
+    if options.output_mode == OutputMode::FixedSuffix && output_path.exists() && !options.overwrite_existing {
+        notes.push(Note::info(format!("{} already exists", output_path.display())));
+        return Ok((output_path, true));
+    }
+
+    let strategy = Strategy::for_name(media_name);
+    if let Strategy::Skip = strategy {
+        notes.push(Note::info("format is skipped, nothing written"));
+        return Ok((output_path, true));
+    }
+    check_not_truncated(MediaFormat::detect(media_name), &read_head(image_path)?)?;
+
+    // Files we can't parse have no EXIF of their own to merge with.
+    let mut metadata = metadata.clone();
+    if options.needs_existing_metadata() && matches!(strategy, Strategy::InPlace(_)) {
+        let file = std::fs::File::open(image_path)
+            .map_err(|e| format!("Error reading image: {}", e))?;
+        let existing = media::read_existing_metadata(&mut std::io::BufReader::new(file))
+            .map_err(|e| format!("Error reading existing EXIF: {}", e))?;
+        match merge_existing(existing, &metadata, options, notes) {
+            Some(merged) => metadata = merged,
+            None => return Ok((output_path, true)),
+        }
+        ctx.count_date_change(&existing, &metadata);
+        if options.dry_run {
+            notes.push(Note::info(describe_changes(existing, &metadata)));
+        }
+    }
+
+    if options.dry_run {
+        return Ok((output_path, false));
+    }
+
+    if let Some(parent) = output_path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Error creating output directory: {}", e))?;
+    }
+
+    let image_path_str = image_path.to_string_lossy();
+    let output_path_str = output_path.to_string_lossy();
+    let MediaMetadata { location, datetime, exif, xmp, iptc, .. } = metadata;
+    let jpeg_fields = media::JpegFields { exif: &exif, xmp: &xmp, iptc: &iptc, placement: options.app1_placement };
+
+    // The output is about the size of the input; the metadata adds little.
+    ctx.throttle_write(std::fs::metadata(image_path).map_or(0, |m| m.len()));
+
+    match strategy {
+        Strategy::InPlace(MediaFormat::Jpeg) => {
+            media::update_jpeg_metadata(&image_path_str, Some(&output_path_str), location, datetime, jpeg_fields)
+                .map_err(|e| format!("JPEG processing error: {}", e))?;
+        }
+        Strategy::InPlace(MediaFormat::Png) if to_jpeg => {
+            let png_data = std::fs::read(image_path)
+                .map_err(|e| format!("Error reading image: {}", e))?;
+            let jpeg_data = media::png_to_jpeg(&png_data, options.png_to_jpeg_quality.unwrap_or(DEFAULT_JPEG_QUALITY))
+                .map_err(|e| format!("PNG to JPEG conversion error: {}", e))?;
+            let output_data = media::update_jpeg_metadata_bytes(&jpeg_data, location, datetime, jpeg_fields)
+                .map_err(|e| format!("JPEG processing error: {}", e))?;
+            media::write_file(&output_path_str, &output_data)
+                .map_err(|e| format!("Error writing output file: {}", e))?;
+        }
+        Strategy::InPlace(MediaFormat::Png) => {
+            media::update_png_metadata(
+                &image_path_str, Some(&output_path_str), location, datetime, &exif, &xmp,
+                options.png_creation_time,
+            )
+                .map_err(|e| format!("PNG processing error: {}", e))?;
+        }
+        Strategy::InPlace(MediaFormat::Heif) => {
+            media::update_heic_metadata(&image_path_str, Some(&output_path_str), location, datetime, &exif)
+                .map_err(|e| format!("HEIF processing error: {}", e))?;
+        }
+        Strategy::InPlace(MediaFormat::Video) => {
+            media::update_video_metadata(&image_path_str, Some(&output_path_str), location, datetime)
+                .map_err(|e| format!("Video processing error: {}", e))?;
+        }
+        Strategy::Sidecar => {
+            if output_path != image_path {
+                std::fs::copy(image_path, &output_path)
+                    .map_err(|e| format!("Error copying media: {}", e))?;
+            }
+            let sidecar_path = xmp_sidecar_path(&output_path);
+            media::write_file(&sidecar_path.to_string_lossy(), media::create_xmp_sidecar(location, datetime, &xmp).as_bytes())
+                .map_err(|e| format!("Error writing XMP sidecar: {}", e))?;
+        }
+        Strategy::Skip => unreachable!("skipped formats return early"),
+    }
+    stamp_file_time(&output_path, datetime, options, notes);
+
+    Ok((output_path, false))
+}
+
+/// Gives a written file the photo's date as its modified and accessed
+/// times, if the options ask for it. Failing only costs the sort order, so
+/// it is noted rather than failing the file.
+fn stamp_file_time(path: &Path, datetime: DateTime<Utc>, options: &ProcessOptions, notes: &mut Vec<Note>) {
+    if !options.photo_date_as_file_time {
+        return;
+    }
+    if let Err(e) = set_file_time(path, datetime) {
+        notes.push(Note::warning(format!("could not set the file's date: {}", e)));
+    }
+}
+
+fn set_file_time(path: &Path, datetime: DateTime<Utc>) -> std::io::Result<()> {
+    let time = std::time::SystemTime::from(datetime);
+    let times = std::fs::FileTimes::new().set_modified(time).set_accessed(time);
+    std::fs::File::options().write(true).open(path)?.set_times(times)
+}
+
+/// Coordinates closer than this (~10 m) are considered the same place.
+const GPS_CONFLICT_TOLERANCE: f64 = 0.0001;
+
+fn same_place(a: GpsLocation, b: GpsLocation) -> bool {
+    (a.latitude - b.latitude).abs() <= GPS_CONFLICT_TOLERANCE
+        && (a.longitude - b.longitude).abs() <= GPS_CONFLICT_TOLERANCE
+}
+
+/// Opens every dry-run preview note.
+const DRY_RUN_NOTE_PREFIX: &str = "dry run: ";
+
+/// In strict mode, the warnings among a file's notes, as an error message.
+fn strict_violations(notes: &[Note], options: &ProcessOptions) -> Option<String> {
+    if !options.strict {
+        return None;
+    }
+    let violations: Vec<&str> = notes.iter()
+        .filter(|note| note.severity == Severity::Warning)
+        .map(|note| note.text.as_str())
+        .collect();
+    (!violations.is_empty()).then(|| format!("strict mode: {}", violations.join("; ")))
+}
+
+/// One line comparing what the file has with what a real run would write.
+/// The file's other EXIF fields are carried over, so a location the sidecar
+/// lacks leaves the file's own in place.
+fn describe_changes(existing: ExistingMetadata, metadata: &MediaMetadata) -> String {
+    let date = |datetime: Option<DateTime<Utc>>| {
+        datetime.map_or("(none)".to_string(), |dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+    };
+    let gps = |location: Option<GpsLocation>| {
+        location.map_or("(none)".to_string(), |l| format!("({:.6}, {:.6})", l.latitude, l.longitude))
+    };
+
+    let date_change = match existing.datetime {
+        Some(old) if old == metadata.datetime => "unchanged",
+        Some(_) => "CHANGED",
+        None => "added",
+    };
+    let gps_change = match (existing.location, metadata.location) {
+        (None, None) => "none",
+        (None, Some(_)) => "added",
+        (Some(_), None) => "unchanged (kept)",
+        (Some(old), Some(new)) if same_place(old, new) => "unchanged",
+        (Some(_), Some(_)) => "CHANGED",
+    };
+
+    format!(
+        "{}date {} → {} [{}], GPS {} → {} [{}]",
+        DRY_RUN_NOTE_PREFIX,
+        date(existing.datetime), date(Some(metadata.datetime)), date_change,
+        gps(existing.location), gps(metadata.location.or(existing.location)), gps_change,
+    )
+}
+
+/// Applies the fill-missing mode and the GPS policy against what the file
+/// already has. Returns `None` when the file should be left untouched.
+fn merge_existing(
+    existing: ExistingMetadata,
+    metadata: &MediaMetadata,
+    options: &ProcessOptions,
+    notes: &mut Vec<Note>,
+) -> Option<MediaMetadata> {
+    if options.fill_missing_only {
+        return fill_missing(existing, metadata, notes);
+    }
+
+    let mut merged = metadata.clone();
+    if let (Some(hours), Some(existing_datetime)) = (options.date_change_threshold_hours, existing.datetime) {
+        let difference_hours = (metadata.datetime - existing_datetime).num_seconds().abs() as f64 / 3600.0;
+        if difference_hours <= hours {
+            merged.datetime = existing_datetime;
+            if difference_hours > 0.0 {
+                notes.push(Note::info(format!("date is within {} h of the file's, kept the file's", hours)));
+            }
+        }
+    }
+
+    if let Some(existing_location) = existing.location {
+        if metadata.fallback_location && options.gps_policy != GpsPolicy::OnlyFillIfMissing {
+            merged.location = Some(existing_location);
+            merged.fallback_location = false;
+            notes.push(Note::info("kept the file's own GPS over the fallback location"));
+            return Some(merged);
+        }
+
+        if let Some(json_location) = metadata.location
+            && !same_place(existing_location, json_location)
+        {
+            notes.push(Note::warning(format!(
+                "GPS conflict: file has ({:.6}, {:.6}), JSON has ({:.6}, {:.6}); keeping {}",
+                existing_location.latitude, existing_location.longitude,
+                json_location.latitude, json_location.longitude,
+                if options.gps_policy == GpsPolicy::PreferJson { "JSON" } else { "file" },
+            )));
+        }
+
+        match options.gps_policy {
+            GpsPolicy::PreferJson => {}
+            GpsPolicy::PreferExisting => {
+                merged.location = Some(existing_location);
+                merged.fallback_location = false;
+            }
+            GpsPolicy::OnlyFillIfMissing => {
+                notes.push(Note::info("already has GPS"));
+                return None;
+            }
+        }
+    }
+
+    Some(merged)
+}
+
+fn fill_missing(existing: ExistingMetadata, metadata: &MediaMetadata, notes: &mut Vec<Note>) -> Option<MediaMetadata> {
+    match (existing.datetime, existing.location) {
+        (Some(_), Some(_)) => {
+            notes.push(Note::info("already has date and location"));
+            None
+        }
+        (datetime, location) => {
+            let mut filled = Vec::new();
+            if datetime.is_none() {
+                filled.push("date");
+            }
+            if location.is_none() && metadata.location.is_some() {
+                filled.push("location");
+            }
+            if filled.is_empty() {
+                notes.push(Note::info("missing location, but the sidecar has none either"));
+                return None;
+            }
+            notes.push(Note::info(format!("filled missing {}", filled.join(" and "))));
+
+            Some(MediaMetadata {
+                location: location.or(metadata.location),
+                fallback_location: location.is_none() && metadata.fallback_location,
+                datetime: datetime.unwrap_or(metadata.datetime),
+                exif: metadata.exif.clone(),
+                xmp: metadata.xmp.clone(),
+                iptc: metadata.iptc.clone(),
+                trashed: metadata.trashed,
+            })
+        }
+    }
+}
+
+/// In-memory counterpart of [`write_media`], used when the media doesn't
+/// live on disk (e.g. inside an archive).
+fn apply_metadata_bytes(
+    format: MediaFormat,
+    data: &[u8],
+    metadata: &MediaMetadata,
+    options: &ProcessOptions,
+) -> Result<Vec<u8>, String> {
+    let MediaMetadata { location, datetime, exif, xmp, iptc, .. } = metadata;
+    let jpeg_fields = media::JpegFields { exif, xmp, iptc, placement: options.app1_placement };
+
+    if format == MediaFormat::Png
+        && let Some(quality) = options.png_to_jpeg_quality
+    {
+        let jpeg_data = media::png_to_jpeg(data, quality)
+            .map_err(|e| format!("PNG to JPEG conversion error: {}", e))?;
+        return media::update_jpeg_metadata_bytes(&jpeg_data, *location, *datetime, jpeg_fields)
+            .map_err(|e| format!("JPEG processing error: {}", e));
+    }
+
+    match format {
+        MediaFormat::Jpeg => media::update_jpeg_metadata_bytes(data, *location, *datetime, jpeg_fields)
+            .map_err(|e| format!("JPEG processing error: {}", e)),
+        MediaFormat::Png => media::update_png_metadata_bytes(
+            data, *location, *datetime, exif, xmp, options.png_creation_time,
+        )
+            .map_err(|e| format!("PNG processing error: {}", e)),
+        MediaFormat::Heif => media::update_heic_metadata_bytes(data, *location, *datetime, exif)
+            .map_err(|e| format!("HEIF processing error: {}", e)),
+        MediaFormat::Video => media::update_video_metadata_bytes(data, *location, *datetime)
+            .map_err(|e| format!("Video processing error: {}", e)),
+    }
+}
+
+/// Copies a failed file untouched into `output_dir/<folder>`, keeping its
+/// path relative to the input root.
+fn quarantine_file(path: &Path, input_dir: &Path, output_dir: &Path, folder: &str) -> Result<(), String> {
+    let relative_path = path.strip_prefix(input_dir)
+        .map_err(|_| "Could not determine relative path")?;
+    let target = output_dir.join(folder).join(relative_path);
+
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Error creating quarantine directory: {}", e))?;
+    }
+    std::fs::copy(path, &target)
+        .map_err(|e| format!("Error copying to quarantine: {}", e))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use location::NoResolver;
+
+    const SIDECAR_WITHOUT_GPS: &str = r#"{"title": "IMG_0001.jpg", "photoTakenTime": {"timestamp": "1563096615"}}"#;
+
+    fn parse(json: &str, options: &ProcessOptions) -> (MediaMetadata, Vec<Note>) {
+        let mut notes = Vec::new();
+        let (_, metadata) = parse_sidecar(json.as_bytes(), options, &NoResolver, None, &mut notes).unwrap();
+        (metadata, notes)
+    }
+
+    #[test]
+    fn strict_mode_fails_anomalies() {
+        let fallback = GpsLocation { latitude: 1.0, longitude: 2.0, altitude: None, accuracy: None, dop: None };
+        let options = ProcessOptions { strict: true, fallback_location: Some(fallback), ..ProcessOptions::default() };
+        let (_, notes) = parse(SIDECAR_WITHOUT_GPS, &options);
+
+        assert_eq!(
+            strict_violations(&notes, &options).as_deref(),
+            Some("strict mode: no location in JSON, used fallback location"),
+        );
+        assert_eq!(strict_violations(&notes, &ProcessOptions { strict: false, ..options }), None);
+    }
+
+    #[test]
+    fn strict_mode_passes_chosen_outcomes() {
+        let options = ProcessOptions { strict: true, fill_missing_only: true, ..ProcessOptions::default() };
+        let json = r#"{"title": "IMG_0001.jpg", "photoTakenTime": {"timestamp": "1563096615"},
+            "geoData": {"latitude": 48.85837, "longitude": 2.294481, "altitude": 35.0}}"#;
+        let (metadata, mut notes) = parse(json, &options);
+        let existing = ExistingMetadata { datetime: Some(metadata.datetime), location: None };
+        merge_existing(existing, &metadata, &options, &mut notes).unwrap();
+
+        assert_eq!(notes.iter().map(|note| note.text.as_str()).collect::<Vec<_>>(), ["filled missing location"]);
+        assert_eq!(strict_violations(&notes, &options), None);
+    }
+
+    #[test]
+    fn file_time_becomes_the_photo_date() {
+        selftest::check_file_time(selftest::sample_datetime()).unwrap();
+    }
+
+    #[test]
+    fn titles_that_escape_the_folder_are_rejected() {
+        for title in ["../../etc/passwd", "photos/../../etc", "..", "/etc/passwd", "\\server\\share.jpg", "C:\\Windows\\x.jpg", "..\\..\\etc"] {
+            assert!(sanitize_media_name(title).is_err(), "{} was accepted", title);
+        }
+        let json = r#"{"title": "../../etc/passwd", "photoTakenTime": {"timestamp": "1563096615"}}"#;
+        let error = parse_sidecar(json.as_bytes(), &ProcessOptions::default(), &NoResolver, None, &mut Vec::new()).unwrap_err();
+        assert_eq!(error, "Unsafe path in JSON title: ../../etc/passwd");
+    }
+
+    #[test]
+    fn titles_with_folders_are_reduced_to_the_file_name() {
+        assert_eq!(sanitize_media_name("IMG_0001.jpg"), Ok("IMG_0001.jpg"));
+        assert_eq!(sanitize_media_name("Trip/./IMG_0001.jpg"), Ok("IMG_0001.jpg"));
+        assert_eq!(sanitize_media_name("Trip\\IMG_0001.jpg/"), Ok("IMG_0001.jpg"));
+        assert!(sanitize_media_name("./").is_err());
+    }
+
+    fn utf16_with_bom(text: &str, to_bytes: fn(u16) -> [u8; 2], bom: [u8; 2]) -> Vec<u8> {
+        bom.into_iter().chain(text.encode_utf16().flat_map(to_bytes)).collect()
+    }
+
+    #[test]
+    fn sidecar_text_is_decoded_by_its_bom() {
+        const JSON: &str = r#"{"title": "Zürich 📷.jpg"}"#;
+        let utf8_bom = [&[0xEF, 0xBB, 0xBF][..], JSON.as_bytes()].concat();
+        let fixtures = [
+            ("UTF-8", JSON.as_bytes().to_vec()),
+            ("UTF-8 with BOM", utf8_bom),
+            ("UTF-16LE", utf16_with_bom(JSON, u16::to_le_bytes, [0xFF, 0xFE])),
+            ("UTF-16BE", utf16_with_bom(JSON, u16::to_be_bytes, [0xFE, 0xFF])),
+        ];
+        for (encoding, bytes) in fixtures {
+            assert_eq!(decode_json_text(&bytes).as_deref(), Ok(JSON), "{}", encoding);
+        }
+
+        let sidecar = utf16_with_bom(SIDECAR_WITHOUT_GPS, u16::to_le_bytes, [0xFF, 0xFE]);
+        let (media_name, _) = parse_sidecar(&sidecar, &ProcessOptions::default(), &NoResolver, None, &mut Vec::new()).unwrap();
+        assert_eq!(media_name, "IMG_0001.jpg");
+    }
+
+    #[test]
+    fn undecodable_sidecar_text_is_an_error() {
+        // A lone high surrogate, then invalid UTF-8 with and without a BOM.
+        assert!(decode_json_text(&[0xFF, 0xFE, 0x3D, 0xD8]).unwrap_err().starts_with("Invalid UTF-16"));
+        assert!(decode_json_text(&[0xEF, 0xBB, 0xBF, 0xC3]).unwrap_err().starts_with("Invalid UTF-8"));
+        assert!(decode_json_text(&[b'{', 0xFF, b'}']).unwrap_err().starts_with("Invalid UTF-8"));
+    }
+
+    #[test]
+    fn out_of_range_coordinates_write_the_date_only() {
+        let json = r#"{"title": "IMG_0001.jpg", "photoTakenTime": {"timestamp": "1563096615"},
+            "geoData": {"latitude": 200.0, "longitude": -400.0, "altitude": 0.0}}"#;
+        let (metadata, notes) = parse(json, &ProcessOptions::default());
+
+        assert!(metadata.location.is_none());
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].severity, Severity::Warning);
+        assert_eq!(notes[0].text, "coordinates (200, -400) out of range, writing date only");
+    }
+
+    /// The title's casing differs from the file's, and the input is reached
+    /// through a symlink, so only a path built from the walked directory
+    /// strips against it.
+    #[cfg(unix)]
+    #[test]
+    fn mismatched_casing_mirrors_the_file_under_its_real_name() {
+        let root = std::env::temp_dir().join(format!("metadata_fix_casing_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let album = root.join("input").join("Album");
+        std::fs::create_dir_all(&album).unwrap();
+        std::fs::write(album.join("IMG_0001.JPG"), selftest::tiny_jpeg()).unwrap();
+        std::fs::write(album.join("IMG_0001.JPG.json"), SIDECAR_WITHOUT_GPS.replace("IMG_0001.jpg", "img_0001.jpg")).unwrap();
+        let input = root.join("linked_input");
+        std::os::unix::fs::symlink(root.join("input"), &input).unwrap();
+
+        let (sender, _receiver) = mpsc::channel();
+        let summary = process_photos(input, root.join("output"), ProcessOptions::default(), &RunControl::new(), sender).unwrap();
+
+        assert_eq!((summary.processed, summary.errors), (1, 0));
+        assert!(root.join("output").join("Album").join("IMG_0001.JPG").is_file());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn absurd_altitudes_are_omitted_with_a_note() {
+        for altitude in ["1e12", "-1e9", "50000.5", "-5000.5"] {
+            let json = format!(
+                r#"{{"title": "IMG_0001.jpg", "photoTakenTime": {{"timestamp": "1563096615"}},
+                "geoData": {{"latitude": 48.85837, "longitude": 2.294481, "altitude": {}}}}}"#,
+                altitude,
+            );
+            let (metadata, notes) = parse(&json, &ProcessOptions::default());
+
+            let location = metadata.location.expect("the coordinates are kept");
+            assert_eq!(location.altitude, None, "{}", altitude);
+            assert_eq!(notes.len(), 1);
+            assert_eq!(notes[0].severity, Severity::Warning);
+            assert_eq!(notes[0].text, format!("altitude {} out of range, omitting it", altitude.parse::<f64>().unwrap()));
+        }
+    }
+
+    #[test]
+    fn absent_altitude_stays_absent() {
+        let with_altitude = |altitude: &str| format!(
+            r#"{{"title": "IMG_0001.jpg", "photoTakenTime": {{"timestamp": "1563096615"}},
+            "geoData": {{"latitude": 48.85837, "longitude": 2.294481{}}}}}"#,
+            altitude,
+        );
+        let altitude = |json: String| parse(&json, &ProcessOptions::default()).0.location.unwrap().altitude;
+
+        assert_eq!(altitude(with_altitude("")), None);
+        assert_eq!(altitude(with_altitude(r#", "altitude": 0.0"#)), Some(0.0));
+        assert_eq!(altitude(with_altitude(r#", "altitude": 35.5"#)), Some(35.5));
+        assert_eq!(altitude(with_altitude(r#", "altitude": -12.5"#)), Some(-12.5));
+    }
+
+    #[test]
+    fn file_panics_become_errors() {
+        assert_eq!(catch_file_panic(|| 7), Ok(7));
+        assert_eq!(catch_file_panic::<()>(|| panic!("bad segment")), Err("Internal error: bad segment".to_string()));
+        let offset = 12;
+        assert_eq!(
+            catch_file_panic::<()>(|| panic!("bad segment at {}", offset)),
+            Err("Internal error: bad segment at 12".to_string()),
+        );
+        assert_eq!(catch_file_panic::<()>(|| std::panic::panic_any(3)), Err("Internal error: unknown panic".to_string()));
+    }
+
+    /// A fresh `input` folder under a temporary root, holding `files`.
+    fn takeout(test: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("metadata_fix_{}_{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("input")).unwrap();
+        for (name, data) in files {
+            std::fs::write(root.join("input").join(name), data).unwrap();
+        }
+        root
+    }
+
+    /// Processes `root/input` into `root/output`, returning the summary and
+    /// each failed file's message.
+    fn run(root: &Path, options: ProcessOptions) -> (ProcessSummary, Vec<String>) {
+        let (sender, receiver) = mpsc::channel();
+        let summary = process_photos(root.join("input"), root.join("output"), options, &RunControl::new(), sender).unwrap();
+        let failures = receiver.try_iter()
+            .filter_map(|message| match message {
+                ProcessMessage::FileFailed { message, .. } => Some(message),
+                _ => None,
+            })
+            .collect();
+        (summary, failures)
+    }
+
+    #[test]
+    fn trashed_items_are_skipped_unless_included() {
+        let jpeg = selftest::tiny_jpeg();
+        let trashed = SIDECAR_WITHOUT_GPS.replace("IMG_0001", "IMG_0002").replace('}', r#", "trashed": true}"#);
+        let root = takeout("trashed", &[
+            ("IMG_0001.jpg", &jpeg),
+            ("IMG_0001.jpg.json", SIDECAR_WITHOUT_GPS.as_bytes()),
+            ("IMG_0002.jpg", &jpeg),
+            ("IMG_0002.jpg.json", trashed.as_bytes()),
+        ]);
+
+        let (summary, _) = run(&root, ProcessOptions::default());
+        assert_eq!((summary.processed, summary.skipped, summary.errors), (1, 1, 0));
+        assert!(!root.join("output").join("IMG_0002.jpg").exists());
+
+        let _ = std::fs::remove_dir_all(root.join("output"));
+        let (summary, _) = run(&root, ProcessOptions { include_trashed: true, ..ProcessOptions::default() });
+        assert_eq!((summary.processed, summary.skipped, summary.errors), (2, 0, 0));
+        assert!(root.join("output").join("IMG_0002.jpg").is_file());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn empty_and_one_byte_heads_are_failed_downloads() {
+        let empty = check_not_truncated(Some(MediaFormat::Jpeg), &[]).unwrap_err();
+        assert_eq!(empty, "Empty file (0 bytes), probably a failed download");
+        let one_byte = check_not_truncated(Some(MediaFormat::Jpeg), &[0xFF]).unwrap_err();
+        assert_eq!(one_byte, "Truncated file (1 bytes), too short for a JPEG; probably a failed download");
+        assert!(check_not_truncated(Some(MediaFormat::Jpeg), &selftest::tiny_jpeg()).is_ok());
+    }
+
+    #[test]
+    fn empty_and_one_byte_files_are_quarantined_as_failed_downloads() {
+        let sidecar = SIDECAR_WITHOUT_GPS.replace("IMG_0001", "IMG_0002");
+        let root = takeout("failed_downloads", &[
+            ("IMG_0001.jpg", &[]),
+            ("IMG_0001.jpg.json", SIDECAR_WITHOUT_GPS.as_bytes()),
+            ("IMG_0002.jpg", &[0xFF]),
+            ("IMG_0002.jpg.json", sidecar.as_bytes()),
+        ]);
+        let options = ProcessOptions { quarantine_folder: Some(DEFAULT_QUARANTINE_FOLDER.into()), ..ProcessOptions::default() };
+
+        let (summary, mut failures) = run(&root, options);
+        failures.sort();
+        assert_eq!((summary.processed, summary.errors), (0, 2));
+        assert_eq!(failures.len(), 2, "{:?}", failures);
+        assert!(failures[0].contains("Empty file (0 bytes)"), "{}", failures[0]);
+        assert!(failures[1].contains("Truncated file (1 bytes)"), "{}", failures[1]);
+
+        let quarantine = root.join("output").join(DEFAULT_QUARANTINE_FOLDER);
+        assert_eq!(std::fs::read(quarantine.join("IMG_0001.jpg")).unwrap(), b"");
+        assert_eq!(std::fs::read(quarantine.join("IMG_0002.jpg")).unwrap(), [0xFF]);
+        assert!(!root.join("output").join("IMG_0001.jpg").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn leftover_write_probes_do_not_block_a_run() {
+        let root = takeout("leftover_probes", &[
+            ("IMG_0001.jpg", &selftest::tiny_jpeg()),
+            ("IMG_0001.jpg.json", SIDECAR_WITHOUT_GPS.as_bytes()),
+        ]);
+        let output = root.join("output");
+        std::fs::create_dir_all(&output).unwrap();
+        let leftovers: Vec<PathBuf> = (0..64)
+            .map(|n| output.join(format!("{}{}_{}", WRITE_PROBE_PREFIX, std::process::id(), n)))
+            .chain([output.join(".metadata_fix_write_test")])
+            .collect();
+        for leftover in &leftovers {
+            std::fs::write(leftover, b"").unwrap();
+        }
+
+        let (summary, failures) = run(&root, ProcessOptions::default());
+        assert!(failures.is_empty(), "{:?}", failures);
+        assert_eq!((summary.processed, summary.errors), (1, 0));
+        assert!(leftovers.iter().all(|leftover| leftover.is_file()));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// The preview says what a real run does: the file's GPS survives a
+    /// sidecar without one.
+    #[test]
+    fn dry_run_reports_kept_gps_when_the_sidecar_has_none() {
+        let location = GpsLocation { latitude: 48.85837, longitude: 2.294481, altitude: None, accuracy: None, dop: None };
+        let jpeg = selftest::write_tiny_jpeg(
+            location, selftest::sample_datetime(), &ExifFields::default(), &XmpFields::default(), App1Placement::First,
+        )
+            .unwrap();
+        let root = takeout("dry_run_kept_gps", &[
+            ("IMG_0001.jpg", &jpeg),
+            ("IMG_0001.jpg.json", SIDECAR_WITHOUT_GPS.as_bytes()),
+        ]);
+
+        let (sender, receiver) = mpsc::channel();
+        let options = ProcessOptions { dry_run: true, ..ProcessOptions::default() };
+        process_photos(root.join("input"), root.join("output"), options, &RunControl::new(), sender).unwrap();
+        let previews: Vec<String> = receiver.try_iter()
+            .filter_map(|message| match message {
+                ProcessMessage::Status(text) if text.contains(DRY_RUN_NOTE_PREFIX) => Some(text),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(previews.len(), 1, "{:?}", previews);
+        assert!(previews[0].ends_with("GPS (48.858370, 2.294481) → (48.858370, 2.294481) [unchanged (kept)]"), "{}", previews[0]);
+
+        let (summary, failures) = run(&root, ProcessOptions::default());
+        assert!(failures.is_empty(), "{:?}", failures);
+        assert_eq!(summary.processed, 1);
+        let file = std::fs::File::open(root.join("output").join("IMG_0001.jpg")).unwrap();
+        let written = media::read_existing_metadata(&mut std::io::BufReader::new(file)).unwrap();
+        assert!(written.location.is_some_and(|written| same_place(written, location)));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+/// Counting semaphore bounding how many files the worker threads may have
+/// open at once, so large parallel runs don't hit "too many open files".
+pub struct FileLimiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+pub struct FilePermit<'a> {
+    limiter: &'a FileLimiter,
+}
+
+impl FileLimiter {
+    pub fn new(max_open_files: usize) -> Self {
+        Self {
+            available: Mutex::new(max_open_files.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn acquire(&self) -> FilePermit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        FilePermit { limiter: self }
+    }
+}
+
+impl Drop for FilePermit<'_> {
+    fn drop(&mut self) {
+        let mut available = self.limiter.available.lock().unwrap();
+        *available += 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+/// Token bucket capping how many bytes per second the workers write, shared
+/// by every thread. A write may overdraw the bucket; the writer then sleeps
+/// until the debt is repaid, so writers that follow it wait their turn.
+pub struct WriteLimiter {
+    bytes_per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl WriteLimiter {
+    pub fn new(megabytes_per_second: f64) -> Self {
+        let bytes_per_second = (megabytes_per_second * 1_000_000.0).max(1.0);
+        Self {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket { tokens: bytes_per_second, refilled_at: Instant::now() }),
+        }
+    }
+
+    /// Takes `bytes` from the bucket, blocking for as long as it's in debt.
+    pub fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.bytes_per_second;
+            // At most one second's worth builds up while idle.
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_second) - bytes as f64;
+            bucket.refilled_at = now;
+            (-bucket.tokens / self.bytes_per_second).max(0.0)
+        };
+        if wait > 0.0 {
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use chrono::{DateTime, FixedOffset, Utc};
+
+use crate::media::PlaceName;
+
+/// Looks up what a photo's coordinates mean on the ground: the local time
+/// offset, for writing dates as local time, and a place name for XMP.
+///
+/// The contract:
+/// - Both methods are called from many worker threads at once, once per
+///   geotagged file, so they should be cheap or cache their answers.
+/// - `None` means "don't know" and leaves the file exactly as it would be
+///   without a resolver. Only real sidecar coordinates are looked up, never
+///   the fallback location.
+/// - A panic fails the file being processed, not the run.
+///
+/// Implement it to plug in a timezone dataset or geocoding service; the
+/// defaults answer nothing.
+pub trait LocationResolver: Send + Sync {
+    /// The offset from UTC in force at the coordinates at instant `at`, so
+    /// zones with daylight saving give the right one for the date.
+    fn timezone(&self, _latitude: f64, _longitude: f64, _at: DateTime<Utc>) -> Option<FixedOffset> {
+        None
+    }
+
+    fn place_name(&self, _latitude: f64, _longitude: f64) -> Option<PlaceName> {
+        None
+    }
+}
+
+/// Resolves nothing; dates stay UTC.
+pub struct NoResolver;
+
+impl LocationResolver for NoResolver {}
+
+/// Offline and dependency-free: the nautical time zone, one hour per 15° of
+/// longitude from Greenwich. Exact at sea and close on most land, but it
+/// knows nothing of borders or daylight saving, so it can be an hour or more
+/// off (Spain, China, India's half hour).
+pub struct NauticalTimezone;
+
+impl LocationResolver for NauticalTimezone {
+    fn timezone(&self, _latitude: f64, longitude: f64, _at: DateTime<Utc>) -> Option<FixedOffset> {
+        let hours = (longitude / 15.0).round() as i32;
+        FixedOffset::east_opt(hours.clamp(-12, 12) * 3600)
+    }
+}
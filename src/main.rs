@@ -1,11 +1,50 @@
 use eframe::egui;
+use std::collections::VecDeque;
 use std::path::PathBuf;
-use std::sync::mpsc;
+use std::process::ExitCode;
+use std::sync::{mpsc, Arc};
 use std::thread;
-use serde_json::Value;
-use chrono::{DateTime, Utc};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use metadata_fix::control::{RunControl, RunState};
+use metadata_fix::hash::HashAlgorithm;
+use metadata_fix::media::{App1Placement, GpsLocation, ModifyDate};
+use metadata_fix::{
+    checkpoint, gpx, process_photos, read_file_list, resolve, runlog, GpsPolicy, OutputMode, Phase,
+    ProcessMessage, ProcessOptions, ProcessSummary, DEFAULT_JPEG_QUALITY, DEFAULT_QUARANTINE_FOLDER,
+};
+
+mod cli;
+mod file_table;
+#[cfg(feature = "server")]
+mod server;
+
+use file_table::FileTable;
+
+const DEFAULT_LOG_LIMIT: usize = 100;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct UiSettings {
+    dark_mode: bool,
+    log_font_size: f32,
+    notify_on_completion: bool,
+    /// Status messages kept on screen; the oldest are dropped past this.
+    /// `None` keeps everything.
+    log_limit: Option<usize>,
+}
 
-mod media;
+impl Default for UiSettings {
+    fn default() -> Self {
+        Self {
+            dark_mode: true,
+            log_font_size: 14.0,
+            notify_on_completion: false,
+            log_limit: Some(DEFAULT_LOG_LIMIT),
+        }
+    }
+}
 
 #[derive(Default)]
 struct MetadataApp {
@@ -14,70 +53,123 @@ struct MetadataApp {
     input_dir_text: String,
     output_dir_text: String,
     is_processing: bool,
+    phase: Option<Phase>,
     progress: f32,
-    status_messages: Vec<String>,
+    status_messages: VecDeque<String>,
     processed_count: usize,
     error_count: usize,
+    skipped_count: usize,
     total_files: usize,
     receiver: Option<mpsc::Receiver<ProcessMessage>>,
-}
-
-#[derive(Debug)]
-enum ProcessMessage {
-    Progress(f32),
-    Status(String),
-    FileProcessed(String, bool),
-    Completed(usize, usize),
-    Error(String),
+    control: Option<Arc<RunControl>>,
+    options: ProcessOptions,
+    ui_settings: UiSettings,
+    file_table: FileTable,
+    show_file_table: bool,
+    /// A native file dialog failed; paths have to be typed in instead.
+    dialogs_unavailable: bool,
 }
 
 impl eframe::App for MetadataApp {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, UI_SETTINGS_KEY, &self.ui_settings);
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        ctx.set_visuals(if self.ui_settings.dark_mode {
+            egui::Visuals::dark()
+        } else {
+            egui::Visuals::light()
+        });
+
         let mut should_clear_receiver = false;
 
-        if let Some(receiver) = &self.receiver {
+        // Taken out for the loop so handlers can borrow `self` mutably.
+        if let Some(receiver) = self.receiver.take() {
             while let Ok(msg) = receiver.try_recv() {
                 match msg {
+                    ProcessMessage::Phase(phase) => {
+                        self.phase = Some(phase);
+                        self.progress = 0.0;
+                    }
                     ProcessMessage::Progress(p) => self.progress = p,
                     ProcessMessage::Status(s) => {
-                        self.status_messages.push(s);
-                        if self.status_messages.len() > 100 {
-                            self.status_messages.remove(0);
-                        }
+                        self.push_status(s);
                     }
-                    ProcessMessage::FileProcessed(file, success) => {
-                        if success {
-                            self.processed_count += 1;
-                            self.status_messages.push(format!("✅ {}", file));
-                        } else {
-                            self.error_count += 1;
-                            self.status_messages.push(format!("❌ {}", file));
-                        }
-                        if self.status_messages.len() > 100 {
-                            self.status_messages.remove(0);
-                        }
+                    ProcessMessage::FilesDiscovered(sidecars) => {
+                        self.total_files += sidecars.len();
+                        self.file_table.add_pending(sidecars);
+                    }
+                    ProcessMessage::FileProcessed { sidecar, media_name, input_path, output_path, metadata } => {
+                        self.processed_count += 1;
+                        self.push_status(format!(
+                            "✅ {}: {} → {}",
+                            media_name, input_path.display(), output_path.display()
+                        ));
+                        self.file_table.set_done(&sidecar, media_name, &metadata);
                     }
-                    ProcessMessage::Completed(processed, errors) => {
+                    ProcessMessage::FileFailed { sidecar, message } => {
+                        self.error_count += 1;
+                        self.push_status(format!(
+                            "❌ {}: {}",
+                            sidecar.file_name().unwrap_or_default().to_string_lossy(), message
+                        ));
+                        self.file_table.set_error(&sidecar, message);
+                    }
+                    ProcessMessage::FileSkipped { sidecar, media_name } => {
+                        self.skipped_count += 1;
+                        self.push_status(format!("⏭ {}", media_name));
+                        self.file_table.set_skipped(&sidecar, media_name);
+                    }
+                    ProcessMessage::Completed(summary) => {
+                        let ProcessSummary { processed, errors, skipped, .. } = summary;
                         self.is_processing = false;
                         self.processed_count = processed;
                         self.error_count = errors;
-                        self.status_messages.push(format!(
-                            "🎉 Processing complete! {} files processed, {} errors",
-                            processed, errors
+                        self.skipped_count = skipped;
+                        self.push_status(format!(
+                            "🎉 Processing complete! {} files processed, {} errors, {} skipped",
+                            processed, errors, skipped
                         ));
+                        for (extension, counts) in &summary.per_format_counts {
+                            let extension = if extension.is_empty() { "(no extension)" } else { extension };
+                            self.push_status(format!(
+                                "   {}: {} ok / {} errors / {} skipped",
+                                extension, counts.processed, counts.errors, counts.skipped
+                            ));
+                        }
                         should_clear_receiver = true;
+
+                        if self.ui_settings.notify_on_completion {
+                            notify_completion(ctx, processed, errors, skipped);
+                        }
                     }
                     ProcessMessage::Error(e) => {
                         self.is_processing = false;
-                        self.status_messages.push(format!("💥 Fatal error: {}", e));
+                        self.push_status(format!("💥 Fatal error: {}", e));
                         should_clear_receiver = true;
                     }
                 }
             }
+
+            // The worker hung up without reporting an outcome.
+            if self.is_processing
+                && !should_clear_receiver
+                && matches!(receiver.try_recv(), Err(mpsc::TryRecvError::Disconnected))
+            {
+                self.is_processing = false;
+                self.push_status("💥 Fatal error: processing stopped unexpectedly".to_string());
+                should_clear_receiver = true;
+            }
+
+            if !should_clear_receiver {
+                self.receiver = Some(receiver);
+            }
         }
 
         if should_clear_receiver {
             self.receiver = None;
+            self.control = None;
         }
 
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -86,31 +178,255 @@ impl eframe::App for MetadataApp {
 
             ui.horizontal(|ui| {
                 ui.label("📁 Input Directory:");
-                if ui.button("Browse...").clicked() {
-                    if let Some(path) = rfd::FileDialog::new().pick_folder() {
-                        self.input_dir_text = path.display().to_string();
-                        self.input_dir = Some(path);
+                if ui.button("Browse...").clicked()
+                    && let Some(path) = self.run_dialog(|| rfd::FileDialog::new().pick_folder())
+                {
+                    self.input_dir_text = path.display().to_string();
+                    self.input_dir = Some(path);
+                }
+                if ui.button("Archive...").clicked()
+                    && let Some(path) = self.run_dialog(|| {
+                        rfd::FileDialog::new()
+                            .add_filter("Takeout archive", &["zip", "tgz", "gz"])
+                            .pick_file()
+                    })
+                {
+                    self.input_dir_text = path.display().to_string();
+                    self.input_dir = Some(path);
+                }
+                if ui.button("Files...").clicked()
+                    && let Some(media) = self.run_dialog(|| rfd::FileDialog::new().pick_files())
+                {
+                    self.select_media_files(media);
+                }
+            });
+            if ui.text_edit_singleline(&mut self.input_dir_text).changed() {
+                self.input_dir = typed_path(&self.input_dir_text);
+            }
+            ui.horizontal(|ui| {
+                if ui.button("File list...").clicked()
+                    && let Some(path) = self.run_dialog(|| rfd::FileDialog::new().add_filter("Text", &["txt"]).pick_file())
+                {
+                    match read_file_list(&path) {
+                        Ok(files) => self.options.file_list = Some(files),
+                        Err(e) => self.push_status(format!("❌ {}", e)),
+                    }
+                }
+                if let Some(files) = &self.options.file_list {
+                    ui.label(format!("Only the {} listed sidecars", files.len()));
+                    if ui.small_button("✖").clicked() {
+                        self.options.file_list = None;
                     }
                 }
             });
-            ui.text_edit_singleline(&mut self.input_dir_text);
             ui.add_space(10.0);
 
             ui.horizontal(|ui| {
                 ui.label("📤 Output Directory:");
-                if ui.button("Browse...").clicked() {
-                    if let Some(path) = rfd::FileDialog::new().pick_folder() {
-                        self.output_dir_text = path.display().to_string();
-                        self.output_dir = Some(path);
+                if ui.button("Browse...").clicked()
+                    && let Some(path) = self.run_dialog(|| rfd::FileDialog::new().pick_folder())
+                {
+                    self.output_dir_text = path.display().to_string();
+                    self.output_dir = Some(path);
+                }
+            });
+            if ui.text_edit_singleline(&mut self.output_dir_text).changed() {
+                self.output_dir = typed_path(&self.output_dir_text);
+            }
+            if self.dialogs_unavailable {
+                ui.label("⚠ File dialogs don't work on this desktop; type the paths into the fields above.");
+            }
+            ui.add_space(10.0);
+
+            ui.collapsing("⚙ Settings", |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Max open files:");
+                    ui.add(egui::DragValue::new(&mut self.options.max_open_files).range(1..=4096));
+                });
+                ui.horizontal(|ui| {
+                    let mut limit_jobs = self.options.jobs.is_some();
+                    if ui.checkbox(&mut limit_jobs, "Limit worker threads to").changed() {
+                        self.options.jobs = limit_jobs.then(|| thread::available_parallelism().map_or(1, |n| n.get()));
+                    }
+                    if let Some(jobs) = &mut self.options.jobs {
+                        ui.add(egui::DragValue::new(jobs).range(1..=256));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    let mut limit_writes = self.options.write_limit_mb_per_sec.is_some();
+                    if ui.checkbox(&mut limit_writes, "Limit writes to").changed() {
+                        self.options.write_limit_mb_per_sec = limit_writes.then_some(50.0);
+                    }
+                    if let Some(limit) = &mut self.options.write_limit_mb_per_sec {
+                        ui.add(egui::DragValue::new(limit).range(1.0..=2000.0).suffix(" MB/s"));
                     }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Output:");
+                    ui.radio_value(&mut self.options.output_mode, OutputMode::MirrorTree, "Mirror into output directory");
+                    ui.radio_value(&mut self.options.output_mode, OutputMode::Flatten, "Flatten into output directory");
+                    ui.radio_value(&mut self.options.output_mode, OutputMode::FixedSuffix, "Write name_fixed next to original");
+                });
+                ui.add_enabled(
+                    self.options.output_mode == OutputMode::FixedSuffix,
+                    egui::Checkbox::new(&mut self.options.overwrite_existing, "Overwrite existing name_fixed files"),
+                );
+                ui.checkbox(&mut self.options.fill_missing_only, "Only fill in missing date/location");
+                ui.checkbox(&mut self.options.include_trashed, "Include photos that are in the Google Photos trash");
+                ui.horizontal(|ui| {
+                    let mut use_threshold = self.options.date_change_threshold_hours.is_some();
+                    if ui.checkbox(&mut use_threshold, "Only change dates that are missing or off by more than").changed() {
+                        self.options.date_change_threshold_hours = use_threshold.then_some(24.0);
+                    }
+                    if let Some(hours) = &mut self.options.date_change_threshold_hours {
+                        ui.add(egui::DragValue::new(hours).range(0.0..=100_000.0).suffix(" h"));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Existing GPS:");
+                    ui.radio_value(&mut self.options.gps_policy, GpsPolicy::PreferJson, "Prefer JSON");
+                    ui.radio_value(&mut self.options.gps_policy, GpsPolicy::PreferExisting, "Prefer existing");
+                    ui.radio_value(&mut self.options.gps_policy, GpsPolicy::OnlyFillIfMissing, "Only fill if missing");
+                });
+                ui.checkbox(&mut self.options.export_gpx, format!("Export GPX track ({})", gpx::GPX_FILE_NAME));
+                ui.checkbox(
+                    &mut self.options.resume_from_log,
+                    format!("Resume: skip files {} marks as finished", runlog::RUN_LOG_FILE_NAME),
+                );
+                ui.checkbox(
+                    &mut self.options.resume_from_checkpoint,
+                    format!("Resume after a crash from {}", checkpoint::CHECKPOINT_FILE_NAME),
+                );
+                ui.checkbox(&mut self.options.dry_run, "Dry run: write nothing, show what would change");
+                ui.checkbox(&mut self.options.strict, "Strict: count files with any warning as failed");
+                ui.checkbox(&mut self.options.size_up_front, "Size all media before starting (steadier progress, slower start)");
+                ui.checkbox(&mut self.options.stream_scan, "Start processing while still scanning (for very large exports)");
+                ui.checkbox(&mut self.options.search_whole_tree, "Look for media missing from an album folder anywhere in the input");
+                ui.checkbox(
+                    &mut self.options.borrow_sibling_timestamp,
+                    "Borrow the date from a same-named file when the JSON has none",
+                );
+                ui.checkbox(&mut self.options.verify_outputs, "Verify every output decodes after the run");
+                ui.horizontal(|ui| {
+                    let mut hash = self.options.content_hash.is_some();
+                    if ui.checkbox(&mut hash, format!("Record image hashes in {}:", runlog::RUN_LOG_FILE_NAME)).changed() {
+                        self.options.content_hash = hash.then_some(HashAlgorithm::PixelSha256);
+                    }
+                    if let Some(algorithm) = &mut self.options.content_hash {
+                        for choice in [HashAlgorithm::PixelSha256, HashAlgorithm::Average] {
+                            ui.radio_value(algorithm, choice, choice.label());
+                        }
+                    }
+                });
+                ui.checkbox(&mut self.options.png_creation_time, "Also write PNG \"Creation Time\" text");
+                ui.checkbox(&mut self.options.photo_date_as_file_time, "Set file dates to when the photo was taken");
+                ui.horizontal(|ui| {
+                    let mut convert = self.options.png_to_jpeg_quality.is_some();
+                    if ui.checkbox(&mut convert, "Convert PNGs to JPEG (lossy), quality:").changed() {
+                        self.options.png_to_jpeg_quality = convert.then_some(DEFAULT_JPEG_QUALITY);
+                    }
+                    if let Some(quality) = &mut self.options.png_to_jpeg_quality {
+                        ui.add(egui::Slider::new(quality, 1..=100));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("JPEG EXIF:");
+                    ui.radio_value(&mut self.options.app1_placement, App1Placement::AfterJfif, "After JFIF");
+                    ui.radio_value(&mut self.options.app1_placement, App1Placement::First, "First");
+                });
+                ui.checkbox(&mut self.options.force_upright, "Pixels are already upright (write Orientation = normal)");
+                ui.horizontal(|ui| {
+                    ui.label("EXIF DateTime (modified):");
+                    ui.radio_value(&mut self.options.modify_date, ModifyDate::SameAsOriginal, "Capture date");
+                    ui.radio_value(&mut self.options.modify_date, ModifyDate::ProcessingTime, "Processing time");
+                    ui.radio_value(&mut self.options.modify_date, ModifyDate::Omit, "Leave unset");
+                });
+                ui.checkbox(&mut self.options.subsec_and_offset, "Write sub-seconds and UTC offset with the dates");
+                ui.checkbox(
+                    &mut self.options.local_time_from_longitude,
+                    "Write local time, with the offset estimated from the longitude (approximate)",
+                );
+                if ui.button("📷 Lightroom-compatible").on_hover_text("Set the EXIF and XMP options above to what Lightroom reads").clicked() {
+                    self.options.apply_lightroom_preset();
                 }
+                ui.horizontal(|ui| {
+                    ui.label("Artist:");
+                    ui.text_edit_singleline(&mut self.options.artist);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Copyright:");
+                    ui.text_edit_singleline(&mut self.options.copyright);
+                });
+                ui.checkbox(&mut self.options.write_xmp, "Write XMP (RFC 3339 dates)");
+                ui.indent("xmp_options", |ui| {
+                    ui.add_enabled(
+                        self.options.write_xmp,
+                        egui::Checkbox::new(&mut self.options.embed_source_url, "Embed Google Photos URL"),
+                    );
+                    ui.add_enabled(
+                        self.options.write_xmp,
+                        egui::Checkbox::new(&mut self.options.album_keyword, "Add the album folder as a keyword"),
+                    );
+                });
+                ui.checkbox(&mut self.options.iptc_album_keyword, "Add the album folder to JPEG IPTC keywords (existing ones are kept)");
+                ui.horizontal(|ui| {
+                    let mut use_fallback = self.options.fallback_location.is_some();
+                    if ui.checkbox(&mut use_fallback, "Fallback location for files without one:").changed() {
+                        self.options.fallback_location = use_fallback
+                            .then_some(GpsLocation { latitude: 0.0, longitude: 0.0, altitude: None, accuracy: None, dop: None });
+                    }
+                    if let Some(location) = &mut self.options.fallback_location {
+                        ui.label("Lat");
+                        ui.add(egui::DragValue::new(&mut location.latitude).range(-90.0..=90.0).speed(0.0001));
+                        ui.label("Lon");
+                        ui.add(egui::DragValue::new(&mut location.longitude).range(-180.0..=180.0).speed(0.0001));
+                        // Unticked writes no GPSAltitude at all, which is not
+                        // the same as sea level.
+                        let mut has_altitude = location.altitude.is_some();
+                        if ui.checkbox(&mut has_altitude, "Alt (m)").changed() {
+                            location.altitude = has_altitude.then_some(0.0);
+                        }
+                        if let Some(altitude) = &mut location.altitude {
+                            ui.add(egui::DragValue::new(altitude).range(-5000.0..=50000.0));
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    let mut quarantine = self.options.quarantine_folder.is_some();
+                    if ui.checkbox(&mut quarantine, "Copy failed files to:").changed() {
+                        self.options.quarantine_folder = quarantine.then(|| DEFAULT_QUARANTINE_FOLDER.to_string());
+                    }
+                    if let Some(folder) = &mut self.options.quarantine_folder {
+                        ui.text_edit_singleline(folder);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Theme:");
+                    ui.selectable_value(&mut self.ui_settings.dark_mode, true, "🌙 Dark");
+                    ui.selectable_value(&mut self.ui_settings.dark_mode, false, "☀ Light");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Log font size:");
+                    ui.add(egui::Slider::new(&mut self.ui_settings.log_font_size, 10.0..=28.0));
+                });
+                ui.checkbox(&mut self.ui_settings.notify_on_completion, "Notify me when processing finishes");
+                ui.horizontal(|ui| {
+                    let mut limited = self.ui_settings.log_limit.is_some();
+                    if ui.checkbox(&mut limited, "Keep at most").changed() {
+                        self.ui_settings.log_limit = limited.then_some(DEFAULT_LOG_LIMIT);
+                    }
+                    if let Some(limit) = &mut self.ui_settings.log_limit {
+                        ui.add(egui::DragValue::new(limit).range(10..=1_000_000));
+                    }
+                    ui.label("log messages");
+                });
             });
-            ui.text_edit_singleline(&mut self.output_dir_text);
             ui.add_space(20.0);
 
             ui.horizontal(|ui| {
+                let needs_output_dir = self.options.output_mode != OutputMode::FixedSuffix;
                 let can_process = self.input_dir.is_some() 
-                && self.output_dir.is_some() 
+                && (self.output_dir.is_some() || !needs_output_dir)
                 && !self.is_processing;
 
                 if ui.add_enabled(can_process, egui::Button::new("Process Media"))
@@ -118,33 +434,93 @@ impl eframe::App for MetadataApp {
                     self.start_processing();
                 }
 
-                if self.is_processing {
-                    ui.spinner();
-                    ui.label("Processing...");
+                if let Some(control) = self.control.clone() {
+                    match control.state() {
+                        RunState::Running => {
+                            if ui.button("⏸ Pause").clicked() {
+                                control.set(RunState::Paused);
+                            }
+                        }
+                        RunState::Paused => {
+                            if ui.button("▶ Resume").clicked() {
+                                control.set(RunState::Running);
+                            }
+                        }
+                        RunState::Cancelled => {}
+                    }
+                    if control.state() != RunState::Cancelled && ui.button("⏹ Cancel").clicked() {
+                        control.set(RunState::Cancelled);
+                    }
+
+                    match control.state() {
+                        RunState::Running => {
+                            ui.spinner();
+                            ui.label("Processing...");
+                        }
+                        RunState::Paused => {
+                            ui.label("⏸ Paused");
+                        }
+                        RunState::Cancelled => {
+                            ui.spinner();
+                            ui.label("Cancelling after current files...");
+                        }
+                    }
                 }
             });
 
             ui.add_space(20.0);
 
             if self.is_processing || self.progress > 0.0 {
-                ui.label(format!("Progress: {:.1}%", self.progress * 100.0));
-                ui.add(egui::ProgressBar::new(self.progress).show_percentage());
+                match self.phase {
+                    Some(phase) if self.is_processing && !phase.is_measurable() => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label(format!("{}...", phase.label()));
+                        });
+                    }
+                    phase => {
+                        let label = phase.map_or("Progress", Phase::label);
+                        ui.label(format!("{}: {:.1}%", label, self.progress * 100.0));
+                        ui.add(egui::ProgressBar::new(self.progress).show_percentage());
+                    }
+                }
                 ui.add_space(10.0);
 
                 ui.label(format!(
-                    "Processed: {} | Errors: {} | Total: {}",
-                    self.processed_count, self.error_count, self.total_files
+                    "Processed: {} | Errors: {} | Skipped: {} | Total: {}",
+                    self.processed_count, self.error_count, self.skipped_count, self.total_files
                 ));
                 ui.add_space(10.0);
             }
 
-            if !self.status_messages.is_empty() {
-                ui.label("📋 Status Log:");
+            if !self.file_table.is_empty() {
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut self.show_file_table, false, "📋 Status Log");
+                    ui.selectable_value(&mut self.show_file_table, true, "🗂 Files");
+                });
+            }
+
+            if self.show_file_table && !self.file_table.is_empty() {
+                self.file_table.show(ui);
+            } else if !self.status_messages.is_empty() {
+                ui.horizontal(|ui| {
+                    ui.label("📋 Status Log:");
+                    if ui.small_button("💾 Save log...").clicked()
+                        && let Some(path) = self.run_dialog(|| {
+                            rfd::FileDialog::new().set_file_name("metadata_fix_status.txt").save_file()
+                        })
+                    {
+                        let text = self.status_messages.iter().map(|m| format!("{}\n", m)).collect::<String>();
+                        if let Err(e) = std::fs::write(&path, text) {
+                            self.push_status(format!("❌ Could not save log: {}", e));
+                        }
+                    }
+                });
                 egui::ScrollArea::vertical()
                     .max_height(200.0)
                     .show(ui, |ui| {
                         for message in &self.status_messages {
-                            ui.label(message);
+                            ui.label(egui::RichText::new(message).size(self.ui_settings.log_font_size));
                         }
                     });
             }
@@ -156,147 +532,132 @@ impl eframe::App for MetadataApp {
     }
 }
 
-impl MetadataApp {
-    fn start_processing(&mut self) {
-        let input_dir = self.input_dir.clone().unwrap();
-        let output_dir = self.output_dir.clone().unwrap();
+const UI_SETTINGS_KEY: &str = "ui_settings";
 
-        let (sender, receiver) = mpsc::channel();
-        self.receiver = Some(receiver);
-        self.is_processing = true;
-        self.progress = 0.0;
-        self.processed_count = 0;
-        self.error_count = 0;
-        self.status_messages.clear();
+/// A dialog that closes sooner than this was never shown.
+const DIALOG_MIN_LIFETIME: Duration = Duration::from_millis(150);
 
-        thread::spawn(move || {
-            process_photos(input_dir, output_dir, sender);
-        });
-    }
+/// The path typed into a directory field, if any.
+fn typed_path(text: &str) -> Option<PathBuf> {
+    let text = text.trim();
+    (!text.is_empty()).then(|| PathBuf::from(text))
 }
 
-fn process_photos(
-    input_dir: PathBuf,
-    output_dir: PathBuf,
-    sender: mpsc::Sender<ProcessMessage>,
-) {
-    let _ = sender.send(ProcessMessage::Status("🔍 Scanning directories...".to_string()));
-
-    if let Err(e) = std::fs::create_dir_all(&output_dir) {
-        let _ = sender.send(ProcessMessage::Error(format!("Could not create output directory: {}", e)));
-        return;
+impl MetadataApp {
+    fn new(cc: &eframe::CreationContext<'_>) -> Self {
+        let ui_settings = cc.storage
+            .and_then(|storage| eframe::get_value(storage, UI_SETTINGS_KEY))
+            .unwrap_or_default();
+
+        Self {
+            ui_settings,
+            ..Default::default()
+        }
     }
 
-    let mut json_files = Vec::new();
-    let mut dirs_to_check = vec![input_dir.clone()];
-
-    while let Some(dir) = dirs_to_check.pop() {
-        match std::fs::read_dir(&dir) {
-            Ok(entries) => {
-                for entry in entries.flatten() {
-                    let path = entry.path();
-                    if path.is_dir() {
-                        dirs_to_check.push(path);
-                    } else if path.extension().map_or(false, |ext| ext == "json") {
-                        json_files.push(path);
-                    }
+    /// Runs a native file dialog. On some Wayland and minimal desktops rfd
+    /// panics, or returns `None` at once without showing anything; both mean
+    /// the user has to type paths instead.
+    fn run_dialog<T>(&mut self, dialog: impl FnOnce() -> Option<T>) -> Option<T> {
+        let opened = Instant::now();
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(dialog)) {
+            Ok(Some(picked)) => Some(picked),
+            // Nobody cancels a dialog this fast; it never appeared.
+            Ok(None) if opened.elapsed() >= DIALOG_MIN_LIFETIME => None,
+            _ => {
+                if !self.dialogs_unavailable {
+                    self.dialogs_unavailable = true;
+                    self.push_status("⚠️ File dialog unavailable; type the path instead".to_string());
                 }
+                None
             }
-            Err(_) => continue,
         }
     }
 
-    let total_files = json_files.len();
-    let _ = sender.send(ProcessMessage::Status(format!("📊 Found {} JSON files to process", total_files)));
-
-    let mut processed_count = 0;
-    let mut error_count = 0;
+    /// Processes just the picked media files: each one's sidecar becomes the
+    /// file list, and the input directory is the folder holding them all.
+    fn select_media_files(&mut self, media: Vec<PathBuf>) {
+        let mut sidecars = Vec::new();
+        for path in media {
+            match resolve::find_sidecar(&path) {
+                Some(sidecar) => sidecars.push(sidecar),
+                None => self.push_status(format!("⚠️ No JSON sidecar found for {}", path.display())),
+            }
+        }
 
-    for (index, json_file) in json_files.iter().enumerate() {
-        let progress = index as f32 / total_files as f32;
-        let _ = sender.send(ProcessMessage::Progress(progress));
+        if let Some(root) = resolve::common_dir(&sidecars) {
+            self.input_dir_text = root.display().to_string();
+            self.input_dir = Some(root);
+            self.options.file_list = Some(sidecars);
+        }
+    }
 
-        match process_single_file(json_file, &input_dir, &output_dir) {
-            Ok(image_name) => {
-                processed_count += 1;
-                let _ = sender.send(ProcessMessage::FileProcessed(image_name, true));
-            }
-            Err(e) => {
-                error_count += 1;
-                let _ = sender.send(ProcessMessage::FileProcessed(
-                    format!("{}: {}", json_file.file_name().unwrap_or_default().to_string_lossy(), e),
-                    false
-                ));
+    /// Appends to the on-screen log, dropping the oldest messages past the
+    /// configured limit.
+    fn push_status(&mut self, message: String) {
+        self.status_messages.push_back(message);
+        if let Some(limit) = self.ui_settings.log_limit {
+            while self.status_messages.len() > limit {
+                self.status_messages.pop_front();
             }
         }
     }
 
-    let _ = sender.send(ProcessMessage::Progress(1.0));
-    let _ = sender.send(ProcessMessage::Completed(processed_count, error_count));
-}
-
-fn process_single_file(
-    json_file: &PathBuf,
-    input_dir: &PathBuf,
-    output_dir: &PathBuf,
-) -> Result<String, String> {
-    let json_string = std::fs::read_to_string(json_file)
-        .map_err(|e| format!("Error reading JSON: {}", e))?;
-
-    let json_data: Value = serde_json::from_str(&json_string)
-        .map_err(|e| format!("Error parsing JSON: {}", e))?;
-
-    let media_name = json_data["title"].as_str()
-        .ok_or("No title found in JSON")?;
-
-    let latitude = json_data["geoData"]["latitude"].as_f64()
-        .ok_or("No latitude found in JSON")?;
-
-    let longitude = json_data["geoData"]["longitude"].as_f64()
-        .ok_or("No longitude found in JSON")?;
-
-    let altitude = json_data["geoData"]["altitude"].as_f64().unwrap_or(0.0);
-
-    let timestamp_str = json_data["photoTakenTime"]["timestamp"].as_str()
-        .ok_or("No timestamp found in JSON")?;
-
-    let timestamp: i64 = timestamp_str.parse()
-        .map_err(|_| "Invalid timestamp format")?;
+    fn start_processing(&mut self) {
+        let input_dir = self.input_dir.clone().unwrap();
+        let output_dir = self.output_dir.clone().unwrap_or_else(|| input_dir.clone());
+        let options = self.options.clone();
+        let control = Arc::new(RunControl::new());
 
-    let datetime = DateTime::<Utc>::from_timestamp(timestamp, 0)
-        .ok_or("Invalid timestamp value")?;
+        let (sender, receiver) = mpsc::channel();
+        self.receiver = Some(receiver);
+        self.control = Some(control.clone());
+        self.is_processing = true;
+        self.phase = None;
+        self.progress = 0.0;
+        self.processed_count = 0;
+        self.error_count = 0;
+        self.skipped_count = 0;
+        self.total_files = 0;
+        self.status_messages.clear();
+        self.file_table.clear();
 
-    let image_path = json_file.parent().unwrap().join(media_name);
-    if !image_path.exists() {
-        return Err("Image file not found".to_string());
+        thread::spawn(move || {
+            let _ = process_photos(input_dir, output_dir, options, &control, sender);
+        });
     }
+}
 
-    let relative_path = image_path.strip_prefix(input_dir)
-        .map_err(|_| "Could not determine relative path")?;
-    let output_path = output_dir.join(relative_path);
+fn notify_completion(ctx: &egui::Context, processed: usize, errors: usize, skipped: usize) {
+    ctx.send_viewport_cmd(egui::ViewportCommand::RequestUserAttention(
+        egui::UserAttentionType::Informational,
+    ));
+
+    // Desktop notification servers can be slow to answer; don't stall the UI.
+    thread::spawn(move || {
+        let _ = notify_rust::Notification::new()
+            .summary("Metadata Fix finished")
+            .body(&format!("{} processed, {} errors, {} skipped", processed, errors, skipped))
+            .show();
+    });
+}
 
-    if let Some(parent) = output_path.parent() {
-        std::fs::create_dir_all(parent)
-            .map_err(|e| format!("Error creating output directory: {}", e))?;
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args);
     }
 
-    let image_path_str = image_path.to_string_lossy();
-    let output_path_str = output_path.to_string_lossy();
-
-    if media_name.to_lowercase().ends_with(".jpg") || media_name.to_lowercase().ends_with(".jpeg") {
-        media::update_jpeg_metadata(&image_path_str, Some(&output_path_str), latitude, longitude, altitude, datetime)
-            .map_err(|e| format!("JPEG processing error: {}", e))?;
-    } else if media_name.to_lowercase().ends_with(".png") {
-        media::update_png_metadata(&image_path_str, Some(&output_path_str), latitude, longitude, altitude, datetime)
-            .map_err(|e| format!("PNG processing error: {}", e))?;
-    } else {
-        return Err("Unsupported file format".to_string());
+    match run_gui() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
     }
-
-    Ok(media_name.to_string())
 }
 
-fn main() -> Result<(), eframe::Error> {
+fn run_gui() -> Result<(), eframe::Error> {
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([600.0, 500.0])
@@ -307,6 +668,6 @@ fn main() -> Result<(), eframe::Error> {
     eframe::run_native(
         "Metadata Fix",
         options,
-        Box::new(|_cc| Ok(Box::<MetadataApp>::default())),
+        Box::new(|cc| Ok(Box::new(MetadataApp::new(cc)))),
     )
 }
@@ -1,75 +1,542 @@
 use std::fs;
-use std::io::{Read, Write, BufWriter, Cursor};
-use exif::{In, Tag, Value, Field, Rational};
-use chrono::{DateTime, Utc};
-use png::{Decoder, Encoder};
+use std::io::{self, BufRead, Read, Seek, Write, BufWriter, Cursor};
+use std::path::Path;
+use exif::{Context, In, Tag, Value, Field, Rational};
+use chrono::{DateTime, FixedOffset, NaiveDate, SecondsFormat, Utc};
+use png::{Decoder, Transformations};
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+/// Marks coordinates as coming from the Takeout sidecar rather than the
+/// camera's own GPS.
+const GPS_PROCESSING_METHOD: &str = "GOOGLE-TAKEOUT-JSON";
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Comfortably fits the TIFF we write (GPS plus dates is ~400 bytes), so the
+/// writer's buffer never has to grow.
+const EXIF_SIZE_HINT: usize = 1024;
+
+/// Namespace identifier that opens an XMP APP1 segment.
+const XMP_JPEG_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// Extended XMP, the overflow of a main packet too big for one APP1.
+const XMP_EXTENSION_HEADER: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+
+/// Opens an APP13 holding Photoshop image resources, IPTC among them.
+const PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+const IPTC_RESOURCE_ID: u16 = 0x0404;
+const IPTC_CODED_CHARACTER_SET: (u8, u8) = (1, 90);
+const IPTC_KEYWORDS: (u8, u8) = (2, 25);
+/// The IIM limit for one keyword.
+const IPTC_KEYWORD_MAX_BYTES: usize = 64;
+
+/// A segment's 16-bit length field counts itself.
+const MAX_SEGMENT_PAYLOAD: usize = 0xFFFF - 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct GpsLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Meters above sea level. `None` means no altitude is written.
+    pub altitude: Option<f64>,
+    /// Horizontal accuracy radius in meters, written as
+    /// GPSHPositioningError.
+    pub accuracy: Option<f64>,
+    /// Dilution of precision as the receiver reported it, written as GPSDOP.
+    pub dop: Option<f64>,
+}
+
+/// Altitudes outside this range (meters) are treated as corrupt rather than
+/// written: below the deepest mines, above anything a balloon reaches.
+const ALTITUDE_RANGE: std::ops::RangeInclusive<f64> = -5_000.0..=50_000.0;
+
+/// Accuracy radii and DOP values are written in thousandths with a `u32` numerator; larger
+/// values would saturate to a wrong one.
+const ERROR_ESTIMATE_RANGE: std::ops::RangeInclusive<f64> = 0.0..=u32::MAX as f64 / 1000.0;
+
+impl GpsLocation {
+    /// Latitude within [-90, 90] and longitude within [-180, 180].
+    pub fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.latitude) && (-180.0..=180.0).contains(&self.longitude)
+    }
+
+    /// Whether `altitude` is finite and plausible, so it fits the EXIF
+    /// rational without wrapping.
+    pub fn is_valid_altitude(altitude: f64) -> bool {
+        ALTITUDE_RANGE.contains(&altitude)
+    }
+
+    /// Whether an accuracy radius or DOP is finite, non-negative and fits the EXIF
+    /// rational without saturating.
+    pub fn is_valid_error_estimate(value: f64) -> bool {
+        ERROR_ESTIMATE_RANGE.contains(&value)
+    }
+}
+
+/// Per-file EXIF tags written alongside the date and location.
+#[derive(Clone, Debug, Default)]
+pub struct ExifFields {
+    /// Caption: ImageDescription, plus UserComment when it isn't ASCII.
+    /// ImageDescription is ASCII only, so there it loses its accents and
+    /// anything else non-ASCII becomes `?`; UserComment keeps it whole as
+    /// UTF-16, and [`XmpFields::description`] as UTF-8.
+    pub description: Option<String>,
+    /// Write Orientation = 1 for files whose pixels are already upright,
+    /// replacing the file's own Orientation, so a viewer doesn't rotate the
+    /// pixels a second time. Also makes it explicit for files with none,
+    /// which a viewer might otherwise take from elsewhere (XMP, a database).
+    pub force_upright: bool,
+    pub modify_date: ModifyDate,
+    /// Also write SubSecTime* (milliseconds) and OffsetTime* next to each
+    /// date. Without an offset readers such as Lightroom take the dates as
+    /// local time.
+    pub subsec_and_offset: bool,
+    /// The offset from UTC where the photo was taken. Dates are written as
+    /// local time with it, and OffsetTime* always accompany them; without
+    /// it they are UTC.
+    pub utc_offset: Option<FixedOffset>,
+    pub lens_make: Option<String>,
+    pub lens_model: Option<String>,
+    pub artist: Option<String>,
+    pub copyright: Option<String>,
+}
+
+/// What goes in IFD0 DateTime, which EXIF defines as the file's last
+/// modification. Some catalogs sort or dedupe on it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ModifyDate {
+    /// The capture date, like DateTimeOriginal.
+    #[default]
+    SameAsOriginal,
+    /// Don't write DateTime; a JPEG keeps its own, if it has one.
+    Omit,
+    /// The time the file is rewritten.
+    ProcessingTime,
+}
+
+/// Properties written to an XMP packet alongside the EXIF. Nothing is
+/// written when every field is empty.
+#[derive(Clone, Debug, Default)]
+pub struct XmpFields {
+    /// Also write the date as RFC 3339 `xmp:CreateDate` and
+    /// `exif:DateTimeOriginal`, for tools that read XMP over EXIF.
+    pub dates: bool,
+    /// Link back to the photo in Google Photos, stored as `dc:source`.
+    pub source_url: Option<String>,
+    /// Album the photo was exported from, stored as a `dc:subject` keyword.
+    pub album: Option<String>,
+    /// Copyright notice, stored as `dc:rights`.
+    pub rights: Option<String>,
+    /// Caption, stored as `dc:description` in full UTF-8.
+    pub description: Option<String>,
+    /// Where the photo was taken, stored as `photoshop:City`, `State` and
+    /// `Country`.
+    pub place: Option<PlaceName>,
+}
+
+impl XmpFields {
+    pub fn is_empty(&self) -> bool {
+        !self.dates
+            && self.source_url.is_none()
+            && self.album.is_none()
+            && self.rights.is_none()
+            && self.description.is_none()
+            && self.place.is_none()
+    }
+}
+
+/// A reverse-geocoded location. Any part may be unknown.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlaceName {
+    pub city: Option<String>,
+    pub region: Option<String>,
+    pub country: Option<String>,
+}
+
+/// IPTC-IIM datasets written to a JPEG's Photoshop APP13. With no keywords
+/// the file's APP13, if any, is copied as it is.
+#[derive(Clone, Debug, Default)]
+pub struct IptcFields {
+    /// Merged with the file's own Keywords (2:25); nothing is removed.
+    pub keywords: Vec<String>,
+}
+
+/// Where our APP1 segments go in a rewritten JPEG. Readers disagree on
+/// which order they accept.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum App1Placement {
+    /// After the JFIF APP0, if there is one, as the JFIF spec requires.
+    #[default]
+    AfterJfif,
+    /// Straight after SOI, ahead of every other segment.
+    First,
+}
+
+/// Date and location already embedded in a file, if any.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExistingMetadata {
+    pub datetime: Option<DateTime<Utc>>,
+    pub location: Option<GpsLocation>,
+}
+
+/// Reads EXIF from images, and `moov` from MP4/QuickTime videos.
+pub fn read_existing_metadata<R: BufRead + Seek>(
+    reader: &mut R,
+) -> Result<ExistingMetadata, Box<dyn std::error::Error>> {
+    if let Some(existing) = read_video_metadata(reader)? {
+        return Ok(existing);
+    }
+    reader.seek(io::SeekFrom::Start(0))?;
+
+    let exif = match exif::Reader::new().read_from_container(reader) {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_)) => return Ok(ExistingMetadata::default()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let datetime = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .and_then(|field| match &field.value {
+            Value::Ascii(values) => values.first().and_then(|v| exif::DateTime::from_ascii(v).ok()),
+            _ => None,
+        })
+        .and_then(|dt| {
+            NaiveDate::from_ymd_opt(dt.year as i32, dt.month as u32, dt.day as u32)?
+                .and_hms_opt(dt.hour as u32, dt.minute as u32, dt.second as u32)
+        })
+        .map(|naive| naive.and_utc());
+
+    let coordinate = |tag: Tag, ref_tag: Tag, negative_ref: u8| -> Option<f64> {
+        let value = match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Rational(dms) if dms.len() >= 3 => {
+                dms[0].to_f64() + dms[1].to_f64() / 60.0 + dms[2].to_f64() / 3600.0
+            }
+            _ => return None,
+        };
+        let negative = match &exif.get_field(ref_tag, In::PRIMARY)?.value {
+            Value::Ascii(values) => values.first().and_then(|v| v.first()) == Some(&negative_ref),
+            _ => false,
+        };
+        Some(if negative { -value } else { value })
+    };
+
+    let altitude = match exif.get_field(Tag::GPSAltitude, In::PRIMARY).map(|field| &field.value) {
+        Some(Value::Rational(values)) if !values.is_empty() => {
+            let below_sea_level = exif.get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0)) == Some(1);
+            let altitude = values[0].to_f64();
+            Some(if below_sea_level { -altitude } else { altitude })
+        }
+        _ => None,
+    };
+
+    let accuracy = match exif.get_field(Tag::GPSHPositioningError, In::PRIMARY).map(|field| &field.value) {
+        Some(Value::Rational(values)) if !values.is_empty() => Some(values[0].to_f64()),
+        _ => None,
+    };
+
+    let dop = match exif.get_field(Tag::GPSDOP, In::PRIMARY).map(|field| &field.value) {
+        Some(Value::Rational(values)) if !values.is_empty() => Some(values[0].to_f64()),
+        _ => None,
+    };
+
+    let location = match (
+        coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S'),
+        coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W'),
+    ) {
+        (Some(latitude), Some(longitude)) => Some(GpsLocation { latitude, longitude, altitude, accuracy, dop }),
+        _ => None,
+    };
+
+    Ok(ExistingMetadata { datetime, location })
+}
 
 pub fn update_png_metadata(
     input_path: &str,
     output_path: Option<&str>,
-    latitude: f64,
-    longitude: f64,
-    altitude: f64,
-    datetime: DateTime<Utc>
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+    exif: &ExifFields,
+    xmp: &XmpFields,
+    creation_time: bool
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut file = fs::File::open(input_path)?;
-    let mut png_data = Vec::new();
-    file.read_to_end(&mut png_data)?;
+    let png_data = read_file(input_path)?;
+    let output_data = update_png_metadata_bytes(&png_data, location, datetime, exif, xmp, creation_time)?;
+    write_file(output_path.unwrap_or(input_path), &output_data)
+}
+
+/// Same as [`update_png_metadata`], but works on an in-memory PNG and returns
+/// the rewritten bytes instead of touching the filesystem.
+///
+/// The PNG is copied chunk by chunk, so pixels, palette, interlacing and
+/// every ancillary chunk stay exactly as they were. Our `eXIf` goes right
+/// after IHDR, merged with and replacing any the file already has.
+///
+/// With `creation_time`, the date also goes in a `Creation Time` tEXt chunk
+/// for viewers that predate eXIf. That chunk, and the XMP iTXt, replace an
+/// existing one only when we write our own.
+pub fn update_png_metadata_bytes(
+    png_data: &[u8],
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+    exif: &ExifFields,
+    xmp: &XmpFields,
+    creation_time: bool
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let chunks = png_chunks(png_data)?;
 
-    let exif_buf = create_exif_data(latitude, longitude, altitude, datetime)?;
+    // eXIf holds bare TIFF, but earlier versions of this tool wrote the
+    // `Exif` header too; accept both.
+    let existing_exif = chunks.iter().find(|chunk| &chunk.kind == b"eXIf").map(|chunk| {
+        let data = &png_data[chunk.data.clone()];
+        if data.starts_with(EXIF_HEADER) { data.to_vec() } else { [EXIF_HEADER, data].concat() }
+    });
+    let exif_buf = merge_exif_data(existing_exif.as_deref(), location, datetime, exif)?;
+    let xmp_packet = create_xmp_data(xmp, datetime);
 
-    let decoder = Decoder::new(&png_data[..]);
-    let mut reader = decoder.read_info()?;
+    let mut output_data = Vec::with_capacity(png_data.len() + exif_buf.len() + PNG_CHUNK_OVERHEAD);
+    output_data.extend_from_slice(PNG_SIGNATURE);
+    for chunk in &chunks {
+        let data = &png_data[chunk.data.clone()];
+        let replaced = match &chunk.kind {
+            b"eXIf" => true,
+            b"tEXt" => creation_time && data.starts_with(PNG_CREATION_TIME_KEYWORD),
+            b"iTXt" => xmp_packet.is_some() && data.starts_with(PNG_XMP_KEYWORD),
+            _ => false,
+        };
+        if replaced {
+            continue;
+        }
+        output_data.extend_from_slice(&png_data[chunk.start..chunk.end]);
+        if &chunk.kind != b"IHDR" {
+            continue;
+        }
+
+        write_png_chunk(&mut output_data, b"eXIf", &exif_buf[EXIF_HEADER.len()..]);
+
+        if creation_time {
+            // The PNG spec recommends the RFC 1123 date format.
+            let mut text = PNG_CREATION_TIME_KEYWORD.to_vec();
+            text.extend_from_slice(datetime.format("%a, %d %b %Y %H:%M:%S GMT").to_string().as_bytes());
+            write_png_chunk(&mut output_data, b"tEXt", &text);
+        }
+
+        if let Some(packet) = &xmp_packet {
+            // Uncompressed iTXt with empty language tag and translated keyword.
+            let mut itxt = PNG_XMP_KEYWORD.to_vec();
+            itxt.extend_from_slice(b"\0\0\0\0");
+            itxt.extend_from_slice(packet.as_bytes());
+            write_png_chunk(&mut output_data, b"iTXt", &itxt);
+        }
+    }
+
+    // Anything after IEND isn't ours to judge; keep it.
+    let trailing = chunks.last().map_or(PNG_SIGNATURE.len(), |chunk| chunk.end);
+    output_data.extend_from_slice(&png_data[trailing..]);
+
+    Ok(output_data)
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
 
-    let out_file = fs::File::create(output_path.unwrap_or(input_path))?;
-    let mut w = BufWriter::new(out_file);
-    let mut encoder = Encoder::new(&mut w, reader.info().width, reader.info().height);
-    encoder.set_color(reader.info().color_type);
-    encoder.set_depth(reader.info().bit_depth);
+/// Length, type and CRC around a chunk's data.
+const PNG_CHUNK_OVERHEAD: usize = 12;
+
+const PNG_CREATION_TIME_KEYWORD: &[u8] = b"Creation Time\0";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+
+struct PngChunk {
+    kind: [u8; 4],
+    start: usize,
+    data: std::ops::Range<usize>,
+    end: usize,
+}
+
+/// The chunks of `png_data` up to and including IEND. The first must be
+/// IHDR; a chunk running past the end of the data is an error.
+fn png_chunks(png_data: &[u8]) -> Result<Vec<PngChunk>, Box<dyn std::error::Error>> {
+    if !png_data.starts_with(PNG_SIGNATURE) {
+        return Err("Invalid PNG file".into());
+    }
+
+    let mut chunks = Vec::new();
+    let mut start = PNG_SIGNATURE.len();
+    while chunks.last().is_none_or(|chunk: &PngChunk| &chunk.kind != b"IEND") {
+        let header = png_data.get(start..start + 8).ok_or("Truncated PNG: no IEND chunk")?;
+        let length = read_be(&header[..4]) as usize;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let end = start + length + PNG_CHUNK_OVERHEAD;
+        if end > png_data.len() {
+            return Err(format!("Truncated PNG: {} chunk runs past the end of the file", String::from_utf8_lossy(&kind)).into());
+        }
+        if chunks.is_empty() && &kind != b"IHDR" {
+            return Err("Invalid PNG file: IHDR is not the first chunk".into());
+        }
+        chunks.push(PngChunk { kind, start, data: start + 8..end - 4, end });
+        start = end;
+    }
+    Ok(chunks)
+}
 
-    let mut writer = encoder.write_header()?;
+fn write_png_chunk(output_data: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
 
-    let chunk_type = png::chunk::ChunkType(*b"eXIf");
-    writer.write_chunk(chunk_type, &exif_buf)?;
+    output_data.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output_data.extend_from_slice(kind);
+    output_data.extend_from_slice(data);
+    output_data.extend_from_slice(&crc.finalize().to_be_bytes());
+}
 
+/// Decodes a PNG and re-encodes it as a baseline JPEG at `quality`
+/// (1-100). Lossy; transparency is flattened onto white. The result carries
+/// no metadata of its own.
+pub fn png_to_jpeg(png_data: &[u8], quality: u8) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut decoder = Decoder::new(png_data);
+    decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
     let mut buf = vec![0; reader.output_buffer_size()];
-    reader.next_frame(&mut buf)?;
-    writer.write_image_data(&buf)?;
-    writer.finish()?;
+    let frame = reader.next_frame(&mut buf)?;
+    buf.truncate(frame.buffer_size());
 
-    Ok(())
+    let over_white = |value: u8, alpha: u8| {
+        ((value as u32 * alpha as u32 + 255 * (255 - alpha as u32)) / 255) as u8
+    };
+    let (pixels, color_type) = match frame.color_type {
+        png::ColorType::Grayscale => (buf, jpeg_encoder::ColorType::Luma),
+        png::ColorType::GrayscaleAlpha => (
+            buf.chunks_exact(2).map(|pixel| over_white(pixel[0], pixel[1])).collect(),
+            jpeg_encoder::ColorType::Luma,
+        ),
+        png::ColorType::Rgba => (
+            buf.chunks_exact(4).flat_map(|pixel| pixel[..3].iter().map(|&value| over_white(value, pixel[3]))).collect(),
+            jpeg_encoder::ColorType::Rgb,
+        ),
+        // Indexed images come out as RGB after EXPAND.
+        png::ColorType::Rgb | png::ColorType::Indexed => (buf, jpeg_encoder::ColorType::Rgb),
+    };
+
+    let width = u16::try_from(frame.width).map_err(|_| "PNG is too wide for JPEG")?;
+    let height = u16::try_from(frame.height).map_err(|_| "PNG is too tall for JPEG")?;
+    let mut jpeg_data = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg_data, quality).encode(&pixels, width, height, color_type)?;
+    Ok(jpeg_data)
+}
+
+/// Everything written into a JPEG besides the date and location.
+#[derive(Clone, Copy)]
+pub struct JpegFields<'a> {
+    pub exif: &'a ExifFields,
+    pub xmp: &'a XmpFields,
+    pub iptc: &'a IptcFields,
+    pub placement: App1Placement,
 }
 
 pub fn update_jpeg_metadata(
     input_path: &str,
     output_path: Option<&str>,
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+    fields: JpegFields,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let jpeg_data = read_file(input_path)?;
+    let output_data = update_jpeg_metadata_bytes(&jpeg_data, location, datetime, fields)?;
+    write_file(output_path.unwrap_or(input_path), &output_data)
+}
+
+/// Writes GPS coordinates (decimal degrees, altitude in meters) and a
+/// capture date into the JPEG at `path`, in place, with no other fields.
+/// This is the stable entry point for geotagging from other code; the
+/// signatures above follow the app's options and may change.
+pub fn geotag_jpeg(
+    path: &Path,
     latitude: f64,
     longitude: f64,
-    altitude: f64,
-    datetime: DateTime<Utc>
+    altitude: Option<f64>,
+    datetime: DateTime<Utc>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut file = fs::File::open(input_path)?;
-    let mut jpeg_data = Vec::new();
-    file.read_to_end(&mut jpeg_data)?;
+    let location = GpsLocation { latitude, longitude, altitude, accuracy: None, dop: None };
+    if !location.is_valid() {
+        return Err(format!("Coordinates ({}, {}) out of range", latitude, longitude).into());
+    }
+    if altitude.is_some_and(|altitude| !GpsLocation::is_valid_altitude(altitude)) {
+        return Err(format!("Altitude {} out of range", altitude.unwrap_or_default()).into());
+    }
 
-    let exif_buf = create_exif_data(latitude, longitude, altitude, datetime)?;
+    let jpeg_data = fs::read(path)?;
+    let fields = JpegFields {
+        exif: &ExifFields::default(),
+        xmp: &XmpFields::default(),
+        iptc: &IptcFields::default(),
+        placement: App1Placement::default(),
+    };
+    let output_data = update_jpeg_metadata_bytes(&jpeg_data, Some(location), datetime, fields)?;
+    write_file(&path.to_string_lossy(), &output_data)
+}
 
+/// Same as [`update_jpeg_metadata`], but works on an in-memory JPEG and returns
+/// the rewritten bytes instead of touching the filesystem.
+pub fn update_jpeg_metadata_bytes(
+    jpeg_data: &[u8],
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+    fields: JpegFields,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let JpegFields { exif, xmp, iptc, placement } = fields;
     if jpeg_data.len() < 2 || jpeg_data[0] != 0xFF || jpeg_data[1] != 0xD8 {
         return Err("Invalid JPEG file".into());
     }
 
-    let mut output_data = Vec::new();
+    let exif_buf = merge_exif_data(existing_exif_payload(jpeg_data), location, datetime, exif)?;
+    if exif_buf.len() > MAX_SEGMENT_PAYLOAD {
+        return Err("EXIF with the file's own fields doesn't fit in one segment".into());
+    }
+    let xmp_packet = create_xmp_data(xmp, datetime);
+
+    // Replaces every Photoshop APP13 in the input; the file's other IRB
+    // resources and IPTC datasets are carried over.
+    let photoshop_buf = if iptc.keywords.is_empty() {
+        None
+    } else {
+        let mut buf = PHOTOSHOP_HEADER.to_vec();
+        buf.extend(merge_iptc_keywords(&photoshop_irb(jpeg_data), &iptc.keywords)?);
+        if buf.len() > MAX_SEGMENT_PAYLOAD {
+            return Err("Photoshop APP13 with merged keywords doesn't fit in one segment".into());
+        }
+        Some(buf)
+    };
+
+    // Our EXIF APP1 goes right after SOI, or after a leading JFIF APP0, ahead
+    // of any other APPn (ICC...), whatever order the original segments were
+    // in. Our XMP APP1, if any, follows it.
+    let xmp_len = xmp_packet.as_ref().map_or(0, |packet| 4 + XMP_JPEG_HEADER.len() + packet.len());
+    let mut output_data = Vec::with_capacity(jpeg_data.len() + 4 + exif_buf.len() + xmp_len);
     output_data.extend_from_slice(&jpeg_data[0..2]);
 
     let mut i = 2;
-    let mut exif_inserted = false;
+    if placement == App1Placement::AfterJfif
+        && jpeg_data.get(2..4) == Some(&[0xFF, 0xE0])
+        && let Some(end) = segment_end(jpeg_data, 2)
+    {
+        output_data.extend_from_slice(&jpeg_data[2..end]);
+        i = end;
+    }
+
+    insert_app1(&mut output_data, &exif_buf)?;
+    if let Some(packet) = &xmp_packet {
+        let mut xmp_buf = XMP_JPEG_HEADER.to_vec();
+        xmp_buf.extend_from_slice(packet.as_bytes());
+        insert_app1(&mut output_data, &xmp_buf)?;
+    }
+    if let Some(photoshop_buf) = &photoshop_buf {
+        insert_segment(&mut output_data, 0xED, photoshop_buf)?;
+    }
 
+    // Re-checked every iteration: a segment that ends exactly at EOF leaves
+    // nothing more to copy.
     while i < jpeg_data.len() {
         if i + 1 >= jpeg_data.len() || jpeg_data[i] != 0xFF {
-            if !exif_inserted {
-                insert_exif(&mut output_data, &exif_buf);
-                exif_inserted = true;
-            }
             output_data.extend_from_slice(&jpeg_data[i..]);
             break;
         }
@@ -78,72 +545,48 @@ pub fn update_jpeg_metadata(
 
         match marker {
             0xE1 => {
-                if i + 3 >= jpeg_data.len() {
-                    output_data.extend_from_slice(&jpeg_data[i..]);
-                    break;
-                }
-                let length = ((jpeg_data[i + 2] as u16) << 8) | (jpeg_data[i + 3] as u16);
-                if i + 2 + length as usize > jpeg_data.len() {
-                    output_data.extend_from_slice(&jpeg_data[i..]);
-                    break;
-                }
-
-                i += 2 + length as usize;
-                i += 2 + length as usize;
-            },
-            0xE0 => {
-                if i + 3 >= jpeg_data.len() {
-                    output_data.extend_from_slice(&jpeg_data[i..]);
-                    break;
-                }
-                let length = ((jpeg_data[i + 2] as u16) << 8) | (jpeg_data[i + 3] as u16);
-                if i + 2 + length as usize > jpeg_data.len() {
+                let Some(end) = segment_end(jpeg_data, i) else {
                     output_data.extend_from_slice(&jpeg_data[i..]);
                     break;
-                }
+                };
 
-                output_data.extend_from_slice(&jpeg_data[i..i + 2 + length as usize]);
-                i += 2 + length as usize;
-
-                if !exif_inserted {
-                    insert_exif(&mut output_data, &exif_buf);
-                    exif_inserted = true;
+                // Ours replaces the file's EXIF. Its XMP is kept unless we
+                // write our own, since readers take only one packet.
+                let payload = &jpeg_data[i + 4..end];
+                let replaced = payload.starts_with(EXIF_HEADER)
+                    || (xmp_packet.is_some()
+                        && (payload.starts_with(XMP_JPEG_HEADER) || payload.starts_with(XMP_EXTENSION_HEADER)));
+                if !replaced {
+                    output_data.extend_from_slice(&jpeg_data[i..end]);
                 }
+                i = end;
             },
             0xDA => {
-                if !exif_inserted {
-                    insert_exif(&mut output_data, &exif_buf);
-                }
                 output_data.extend_from_slice(&jpeg_data[i..]);
                 break;
             },
-            _ if marker >= 0xE2 && marker <= 0xEF => {
-                if i + 3 >= jpeg_data.len() {
-                    output_data.extend_from_slice(&jpeg_data[i..]);
-                    break;
-                }
-                let length = ((jpeg_data[i + 2] as u16) << 8) | (jpeg_data[i + 3] as u16);
-                if i + 2 + length as usize > jpeg_data.len() {
+            0xED if photoshop_buf.is_some()
+                && segment_end(jpeg_data, i).is_some_and(|end| jpeg_data[i + 4..end].starts_with(PHOTOSHOP_HEADER)) =>
+            {
+                i = segment_end(jpeg_data, i).unwrap_or(jpeg_data.len());
+            },
+            _ if (0xE2..=0xEF).contains(&marker) => {
+                let Some(end) = segment_end(jpeg_data, i) else {
                     output_data.extend_from_slice(&jpeg_data[i..]);
                     break;
-                }
+                };
 
-                output_data.extend_from_slice(&jpeg_data[i..i + 2 + length as usize]);
-                i += 2 + length as usize;
+                output_data.extend_from_slice(&jpeg_data[i..end]);
+                i = end;
             },
-            _ if (marker >= 0xC0 && marker <= 0xFE) && marker != 0xD8 && marker != 0xD9 => {
-                if i + 3 >= jpeg_data.len() {
-                    output_data.extend_from_slice(&jpeg_data[i..]);
-                    break;
-                }
-                let length = ((jpeg_data[i + 2] as u16) << 8) | (jpeg_data[i + 3] as u16);
-                if i + 2 + length as usize > jpeg_data.len() {
+            _ if (0xC0..=0xFE).contains(&marker) && marker != 0xD8 && marker != 0xD9 => {
+                let Some(end) = segment_end(jpeg_data, i) else {
                     output_data.extend_from_slice(&jpeg_data[i..]);
                     break;
-                }
+                };
 
-                output_data.extend_from_slice(&jpeg_data[i..i + 2 + length as usize]);
-                i += 2 + length as usize;
+                output_data.extend_from_slice(&jpeg_data[i..end]);
+                i = end;
             },
             _ => {
                 output_data.push(jpeg_data[i]);
@@ -152,131 +595,2163 @@ pub fn update_jpeg_metadata(
         }
     }
 
-    let out_file = fs::File::create(output_path.unwrap_or(input_path))?;
-    let mut writer = BufWriter::new(out_file);
-    writer.write_all(&output_data)?;
+    // Whatever the input looked like, the output must carry exactly one EXIF
+    // segment: ours.
+    let exif_segments = count_exif_segments(&output_data);
+    if exif_segments != 1 {
+        return Err(format!("Expected one EXIF segment in output, found {}", exif_segments).into());
+    }
 
-    Ok(())
+    Ok(output_data)
 }
 
-fn create_exif_data(
-    latitude: f64,
-    longitude: f64,
-    altitude: f64,
-    datetime: DateTime<Utc>
+/// The payload of the first EXIF APP1 in the header of a JPEG.
+fn existing_exif_payload(jpeg_data: &[u8]) -> Option<&[u8]> {
+    let mut i = 2;
+
+    while i + 3 < jpeg_data.len() && jpeg_data[i] == 0xFF {
+        let marker = jpeg_data[i + 1];
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+
+        let end = segment_end(jpeg_data, i)?;
+        if marker == 0xE1 && jpeg_data[i + 4..end].starts_with(EXIF_HEADER) {
+            return Some(&jpeg_data[i + 4..end]);
+        }
+        i = end;
+    }
+
+    None
+}
+
+/// Counts APP1 segments carrying an `Exif\0\0` payload in the header of a
+/// JPEG, i.e. before the first SOS.
+fn count_exif_segments(jpeg_data: &[u8]) -> usize {
+    let mut count = 0;
+    let mut i = 2;
+
+    while i + 3 < jpeg_data.len() && jpeg_data[i] == 0xFF {
+        let marker = jpeg_data[i + 1];
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+
+        let Some(end) = segment_end(jpeg_data, i) else {
+            break;
+        };
+        if marker == 0xE1 && jpeg_data[i + 4..end].starts_with(EXIF_HEADER) {
+            count += 1;
+        }
+        i = end;
+    }
+
+    count
+}
+
+/// The Photoshop image resource block from every APP13 in the header,
+/// joined: writers split large blocks across consecutive segments.
+fn photoshop_irb(jpeg_data: &[u8]) -> Vec<u8> {
+    let mut irb = Vec::new();
+    let mut i = 2;
+
+    while i + 3 < jpeg_data.len() && jpeg_data[i] == 0xFF {
+        let marker = jpeg_data[i + 1];
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+
+        let Some(end) = segment_end(jpeg_data, i) else {
+            break;
+        };
+        if marker == 0xED
+            && let Some(resources) = jpeg_data[i + 4..end].strip_prefix(PHOTOSHOP_HEADER)
+        {
+            irb.extend_from_slice(resources);
+        }
+        i = end;
+    }
+
+    irb
+}
+
+/// Rewrites `irb` with `keywords` added to its IPTC resource (creating it if
+/// needed). Existing keywords keep their order and encoding; ours are
+/// appended unless already present.
+fn merge_iptc_keywords(irb: &[u8], keywords: &[String]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut resources = parse_irb(irb)?;
+    let position = resources.iter().position(|resource| resource.id == IPTC_RESOURCE_ID);
+    let iptc = position.map_or(&[][..], |position| &resources[position].data);
+
+    let mut datasets = parse_iptc(iptc)?;
+    let has_charset = datasets.iter().any(|dataset| dataset.tag == IPTC_CODED_CHARACTER_SET);
+    if !has_charset && keywords.iter().any(|keyword| !keyword.is_ascii()) {
+        // ESC % G: the record is UTF-8.
+        datasets.insert(0, IptcDataset { tag: IPTC_CODED_CHARACTER_SET, value: b"%G".to_vec() });
+    }
+    for keyword in keywords {
+        let value = truncate_utf8(keyword, IPTC_KEYWORD_MAX_BYTES).as_bytes().to_vec();
+        if !datasets.iter().any(|dataset| dataset.tag == IPTC_KEYWORDS && dataset.value == value) {
+            datasets.push(IptcDataset { tag: IPTC_KEYWORDS, value });
+        }
+    }
+    // Datasets must be in record order.
+    datasets.sort_by_key(|dataset| dataset.tag.0);
+
+    let mut data = Vec::new();
+    for IptcDataset { tag: (record, dataset), value } in &datasets {
+        data.extend_from_slice(&[0x1C, *record, *dataset]);
+        data.extend_from_slice(&u16::try_from(value.len())?.to_be_bytes());
+        data.extend_from_slice(value);
+    }
+    let iptc_resource = IrbResource { id: IPTC_RESOURCE_ID, name: vec![0, 0], data };
+    match position {
+        Some(position) => resources[position] = iptc_resource,
+        None => resources.push(iptc_resource),
+    }
+
+    let mut out = Vec::new();
+    for IrbResource { id, name, data } in &resources {
+        out.extend_from_slice(b"8BIM");
+        out.extend_from_slice(&id.to_be_bytes());
+        out.extend_from_slice(name);
+        out.extend_from_slice(&u32::try_from(data.len())?.to_be_bytes());
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            out.push(0);
+        }
+    }
+    Ok(out)
+}
+
+struct IrbResource {
+    id: u16,
+    /// The Pascal-string name as stored, padding included.
+    name: Vec<u8>,
+    data: Vec<u8>,
+}
+
+fn parse_irb(mut irb: &[u8]) -> Result<Vec<IrbResource>, Box<dyn std::error::Error>> {
+    let malformed = || -> Box<dyn std::error::Error> { "Malformed Photoshop APP13".into() };
+    let mut resources = Vec::new();
+    while !irb.is_empty() {
+        let rest = irb.strip_prefix(b"8BIM").ok_or_else(malformed)?;
+        let id = u16::from_be_bytes(rest.get(..2).ok_or_else(malformed)?.try_into()?);
+        // Length byte plus name, padded to an even size.
+        let name_len = (1 + *rest.get(2).ok_or_else(malformed)? as usize).next_multiple_of(2);
+        let name = rest.get(2..2 + name_len).ok_or_else(malformed)?.to_vec();
+        let size_at = 2 + name_len;
+        let size = u32::from_be_bytes(rest.get(size_at..size_at + 4).ok_or_else(malformed)?.try_into()?) as usize;
+        let data = rest.get(size_at + 4..size_at + 4 + size).ok_or_else(malformed)?.to_vec();
+        irb = rest.get(size_at + 4 + size.next_multiple_of(2)..).unwrap_or_default();
+        resources.push(IrbResource { id, name, data });
+    }
+    Ok(resources)
+}
+
+struct IptcDataset {
+    /// Record and dataset number, e.g. (2, 25) for Keywords.
+    tag: (u8, u8),
+    value: Vec<u8>,
+}
+
+fn parse_iptc(mut iptc: &[u8]) -> Result<Vec<IptcDataset>, Box<dyn std::error::Error>> {
+    let mut datasets = Vec::new();
+    // Some writers pad the resource with zeros.
+    while let [0x1C, record, dataset, high, low, rest @ ..] = iptc {
+        if high & 0x80 != 0 {
+            return Err("IPTC extended datasets are not supported".into());
+        }
+        let size = u16::from_be_bytes([*high, *low]) as usize;
+        let value = rest.get(..size).ok_or("Malformed IPTC dataset")?;
+        datasets.push(IptcDataset { tag: (*record, *dataset), value: value.to_vec() });
+        iptc = &rest[size..];
+    }
+    Ok(datasets)
+}
+
+/// The longest prefix of `text` that fits in `max` bytes without splitting
+/// a character.
+fn truncate_utf8(text: &str, max: usize) -> &str {
+    let end = (0..=max.min(text.len())).rev().find(|&end| text.is_char_boundary(end)).unwrap_or(0);
+    &text[..end]
+}
+
+pub fn update_heic_metadata(
+    input_path: &str,
+    output_path: Option<&str>,
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+    exif: &ExifFields,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let heic_data = read_file(input_path)?;
+    let output_data = update_heic_metadata_bytes(&heic_data, location, datetime, exif)?;
+    write_file(output_path.unwrap_or(input_path), &output_data)
+}
+
+/// Same as [`update_heic_metadata`], but works on an in-memory HEIC/HEIF
+/// and returns the rewritten bytes.
+///
+/// The file's EXIF item is merged with ours as for JPEG. The new item goes
+/// in an `mdat` of its own appended to the file and the item's `iloc` entry
+/// is pointed at it; the old bytes stay where they were, unreferenced, so
+/// nothing else in the file moves. Files without an EXIF item, or whose item
+/// is split or stored inside the `meta` box, are refused.
+pub fn update_heic_metadata_bytes(
+    heic_data: &[u8],
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+    exif: &ExifFields,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut writer = exif::experimental::Writer::new();
+    let top_level = iso_boxes(heic_data, 0, heic_data.len());
+    if top_level.first().map(|b| &b.kind) != Some(b"ftyp") {
+        return Err("Invalid HEIF file".into());
+    }
+    let meta = top_level.iter().find(|b| &b.kind == b"meta").ok_or("HEIF file has no meta box")?;
+    // A full box: version and flags come before the children.
+    let children = iso_boxes(heic_data, meta.body + 4, meta.end);
+    let child = |kind: &[u8; 4]| children.iter().find(|b| &b.kind == kind);
+    let iinf = child(b"iinf").ok_or("HEIF file has no item info box")?;
+    let iloc = child(b"iloc").ok_or("HEIF file has no item location box")?;
 
-    let gps_version_field = Field {
-        tag: Tag::GPSVersionID,
-        ifd_num: In::PRIMARY,
-        value: Value::Byte(vec![2, 3, 0, 0]),
+    let item_id = heif_exif_item_id(heic_data, iinf)?.ok_or("HEIF file has no EXIF item to update")?;
+    let location_entry = heif_item_location(heic_data, iloc, item_id)?;
+
+    // The item opens with the offset of the TIFF header past its first four
+    // bytes, usually 6 to skip `Exif\0\0`.
+    let start = location_entry.base_offset.checked_add(location_entry.offset)
+        .and_then(|start| usize::try_from(start).ok())
+        .ok_or("HEIF EXIF item lies outside the file")?;
+    let end = match location_entry.length {
+        0 => heic_data.len(),
+        length => start.saturating_add(usize::try_from(length)?),
     };
-    writer.push_field(&gps_version_field);
+    let item = heic_data.get(start..end).ok_or("HEIF EXIF item lies outside the file")?;
+    let tiff_offset = item.get(..4).map(read_be).ok_or("HEIF EXIF item is truncated")?;
+    let tiff = usize::try_from(tiff_offset).ok()
+        .and_then(|tiff_offset| item.get(4 + tiff_offset..))
+        .ok_or("HEIF EXIF item is truncated")?;
+    let mut existing = EXIF_HEADER.to_vec();
+    existing.extend_from_slice(tiff);
 
-    let lat_deg = latitude.abs().floor();
-    let lat_min = (latitude.abs() - lat_deg) * 60.0;
-    let lat_min_whole = lat_min.floor();
-    let lat_sec = (lat_min - lat_min_whole) * 60.0;
+    let mut new_item = (EXIF_HEADER.len() as u32).to_be_bytes().to_vec();
+    new_item.extend(merge_exif_data(Some(&existing), location, datetime, exif)?);
+    let mdat_size = u32::try_from(8 + new_item.len())?;
 
-    let lat_ref = if latitude >= 0.0 { "N" } else { "S" };
-    let lat_ref_field = Field {
-        tag: Tag::GPSLatitudeRef,
-        ifd_num: In::PRIMARY,
-        value: Value::Ascii(vec![lat_ref.as_bytes().to_vec()]),
-    };
-    writer.push_field(&lat_ref_field);
+    let new_offset = (heic_data.len() as u64 + 8)
+        .checked_sub(location_entry.base_offset)
+        .ok_or("HEIF EXIF item base offset is past the end of the file")?;
+    let mut output_data = Vec::with_capacity(heic_data.len() + 8 + new_item.len());
+    output_data.extend_from_slice(heic_data);
+    patch_be(&mut output_data, location_entry.offset_field, new_offset)?;
+    // Without a length field the item runs to the end of the file, which
+    // ours does.
+    if location_entry.length_field.1 > 0 {
+        patch_be(&mut output_data, location_entry.length_field, new_item.len() as u64)?;
+    }
 
-    let lat_field = Field {
-        tag: Tag::GPSLatitude,
-        ifd_num: In::PRIMARY,
-        value: Value::Rational(vec![
-            Rational { num: lat_deg as u32, denom: 1 },
-            Rational { num: lat_min_whole as u32, denom: 1 },
-            Rational { num: (lat_sec * 1000000.0) as u32, denom: 1000000 },
-        ]),
-    };
-    writer.push_field(&lat_field);
+    // A last box sized 0 runs to the end of the file; it has to stop where
+    // ours starts.
+    if let Some(last) = top_level.last()
+        && read_be(&heic_data[last.start..last.start + 4]) == 0
+    {
+        patch_be(&mut output_data, (last.start, 4), (last.end - last.start) as u64)?;
+    }
 
-    let lon_deg = longitude.abs().floor();
-    let lon_min = (longitude.abs() - lon_deg) * 60.0;
-    let lon_min_whole = lon_min.floor();
-    let lon_sec = (lon_min - lon_min_whole) * 60.0;
+    output_data.extend_from_slice(&mdat_size.to_be_bytes());
+    output_data.extend_from_slice(b"mdat");
+    output_data.extend_from_slice(&new_item);
+    Ok(output_data)
+}
 
-    let lon_ref = if longitude >= 0.0 { "E" } else { "W" };
-    let lon_ref_field = Field {
-        tag: Tag::GPSLongitudeRef,
-        ifd_num: In::PRIMARY,
-        value: Value::Ascii(vec![lon_ref.as_bytes().to_vec()]),
-    };
-    writer.push_field(&lon_ref_field);
+/// A box in an ISO base media file starting at `start`; its content,
+/// after the header, is `body..end`.
+struct IsoBox {
+    kind: [u8; 4],
+    start: usize,
+    body: usize,
+    end: usize,
+}
 
-    let lon_field = Field {
-        tag: Tag::GPSLongitude,
-        ifd_num: In::PRIMARY,
-        value: Value::Rational(vec![
-            Rational { num: lon_deg as u32, denom: 1 },
-            Rational { num: lon_min_whole as u32, denom: 1 },
-            Rational { num: (lon_sec * 1000000.0) as u32, denom: 1000000 },
-        ]),
-    };
-    writer.push_field(&lon_field);
+/// The boxes laid end to end in `data[start..end]`, up to the first
+/// malformed header.
+fn iso_boxes(data: &[u8], mut start: usize, end: usize) -> Vec<IsoBox> {
+    let mut boxes = Vec::new();
 
-    let alt_field = Field {
-        tag: Tag::GPSAltitude,
-        ifd_num: In::PRIMARY,
-        value: Value::Rational(vec![
-            Rational { num: (altitude.abs() * 1000.0) as u32, denom: 1000 }
-        ]),
-    };
-    writer.push_field(&alt_field);
+    while start + 8 <= end {
+        let kind = [data[start + 4], data[start + 5], data[start + 6], data[start + 7]];
+        let (header, size) = match read_be(&data[start..start + 4]) {
+            0 => (8, Some(end - start)),
+            1 if start + 16 <= end => (16, usize::try_from(read_be(&data[start + 8..start + 16])).ok()),
+            1 => break,
+            size => (8, usize::try_from(size).ok()),
+        };
+        let Some(box_end) = size.filter(|&size| size >= header).and_then(|size| start.checked_add(size)) else {
+            break;
+        };
+        if box_end > end {
+            break;
+        }
+        boxes.push(IsoBox { kind, start, body: start + header, end: box_end });
+        start = box_end;
+    }
 
-    let alt_ref_field = Field {
-        tag: Tag::GPSAltitudeRef,
-        ifd_num: In::PRIMARY,
-        value: Value::Byte(vec![if altitude >= 0.0 { 0 } else { 1 }]),
-    };
-    writer.push_field(&alt_ref_field);
+    boxes
+}
 
-    let datetime_str = datetime.format("%Y:%m:%d %H:%M:%S").to_string();
+/// Reads the big-endian fields of a box body in order.
+struct BoxReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    end: usize,
+}
 
-    let datetime_field = Field {
-        tag: Tag::DateTime,
-        ifd_num: In::PRIMARY,
-        value: Value::Ascii(vec![datetime_str.as_bytes().to_vec()]),
-    };
-    writer.push_field(&datetime_field);
+impl<'a> BoxReader<'a> {
+    fn new(data: &'a [u8], iso_box: &IsoBox) -> Self {
+        Self { data, pos: iso_box.body, end: iso_box.end }
+    }
 
-    let datetime_orig_field = Field {
-        tag: Tag::DateTimeOriginal,
-        ifd_num: In::PRIMARY,
-        value: Value::Ascii(vec![datetime_str.as_bytes().to_vec()]),
-    };
-    writer.push_field(&datetime_orig_field);
+    /// An unsigned field of `size` bytes; a size of 0 reads as 0.
+    fn read(&mut self, size: usize) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(read_be(self.bytes(size)?))
+    }
 
-    let datetime_dig_field = Field {
-        tag: Tag::DateTimeDigitized,
-        ifd_num: In::PRIMARY,
-        value: Value::Ascii(vec![datetime_str.as_bytes().to_vec()]),
-    };
-    writer.push_field(&datetime_dig_field);
+    fn bytes(&mut self, size: usize) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        if self.pos + size > self.end {
+            return Err("HEIF box is truncated".into());
+        }
+        self.pos += size;
+        Ok(&self.data[self.pos - size..self.pos])
+    }
+}
 
-    let mut tiff_buf = Cursor::new(Vec::new());
-    writer.write(&mut tiff_buf, false)?;
-    let tiff_data = tiff_buf.into_inner();
+fn read_be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, &byte| value << 8 | u64::from(byte))
+}
 
-    let mut buf = Vec::new();
-    buf.extend_from_slice(b"Exif\0\0");
-    buf.extend_from_slice(&tiff_data);
+/// Overwrites the big-endian field at `(position, size)` with `value`.
+fn patch_be(data: &mut [u8], (position, size): (usize, usize), value: u64) -> Result<(), Box<dyn std::error::Error>> {
+    if size == 0 || (size < 8 && value >> (size * 8) != 0) {
+        return Err(format!("Field of {} bytes can't hold {}", size, value).into());
+    }
+    let field = data.get_mut(position..position + size).ok_or("Field lies outside the file")?;
+    for (i, byte) in field.iter_mut().rev().enumerate() {
+        *byte = (value >> (i * 8)) as u8;
+    }
+    Ok(())
+}
 
-    Ok(buf)
+/// The ID of the first `Exif` item in an `iinf` box.
+fn heif_exif_item_id(data: &[u8], iinf: &IsoBox) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let mut reader = BoxReader::new(data, iinf);
+    let version = reader.read(1)?;
+    reader.bytes(3)?;
+    reader.bytes(if version == 0 { 2 } else { 4 })?;
+
+    for infe in iso_boxes(data, reader.pos, iinf.end).iter().filter(|b| &b.kind == b"infe") {
+        let mut reader = BoxReader::new(data, infe);
+        let version = reader.read(1)?;
+        reader.bytes(3)?;
+        // Versions 0 and 1 predate item types.
+        if version < 2 {
+            continue;
+        }
+        let item_id = reader.read(if version == 2 { 2 } else { 4 })?;
+        reader.bytes(2)?;
+        if reader.bytes(4)? == b"Exif" {
+            return Ok(Some(item_id));
+        }
+    }
+    Ok(None)
 }
 
-fn insert_exif(output_data: &mut Vec<u8>, exif_buf: &[u8]) {
-    output_data.push(0xFF);
-    output_data.push(0xE1);
-    let length = exif_buf.len() + 2;
-    output_data.push((length >> 8) as u8);
+/// Where an item's data is, and where its `iloc` offset and length fields
+/// are as `(position, size)`, for patching.
+struct ItemLocation {
+    base_offset: u64,
+    offset: u64,
+    length: u64,
+    offset_field: (usize, usize),
+    length_field: (usize, usize),
+}
+
+/// The location of `item_id` from an `iloc` box. Only items in a single
+/// extent addressed by file offset are supported.
+fn heif_item_location(data: &[u8], iloc: &IsoBox, item_id: u64) -> Result<ItemLocation, Box<dyn std::error::Error>> {
+    let mut reader = BoxReader::new(data, iloc);
+    let version = reader.read(1)?;
+    reader.bytes(3)?;
+    let sizes = reader.read(1)?;
+    let (offset_size, length_size) = ((sizes >> 4) as usize, (sizes & 0xF) as usize);
+    let sizes = reader.read(1)?;
+    let base_offset_size = (sizes >> 4) as usize;
+    let index_size = if matches!(version, 1 | 2) { (sizes & 0xF) as usize } else { 0 };
+    let item_count = reader.read(if version < 2 { 2 } else { 4 })?;
+
+    for _ in 0..item_count {
+        let id = reader.read(if version < 2 { 2 } else { 4 })?;
+        let construction_method = if matches!(version, 1 | 2) { reader.read(2)? & 0xF } else { 0 };
+        reader.bytes(2)?;
+        let base_offset = reader.read(base_offset_size)?;
+        let extent_count = reader.read(2)?;
+
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            reader.bytes(index_size)?;
+            let offset_field = (reader.pos, offset_size);
+            let offset = reader.read(offset_size)?;
+            let length_field = (reader.pos, length_size);
+            let length = reader.read(length_size)?;
+            extents.push(ItemLocation { base_offset, offset, length, offset_field, length_field });
+        }
+
+        if id != item_id {
+            continue;
+        }
+        if construction_method != 0 {
+            return Err("HEIF EXIF item is stored inside the meta box, which isn't supported".into());
+        }
+        return match extents.len() {
+            1 if offset_size == 0 => Err("HEIF EXIF item has no offset field to point at the new item".into()),
+            1 => Ok(extents.remove(0)),
+            count => Err(format!("HEIF EXIF item is in {} extents, which isn't supported", count).into()),
+        };
+    }
+    Err("HEIF EXIF item has no location".into())
+}
+
+pub fn update_video_metadata(
+    input_path: &str,
+    output_path: Option<&str>,
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = io::BufReader::new(fs::File::open(input_path)?);
+    let boxes = scan_top_level(&mut input)?;
+    let moov = boxes.iter().find(|b| &b.kind == b"moov").ok_or("Not an MP4/QuickTime file: no moov box")?;
+    let mut old_moov = vec![0; moov.end - moov.start];
+    input.seek(io::SeekFrom::Start(moov.start as u64))?;
+    input.read_exact(&mut old_moov)?;
+    let new_moov = rewrite_moov(&old_moov, moov.end, location, datetime)?;
+
+    write_file_with(output_path.unwrap_or(input_path), |writer| {
+        input.seek(io::SeekFrom::Start(0))?;
+        io::copy(&mut (&mut input).take(moov.start as u64), writer)?;
+        writer.write_all(&new_moov)?;
+        input.seek(io::SeekFrom::Start(moov.end as u64))?;
+        io::copy(&mut input, writer)?;
+        Ok(())
+    })
+}
+
+/// Same as [`update_video_metadata`], but works on an in-memory MP4/MOV
+/// and returns the rewritten bytes.
+///
+/// Only `moov` changes: the date goes in `mvhd`'s creation_time and the
+/// location in a `udta/©xyz` ISO 6709 string, replacing any there. The
+/// media data is copied untouched; when `moov` grows and precedes it, the
+/// tracks' chunk offsets are shifted to match.
+pub fn update_video_metadata_bytes(
+    video_data: &[u8],
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let boxes = iso_boxes(video_data, 0, video_data.len());
+    if !boxes.first().is_some_and(|b| VIDEO_FIRST_BOXES.contains(&&b.kind)) {
+        return Err("Invalid MP4/QuickTime file".into());
+    }
+    let moov = boxes.iter().find(|b| &b.kind == b"moov").ok_or("Not an MP4/QuickTime file: no moov box")?;
+    let new_moov = rewrite_moov(&video_data[moov.start..moov.end], moov.end, location, datetime)?;
+
+    let mut output_data = Vec::with_capacity(video_data.len() + new_moov.len() - (moov.end - moov.start));
+    output_data.extend_from_slice(&video_data[..moov.start]);
+    output_data.extend_from_slice(&new_moov);
+    output_data.extend_from_slice(&video_data[moov.end..]);
+    Ok(output_data)
+}
+
+/// Boxes a QuickTime or MP4 file can start with.
+const VIDEO_FIRST_BOXES: &[&[u8; 4]] = &[b"ftyp", b"moov", b"wide", b"free", b"skip", b"mdat"];
+
+/// Seconds from the QuickTime epoch, 1904-01-01, to the Unix epoch.
+const QUICKTIME_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// Language code Apple writes for `©xyz`.
+const ISO6709_LANGUAGE: u16 = 0x15C7;
+
+/// The top-level boxes of a video file, read by seeking from header to
+/// header. Empty if it doesn't start like one.
+fn scan_top_level<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<IsoBox>> {
+    let len = reader.seek(io::SeekFrom::End(0))?;
+    let mut boxes = Vec::new();
+    let mut start = 0u64;
+
+    while start + 8 <= len {
+        let mut header = [0; 16];
+        reader.seek(io::SeekFrom::Start(start))?;
+        reader.read_exact(&mut header[..8])?;
+        let kind = [header[4], header[5], header[6], header[7]];
+        if boxes.is_empty() && !VIDEO_FIRST_BOXES.contains(&&kind) {
+            break;
+        }
+        let (header_len, size) = match read_be(&header[..4]) {
+            0 => (8, len - start),
+            1 if start + 16 <= len => {
+                reader.read_exact(&mut header[8..])?;
+                (16, read_be(&header[8..]))
+            }
+            1 => break,
+            size => (8, size),
+        };
+        if size < header_len || start + size > len {
+            break;
+        }
+        let (Ok(box_start), Ok(box_end)) = (usize::try_from(start), usize::try_from(start + size)) else {
+            break;
+        };
+        boxes.push(IsoBox { kind, start: box_start, body: box_start + header_len as usize, end: box_end });
+        start += size;
+    }
+
+    Ok(boxes)
+}
+
+/// `moov` with the new date and location. `moov_end` is where the original
+/// ended in the file: chunk offsets past it move by however much it grew.
+fn rewrite_moov(
+    moov: &[u8],
+    moov_end: usize,
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let Some(moov_box) = iso_boxes(moov, 0, moov.len()).pop() else {
+        return Err("moov box is malformed".into());
+    };
+
+    let mut body = Vec::with_capacity(moov.len());
+    let mut has_udta = false;
+    for child in iso_boxes(moov, moov_box.body, moov_box.end) {
+        match location {
+            Some(location) if &child.kind == b"udta" => {
+                has_udta = true;
+                body.extend(with_iso6709(moov, &child, location)?);
+            }
+            _ => body.extend_from_slice(&moov[child.start..child.end]),
+        }
+    }
+    if let Some(location) = location
+        && !has_udta
+    {
+        body.extend(plain_box(b"udta", &iso6709_atom(location))?);
+    }
+    let mut new_moov = plain_box(b"moov", &body)?;
+
+    let children = iso_boxes(&new_moov, 8, new_moov.len());
+    let mvhd = children.iter().find(|b| &b.kind == b"mvhd").ok_or("moov has no mvhd box")?;
+    let seconds = u64::try_from(datetime.timestamp() + QUICKTIME_EPOCH_OFFSET)
+        .map_err(|_| "Date is before 1904, which QuickTime can't store")?;
+    // A full box: version 1 has 64-bit times, version 0 32-bit.
+    let time_size = if new_moov.get(mvhd.body) == Some(&1) { 8 } else { 4 };
+    if mvhd.body + 4 + time_size > mvhd.end {
+        return Err("mvhd box is truncated".into());
+    }
+    patch_be(&mut new_moov, (mvhd.body + 4, time_size), seconds)?;
+
+    let delta = new_moov.len() as i64 - moov.len() as i64;
+    if delta != 0 {
+        for trak in children.iter().filter(|b| &b.kind == b"trak") {
+            shift_chunk_offsets(&mut new_moov, trak, moov_end as u64, delta)?;
+        }
+    }
+
+    Ok(new_moov)
+}
+
+/// A copy of `udta` with any `©xyz` replaced by one for `location`.
+fn with_iso6709(moov: &[u8], udta: &IsoBox, location: GpsLocation) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let children = iso_boxes(moov, udta.body, udta.end);
+    let mut body = Vec::new();
+    for child in children.iter().filter(|b| &b.kind != b"\xA9xyz") {
+        body.extend_from_slice(&moov[child.start..child.end]);
+    }
+    body.extend(iso6709_atom(location));
+    // Old QuickTime ends udta with a 32-bit zero; keep whatever follows.
+    let parsed_end = children.last().map_or(udta.body, |b| b.end);
+    body.extend_from_slice(&moov[parsed_end..udta.end]);
+    plain_box(b"udta", &body)
+}
+
+/// `©xyz` holding `location` as an ISO 6709 string, e.g.
+/// `+48.8584+002.2945+035.500/`.
+fn iso6709_atom(location: GpsLocation) -> Vec<u8> {
+    let mut text = format!("{:+08.4}{:+09.4}", location.latitude, location.longitude);
+    if let Some(altitude) = location.altitude {
+        text.push_str(&format!("{:+.3}", altitude));
+    }
+    text.push('/');
+
+    let mut atom = Vec::with_capacity(12 + text.len());
+    atom.extend_from_slice(&((12 + text.len()) as u32).to_be_bytes());
+    atom.extend_from_slice(b"\xA9xyz");
+    atom.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    atom.extend_from_slice(&ISO6709_LANGUAGE.to_be_bytes());
+    atom.extend_from_slice(text.as_bytes());
+    atom
+}
+
+/// Parses the decimal-degree ISO 6709 strings [`iso6709_atom`] and Apple
+/// devices write.
+fn parse_iso6709(text: &str) -> Option<GpsLocation> {
+    let text = text.split('/').next()?;
+    let starts: Vec<usize> = text.match_indices(['+', '-']).map(|(i, _)| i).collect();
+    let numbers: Vec<f64> = starts.iter().enumerate()
+        .map(|(n, &start)| text[start..starts.get(n + 1).copied().unwrap_or(text.len())].parse())
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let location = match numbers[..] {
+        [latitude, longitude] => GpsLocation { latitude, longitude, altitude: None, accuracy: None, dop: None },
+        [latitude, longitude, altitude] => {
+            GpsLocation { latitude, longitude, altitude: Some(altitude), accuracy: None, dop: None }
+        }
+        _ => return None,
+    };
+    location.is_valid().then_some(location)
+}
+
+/// Wraps `body` in a box with a 32-bit size.
+fn plain_box(kind: &[u8; 4], body: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let size = u32::try_from(8 + body.len()).map_err(|_| format!("{} box is too large", String::from_utf8_lossy(kind)))?;
+    let mut iso_box = Vec::with_capacity(8 + body.len());
+    iso_box.extend_from_slice(&size.to_be_bytes());
+    iso_box.extend_from_slice(kind);
+    iso_box.extend_from_slice(body);
+    Ok(iso_box)
+}
+
+/// Adds `delta` to every `stco`/`co64` chunk offset in `trak` at or past
+/// `from`.
+fn shift_chunk_offsets(moov: &mut [u8], trak: &IsoBox, from: u64, delta: i64) -> Result<(), Box<dyn std::error::Error>> {
+    let mut tables = Vec::new();
+    let child = |data: &[u8], parent: &IsoBox, kind: &[u8; 4]| {
+        iso_boxes(data, parent.body, parent.end).into_iter().find(|b| &b.kind == kind)
+    };
+    if let Some(stbl) = child(moov, trak, b"mdia")
+        .and_then(|mdia| child(moov, &mdia, b"minf"))
+        .and_then(|minf| child(moov, &minf, b"stbl"))
+    {
+        tables.extend(iso_boxes(moov, stbl.body, stbl.end).into_iter().filter(|b| &b.kind == b"stco" || &b.kind == b"co64"));
+    }
+
+    for table in tables {
+        let entry_size = if &table.kind == b"co64" { 8 } else { 4 };
+        let entry_count = read_be(moov.get(table.body + 4..table.body + 8).ok_or("chunk offset box is truncated")?);
+        let entries = table.body + 8;
+        if entries as u64 + entry_count * entry_size as u64 > table.end as u64 {
+            return Err("chunk offset box is truncated".into());
+        }
+        for n in 0..entry_count as usize {
+            let field = (entries + n * entry_size, entry_size);
+            let offset = read_be(&moov[field.0..field.0 + entry_size]);
+            if offset >= from {
+                let shifted = offset.checked_add_signed(delta).ok_or("chunk offset out of range")?;
+                patch_be(moov, field, shifted)
+                    .map_err(|_| "moov grew past a 32-bit chunk offset; the file needs co64")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The date and location in a video's `moov`, if it has one.
+fn read_video_metadata<R: Read + Seek>(reader: &mut R) -> io::Result<Option<ExistingMetadata>> {
+    let boxes = scan_top_level(reader)?;
+    let Some(moov) = boxes.iter().find(|b| &b.kind == b"moov") else {
+        return Ok(None);
+    };
+    let mut data = vec![0; moov.end - moov.start];
+    reader.seek(io::SeekFrom::Start(moov.start as u64))?;
+    reader.read_exact(&mut data)?;
+
+    let Some(moov) = iso_boxes(&data, 0, data.len()).pop() else {
+        return Ok(Some(ExistingMetadata::default()));
+    };
+    let children = iso_boxes(&data, moov.body, moov.end);
+
+    let datetime = children.iter().find(|b| &b.kind == b"mvhd").and_then(|mvhd| {
+        let time_size = if data.get(mvhd.body)? == &1 { 8 } else { 4 };
+        let seconds = read_be(data.get(mvhd.body + 4..mvhd.body + 4 + time_size)?);
+        // 0 is "not set".
+        let seconds = i64::try_from(seconds).ok().filter(|&seconds| seconds != 0)?;
+        DateTime::<Utc>::from_timestamp(seconds - QUICKTIME_EPOCH_OFFSET, 0)
+    });
+    let location = children.iter().find(|b| &b.kind == b"udta").and_then(|udta| {
+        let xyz = iso_boxes(&data, udta.body, udta.end).into_iter().find(|b| &b.kind == b"\xA9xyz")?;
+        let len = read_be(data.get(xyz.body..xyz.body + 2)?) as usize;
+        let text = data.get(xyz.body + 4..xyz.body + 4 + len)?;
+        parse_iso6709(std::str::from_utf8(text).ok()?)
+    });
+
+    Ok(Some(ExistingMetadata { datetime, location }))
+}
+
+/// End offset of the marker segment starting at `i`, or `None` if its
+/// length field is missing, too small, or runs past the end of the buffer.
+fn segment_end(jpeg_data: &[u8], i: usize) -> Option<usize> {
+    if i + 3 >= jpeg_data.len() {
+        return None;
+    }
+    let length = ((jpeg_data[i + 2] as usize) << 8) | (jpeg_data[i + 3] as usize);
+    let end = i + 2 + length;
+    (length >= 2 && end <= jpeg_data.len()).then_some(end)
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut file = fs::File::open(path)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Writes `data` to a temporary file next to `path` and renames it over
+/// `path`, so a crash or a full disk never leaves the target (which may be
+/// the original) truncated.
+///
+/// The temporary file sits in the target's directory, so the rename normally
+/// stays on one filesystem. Where it still fails with EXDEV (bind mounts,
+/// some network and overlay filesystems), the synced temporary copy is copied
+/// to a second temporary file beside the target, synced, and that is renamed
+/// over the target; the target is never written to directly. If that fails
+/// too, the first temporary copy is kept so the new data can be recovered.
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    write_file_with(path, |writer| Ok(writer.write_all(data)?))
+}
+
+/// Like [`write_file`], with the content written by `fill`.
+fn write_file_with(
+    path: &str,
+    fill: impl FnOnce(&mut BufWriter<fs::File>) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(path);
+    let temp_path = temp_sibling(path, "tmp")?;
+
+    let written = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = BufWriter::new(fs::File::create(&temp_path)?);
+        fill(&mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        Ok(())
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    replace_with_temp(&temp_path, path, |from, to| fs::rename(from, to))
+}
+
+/// Hidden file beside `path`, named after it with `suffix`.
+fn temp_sibling(path: &Path, suffix: &str) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    let file_name = path.file_name().ok_or("Output path has no file name")?;
+    Ok(path.with_file_name(format!(".{}.{}", file_name.to_string_lossy(), suffix)))
+}
+
+/// Moves the synced `temp_path` over `path` with `rename`, falling back on
+/// EXDEV as [`write_file`] describes. `rename` is a parameter so the
+/// fallback can be exercised without a second filesystem.
+fn replace_with_temp(
+    temp_path: &Path,
+    path: &Path,
+    rename: impl Fn(&Path, &Path) -> io::Result<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match rename(temp_path, path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let staged_path = temp_sibling(path, "copy.tmp")?;
+            let staged = fs::copy(temp_path, &staged_path)
+                .and_then(|_| fs::OpenOptions::new().write(true).open(&staged_path)?.sync_all())
+                .and_then(|()| rename(&staged_path, path));
+            if let Err(e) = staged {
+                let _ = fs::remove_file(&staged_path);
+                return Err(format!("{} (new data kept in {})", e, temp_path.display()).into());
+            }
+            fs::remove_file(temp_path)?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = fs::remove_file(temp_path);
+            Err(e.into())
+        }
+    }
+}
+
+pub fn create_exif_data(
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+    exif: &ExifFields
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    merge_exif_data(None, location, datetime, exif)
+}
+
+/// Like [`create_exif_data`], but every field of `existing` (a file's own
+/// EXIF APP1 payload) that we don't write ourselves is carried over: make,
+/// model, exposure, orientation and the like. Unreadable EXIF, and the
+/// MakerNote, are dropped.
+fn merge_exif_data(
+    existing: Option<&[u8]>,
+    location: Option<GpsLocation>,
+    datetime: DateTime<Utc>,
+    exif: &ExifFields
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut fields = exif_fields(location, datetime, exif);
+    if let Some(tiff) = existing.and_then(|payload| payload.strip_prefix(EXIF_HEADER)) {
+        fields.extend(surviving_fields(tiff, &fields));
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in &fields {
+        writer.push_field(field);
+    }
+
+    // The writer takes offsets from the stream position, so the TIFF has to
+    // start at 0; the `Exif` header is prepended afterwards into a buffer
+    // sized up front.
+    let mut tiff_buf = Cursor::new(Vec::with_capacity(EXIF_SIZE_HINT));
+    writer.write(&mut tiff_buf, false)?;
+    let tiff_data = tiff_buf.into_inner();
+
+    let mut buf = Vec::with_capacity(EXIF_HEADER.len() + tiff_data.len());
+    buf.extend_from_slice(EXIF_HEADER);
+    buf.extend_from_slice(&tiff_data);
+
+    Ok(buf)
+}
+
+/// Tags that locate data inside the TIFF. The writer makes its own, and
+/// copied ones would point at the old layout.
+const LAYOUT_TAGS: &[Tag] = &[
+    Tag::ExifIFDPointer,
+    Tag::GPSInfoIFDPointer,
+    Tag::InteropIFDPointer,
+    Tag::StripOffsets,
+    Tag::StripByteCounts,
+    Tag::TileOffsets,
+    Tag::TileByteCounts,
+    Tag::JPEGInterchangeFormat,
+    Tag::JPEGInterchangeFormatLength,
+];
+
+/// Qualify the file's old dates, which ours always replace, so they go too
+/// even when we don't write them.
+const DATE_COMPANION_TAGS: &[Tag] = &[
+    Tag::SubSecTime,
+    Tag::SubSecTimeOriginal,
+    Tag::SubSecTimeDigitized,
+    Tag::OffsetTime,
+    Tag::OffsetTimeOriginal,
+    Tag::OffsetTimeDigitized,
+];
+
+/// The fields of `tiff` to keep next to `ours`, keyed on `(Tag, In)` so ours
+/// win. When we write a location the file's whole GPS IFD goes, so stale
+/// tags like GPSSpeed don't sit beside new coordinates. The thumbnail IFD is
+/// dropped, as its image data isn't carried over, and so is the MakerNote:
+/// Canon, Nikon, Sony and others point into the TIFF by absolute offset from
+/// inside it, and the rewrite moves it.
+fn surviving_fields(tiff: &[u8], ours: &[Field]) -> Vec<Field> {
+    let Ok(existing) = exif::Reader::new().read_raw(tiff.to_vec()) else {
+        return Vec::new();
+    };
+    let replacing_gps = ours.iter().any(|field| field.tag.context() == Context::Gps);
+    existing.fields()
+        .filter(|field| field.ifd_num == In::PRIMARY)
+        .filter(|field| !LAYOUT_TAGS.contains(&field.tag) && !DATE_COMPANION_TAGS.contains(&field.tag))
+        .filter(|field| field.tag != Tag::MakerNote)
+        .filter(|field| !(replacing_gps && field.tag.context() == Context::Gps))
+        // The writer can't encode types it doesn't know.
+        .filter(|field| !matches!(field.value, Value::Unknown(..)))
+        .filter(|field| !ours.iter().any(|our| our.tag == field.tag && our.ifd_num == field.ifd_num))
+        .cloned()
+        .collect()
+}
+
+/// The fields [`create_exif_data`] writes.
+fn exif_fields(location: Option<GpsLocation>, datetime: DateTime<Utc>, exif: &ExifFields) -> Vec<Field> {
+    let mut fields = Vec::new();
+
+    if let Some(GpsLocation { latitude, longitude, altitude, accuracy, dop }) = location {
+        fields.push(Field {
+            tag: Tag::GPSVersionID,
+            ifd_num: In::PRIMARY,
+            value: Value::Byte(vec![2, 3, 0, 0]),
+        });
+
+        let lat_ref = if latitude >= 0.0 { "N" } else { "S" };
+        fields.push(Field {
+            tag: Tag::GPSLatitudeRef,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![lat_ref.as_bytes().to_vec()]),
+        });
+
+        fields.push(Field {
+            tag: Tag::GPSLatitude,
+            ifd_num: In::PRIMARY,
+            value: Value::Rational(decimal_to_dms_rationals(latitude).to_vec()),
+        });
+
+        let lon_ref = if longitude >= 0.0 { "E" } else { "W" };
+        fields.push(Field {
+            tag: Tag::GPSLongitudeRef,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![lon_ref.as_bytes().to_vec()]),
+        });
+
+        fields.push(Field {
+            tag: Tag::GPSLongitude,
+            ifd_num: In::PRIMARY,
+            value: Value::Rational(decimal_to_dms_rationals(longitude).to_vec()),
+        });
+
+        if let Some(altitude) = altitude.filter(|&altitude| GpsLocation::is_valid_altitude(altitude)) {
+            fields.push(Field {
+                tag: Tag::GPSAltitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![Rational { num: altitude_millimeters(altitude), denom: 1000 }]),
+            });
+
+            fields.push(Field {
+                tag: Tag::GPSAltitudeRef,
+                ifd_num: In::PRIMARY,
+                value: Value::Byte(vec![if altitude >= 0.0 { 0 } else { 1 }]),
+            });
+        }
+
+        if let Some(accuracy) = accuracy.filter(|&accuracy| GpsLocation::is_valid_error_estimate(accuracy)) {
+            fields.push(Field {
+                tag: Tag::GPSHPositioningError,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![
+                    Rational { num: (accuracy * 1000.0).round() as u32, denom: 1000 }
+                ]),
+            });
+        }
+
+        if let Some(dop) = dop.filter(|&dop| GpsLocation::is_valid_error_estimate(dop)) {
+            fields.push(Field {
+                tag: Tag::GPSDOP,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![
+                    Rational { num: (dop * 1000.0).round() as u32, denom: 1000 }
+                ]),
+            });
+        }
+
+        // UNDEFINED with an 8-byte character code prefix, per the EXIF spec.
+        let mut processing_method = b"ASCII\0\0\0".to_vec();
+        processing_method.extend_from_slice(GPS_PROCESSING_METHOD.as_bytes());
+        fields.push(Field {
+            tag: Tag::GPSProcessingMethod,
+            ifd_num: In::PRIMARY,
+            value: Value::Undefined(processing_method, 0),
+        });
+    }
+
+    // `In::PRIMARY` selects the primary image, not IFD0 itself: the writer
+    // files each tag by its context, so DateTime lands in IFD0 while
+    // DateTimeOriginal/DateTimeDigitized go to the Exif sub-IFD, and the GPS
+    // tags above to the GPS IFD, as strict readers expect.
+    let offset = exif.utc_offset.unwrap_or(FixedOffset::east_opt(0).unwrap());
+    let exif_date = |datetime: DateTime<Utc>| {
+        datetime.with_timezone(&offset).format("%Y:%m:%d %H:%M:%S").to_string().into_bytes()
+    };
+    let datetime_bytes = exif_date(datetime);
+
+    let modify_date = match exif.modify_date {
+        ModifyDate::SameAsOriginal => Some(datetime),
+        ModifyDate::Omit => None,
+        ModifyDate::ProcessingTime => Some(Utc::now()),
+    };
+    if let Some(modify_date) = modify_date {
+        fields.push(Field {
+            tag: Tag::DateTime,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![exif_date(modify_date)]),
+        });
+    }
+
+    fields.push(Field {
+        tag: Tag::DateTimeOriginal,
+        ifd_num: In::PRIMARY,
+        value: Value::Ascii(vec![datetime_bytes.clone()]),
+    });
+
+    fields.push(Field {
+        tag: Tag::DateTimeDigitized,
+        ifd_num: In::PRIMARY,
+        value: Value::Ascii(vec![datetime_bytes]),
+    });
+
+    let subsec = |datetime: DateTime<Utc>| datetime.format("%3f").to_string().into_bytes();
+    let mut time_tags = Vec::new();
+    if exif.subsec_and_offset {
+        time_tags.push((Tag::SubSecTimeOriginal, subsec(datetime)));
+        time_tags.push((Tag::SubSecTimeDigitized, subsec(datetime)));
+        if let Some(modify_date) = modify_date {
+            time_tags.push((Tag::SubSecTime, subsec(modify_date)));
+        }
+    }
+    if exif.subsec_and_offset || exif.utc_offset.is_some() {
+        let offset = offset.to_string().into_bytes();
+        time_tags.push((Tag::OffsetTimeOriginal, offset.clone()));
+        time_tags.push((Tag::OffsetTimeDigitized, offset.clone()));
+        if modify_date.is_some() {
+            time_tags.push((Tag::OffsetTime, offset));
+        }
+    }
+    for (tag, value) in time_tags {
+        fields.push(Field { tag, ifd_num: In::PRIMARY, value: Value::Ascii(vec![value]) });
+    }
+
+    if exif.force_upright {
+        fields.push(Field {
+            tag: Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![1]),
+        });
+    }
+
+    if let Some(description) = &exif.description {
+        fields.push(Field {
+            tag: Tag::ImageDescription,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![ascii_fallback(description).into_bytes()]),
+        });
+
+        if !description.is_ascii() {
+            // UNDEFINED with the "UNICODE" character code, then UTF-16 in
+            // the TIFF's byte order, which is big-endian as written below.
+            let mut comment = b"UNICODE\0".to_vec();
+            comment.extend(description.encode_utf16().flat_map(u16::to_be_bytes));
+            fields.push(Field {
+                tag: Tag::UserComment,
+                ifd_num: In::PRIMARY,
+                value: Value::Undefined(comment, 0),
+            });
+        }
+    }
+
+    let text_tags = [
+        (Tag::LensMake, &exif.lens_make),
+        (Tag::LensModel, &exif.lens_model),
+        (Tag::Artist, &exif.artist),
+        (Tag::Copyright, &exif.copyright),
+    ];
+    for (tag, value) in text_tags {
+        if let Some(value) = value {
+            fields.push(Field {
+                tag,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![ascii_fallback(value).into_bytes()]),
+            });
+        }
+    }
+
+    fields
+}
+
+/// Builds an XMP packet for `xmp`, or `None` if there is nothing to write.
+fn create_xmp_data(xmp: &XmpFields, datetime: DateTime<Utc>) -> Option<String> {
+    if xmp.is_empty() {
+        return None;
+    }
+
+    let mut properties = String::new();
+    if xmp.dates {
+        properties.push_str(&xmp_date_properties(datetime));
+    }
+    properties.push_str(&xmp_properties(xmp));
+    Some(wrap_xmp_packet(&properties))
+}
+
+/// Builds a standalone `.xmp` sidecar carrying the date and location as
+/// well, for formats we can't embed metadata into.
+pub fn create_xmp_sidecar(location: Option<GpsLocation>, datetime: DateTime<Utc>, xmp: &XmpFields) -> String {
+    let mut properties = xmp_date_properties(datetime);
+    if let Some(GpsLocation { latitude, longitude, altitude, .. }) = location {
+        properties.push_str(&format!(
+            "   <exif:GPSLatitude>{}</exif:GPSLatitude>\n",
+            xmp_coordinate(latitude, 'N', 'S')
+        ));
+        properties.push_str(&format!(
+            "   <exif:GPSLongitude>{}</exif:GPSLongitude>\n",
+            xmp_coordinate(longitude, 'E', 'W')
+        ));
+        if let Some(altitude) = altitude.filter(|&altitude| GpsLocation::is_valid_altitude(altitude)) {
+            properties.push_str(&format!(
+                "   <exif:GPSAltitude>{}/1000</exif:GPSAltitude>\n",
+                altitude_millimeters(altitude)
+            ));
+            properties.push_str(&format!(
+                "   <exif:GPSAltitudeRef>{}</exif:GPSAltitudeRef>\n",
+                if altitude >= 0.0 { 0 } else { 1 }
+            ));
+        }
+    }
+    properties.push_str(&xmp_properties(xmp));
+
+    wrap_xmp_packet(&properties)
+}
+
+/// The same instant as the EXIF dates, in RFC 3339.
+fn xmp_date_properties(datetime: DateTime<Utc>) -> String {
+    let rfc3339 = datetime.to_rfc3339_opts(SecondsFormat::Secs, true);
+    format!(
+        "   <xmp:CreateDate>{0}</xmp:CreateDate>\n   <exif:DateTimeOriginal>{0}</exif:DateTimeOriginal>\n",
+        rfc3339
+    )
+}
+
+/// Properties from `xmp` other than the dates.
+fn xmp_properties(xmp: &XmpFields) -> String {
+    let mut properties = String::new();
+    if let Some(url) = &xmp.source_url {
+        properties.push_str(&format!("   <dc:source>{}</dc:source>\n", escape_xml(url)));
+    }
+    if let Some(album) = &xmp.album {
+        properties.push_str(&format!(
+            "   <dc:subject>\n    <rdf:Bag>\n     <rdf:li>{}</rdf:li>\n    </rdf:Bag>\n   </dc:subject>\n",
+            escape_xml(album)
+        ));
+    }
+    if let Some(rights) = &xmp.rights {
+        properties.push_str(&format!(
+            "   <dc:rights>\n    <rdf:Alt>\n     <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n    </rdf:Alt>\n   </dc:rights>\n",
+            escape_xml(rights)
+        ));
+    }
+    if let Some(description) = &xmp.description {
+        properties.push_str(&format!(
+            "   <dc:description>\n    <rdf:Alt>\n     <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n    </rdf:Alt>\n   </dc:description>\n",
+            escape_xml(description)
+        ));
+    }
+    if let Some(place) = &xmp.place {
+        let parts = [("City", &place.city), ("State", &place.region), ("Country", &place.country)];
+        for (name, value) in parts {
+            if let Some(value) = value {
+                properties.push_str(&format!("   <photoshop:{0}>{1}</photoshop:{0}>\n", name, escape_xml(value)));
+            }
+        }
+    }
+    properties
+}
+
+/// XMP writes coordinates as `DDD,MM.mmmmmmK`.
+fn xmp_coordinate(value: f64, positive_ref: char, negative_ref: char) -> String {
+    let abs = value.abs();
+    let degrees = abs.floor();
+    let minutes = (abs - degrees) * 60.0;
+    let reference = if value >= 0.0 { positive_ref } else { negative_ref };
+    format!("{},{:.6}{}", degrees as u32, minutes, reference)
+}
+
+fn wrap_xmp_packet(properties: &str) -> String {
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+            " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+            "  <rdf:Description rdf:about=\"\"\n",
+            "    xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n",
+            "    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n",
+            "    xmlns:exif=\"http://ns.adobe.com/exif/1.0/\"\n",
+            "    xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\">\n",
+            "{}",
+            "  </rdf:Description>\n",
+            " </rdf:RDF>\n",
+            "</x:xmpmeta>\n",
+            "<?xpacket end=\"w\"?>",
+        ),
+        properties
+    )
+}
+
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// ImageDescription is ASCII only: accents are stripped from their letters
+/// and anything else non-ASCII becomes `?`. The full text goes in
+/// UserComment.
+fn ascii_fallback(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .map(|c| if c.is_ascii() { c } else { '?' })
+        .collect()
+}
+
+/// Distance from sea level in whole millimetres, rounded; the sign goes in
+/// GPSAltitudeRef. Callers check [`GpsLocation::is_valid_altitude`] first,
+/// so the value is finite and fits.
+fn altitude_millimeters(altitude: f64) -> u32 {
+    (altitude.abs() * 1000.0).round() as u32
+}
+
+/// Denominator of the minutes rational: 1e-7 minute is under a millimetre,
+/// and 60 minutes still fits a `u32` numerator.
+const MINUTES_DENOM: u32 = 10_000_000;
+
+/// Splits a decimal coordinate into whole degrees and the rest as a single
+/// rounded minutes rational, with seconds 0/1, so only one rounding step
+/// is ever taken. The sign is dropped; it goes in the matching `*Ref` tag.
+fn decimal_to_dms_rationals(value: f64) -> [Rational; 3] {
+    let value = value.abs();
+    let mut degrees = value.floor() as u32;
+    let mut minutes = ((value - value.floor()) * 60.0 * MINUTES_DENOM as f64).round() as u32;
+
+    // Rounding can push the minutes to exactly 60, which must carry over.
+    if minutes >= 60 * MINUTES_DENOM {
+        minutes -= 60 * MINUTES_DENOM;
+        degrees += 1;
+    }
+
+    [
+        Rational { num: degrees, denom: 1 },
+        Rational { num: minutes, denom: MINUTES_DENOM },
+        Rational { num: 0, denom: 1 },
+    ]
+}
+
+/// Appends an APP1 segment carrying `payload`.
+fn insert_app1(output_data: &mut Vec<u8>, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    insert_segment(output_data, 0xE1, payload)
+}
+
+/// Appends a segment carrying `payload`, refusing one whose length field
+/// would wrap: a long caption or packet must fail, not corrupt the file.
+fn insert_segment(output_data: &mut Vec<u8>, marker: u8, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    if payload.len() > MAX_SEGMENT_PAYLOAD {
+        return Err(format!("{} bytes of metadata don't fit in one JPEG segment", payload.len()).into());
+    }
+    output_data.push(0xFF);
+    output_data.push(marker);
+    let length = payload.len() + 2;
+    output_data.push((length >> 8) as u8);
     output_data.push(length as u8);
-    output_data.extend_from_slice(exif_buf);
+    output_data.extend_from_slice(payload);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest;
+
+    /// A fresh, empty directory for one test.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("metadata_fix_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn crosses_devices() -> io::Error {
+        io::Error::from(io::ErrorKind::CrossesDevices)
+    }
+
+    #[test]
+    fn exdev_falls_back_to_a_staged_copy() {
+        let dir = test_dir("exdev_fallback");
+        let (temp_path, path) = (dir.join(".photo.jpg.tmp"), dir.join("photo.jpg"));
+        fs::write(&path, b"original").unwrap();
+        fs::write(&temp_path, b"rewritten").unwrap();
+
+        // Only the direct rename crosses devices; the staged copy sits beside
+        // the target.
+        replace_with_temp(&temp_path, &path, |from, to| {
+            if from == temp_path { Err(crosses_devices()) } else { fs::rename(from, to) }
+        })
+            .unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"rewritten");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "temporary files left behind");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_exdev_fallback_leaves_the_target_whole() {
+        let dir = test_dir("exdev_failure");
+        let (temp_path, path) = (dir.join(".photo.jpg.tmp"), dir.join("photo.jpg"));
+        fs::write(&path, b"original").unwrap();
+        fs::write(&temp_path, b"rewritten").unwrap();
+
+        let error = replace_with_temp(&temp_path, &path, |_, _| Err(crosses_devices())).unwrap_err();
+
+        assert!(error.to_string().contains("new data kept in"), "{}", error);
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+        assert_eq!(fs::read(&temp_path).unwrap(), b"rewritten");
+        assert!(!temp_sibling(&path, "copy.tmp").unwrap().exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn coordinates_are_valid_up_to_the_poles_and_antimeridian() {
+        let at = |latitude, longitude| GpsLocation { latitude, longitude, altitude: None, accuracy: None, dop: None };
+        for (latitude, longitude) in [(90.0, 180.0), (-90.0, -180.0), (0.0, 0.0), (-0.0, 179.999_999)] {
+            assert!(at(latitude, longitude).is_valid(), "({}, {})", latitude, longitude);
+        }
+        let out_of_range = [
+            (90.000_001, 0.0), (-90.000_001, 0.0), (0.0, 180.000_001), (0.0, -180.000_001),
+            (200.0, 0.0), (0.0, -400.0), (f64::NAN, 0.0), (0.0, f64::INFINITY),
+        ];
+        for (latitude, longitude) in out_of_range {
+            assert!(!at(latitude, longitude).is_valid(), "({}, {})", latitude, longitude);
+        }
+    }
+
+    /// The marker and first four payload bytes of each APPn segment, in order.
+    fn app_segments(jpeg: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut segments = Vec::new();
+        let mut i = 2;
+        while jpeg[i + 1] != 0xDA {
+            let end = segment_end(jpeg, i).unwrap();
+            if (0xE0..=0xEF).contains(&jpeg[i + 1]) {
+                segments.push((jpeg[i + 1], &jpeg[i + 4..i + 8]));
+            }
+            i = end;
+        }
+        segments
+    }
+
+    /// [`selftest::tiny_jpeg`] with an XMP APP1 ahead of its JFIF APP0.
+    fn jpeg_leading_with_xmp() -> Vec<u8> {
+        let mut payload = XMP_JPEG_HEADER.to_vec();
+        payload.extend_from_slice(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\" x:xmptk=\"Phone\"></x:xmpmeta>");
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(&payload);
+
+        let mut jpeg = selftest::tiny_jpeg();
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn exif_goes_ahead_of_a_leading_xmp() {
+        let input = jpeg_leading_with_xmp();
+        let (exif, xmp, iptc) = (ExifFields::default(), XmpFields::default(), IptcFields::default());
+        for placement in [App1Placement::AfterJfif, App1Placement::First] {
+            let output = update_jpeg_metadata_bytes(
+                &input, None, selftest::sample_datetime(), JpegFields { exif: &exif, xmp: &xmp, iptc: &iptc, placement },
+            )
+                .unwrap();
+
+            assert_eq!(
+                app_segments(&output),
+                [(0xE1, &b"Exif"[..]), (0xE1, b"http"), (0xE0, b"JFIF")],
+                "{:?}", placement,
+            );
+            jpeg_decoder::Decoder::new(Cursor::new(&output)).decode().unwrap();
+        }
+    }
+
+    #[test]
+    fn our_xmp_replaces_a_leading_one_and_follows_the_exif() {
+        let xmp = XmpFields { description: Some("Harbour".to_string()), ..XmpFields::default() };
+        let output = update_jpeg_metadata_bytes(
+            &jpeg_leading_with_xmp(), None, selftest::sample_datetime(),
+            JpegFields { exif: &ExifFields::default(), xmp: &xmp, iptc: &IptcFields::default(), placement: App1Placement::First },
+        )
+            .unwrap();
+
+        assert_eq!(app_segments(&output), [(0xE1, &b"Exif"[..]), (0xE1, b"http"), (0xE0, b"JFIF")]);
+        assert!(!output.windows(5).any(|window| window == b"Phone"), "the file's own XMP packet was kept");
+    }
+
+    /// A 2x2 PNG of the given color type, 8 bits per sample.
+    fn encode_png(color: png::ColorType, pixels: &[u8], palette: Option<(&[u8], &[u8])>) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 2, 2);
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        if let Some((palette, trns)) = palette {
+            encoder.set_palette(palette.to_vec());
+            encoder.set_trns(trns.to_vec());
+        }
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(pixels).unwrap();
+        writer.finish().unwrap();
+        data
+    }
+
+    /// A PNG as stored, without expanding the palette or dropping alpha.
+    #[derive(Debug, PartialEq)]
+    struct DecodedPng {
+        color: png::ColorType,
+        depth: png::BitDepth,
+        palette: Option<Vec<u8>>,
+        trns: Option<Vec<u8>>,
+        pixels: Vec<u8>,
+    }
+
+    fn decode_png(data: &[u8]) -> DecodedPng {
+        let mut reader = png::Decoder::new(Cursor::new(data)).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        let info = reader.info();
+        DecodedPng {
+            color: info.color_type,
+            depth: info.bit_depth,
+            palette: info.palette.as_ref().map(|palette| palette.to_vec()),
+            trns: info.trns.as_ref().map(|trns| trns.to_vec()),
+            pixels,
+        }
+    }
+
+    const PALETTE: [u8; 9] = [0, 0, 0, 255, 0, 0, 0, 0, 255];
+    const TRNS: [u8; 2] = [255, 128];
+
+    #[test]
+    fn png_color_types_and_palettes_come_through_unchanged() {
+        let fixtures = [
+            ("indexed", encode_png(png::ColorType::Indexed, &[0, 1, 2, 1], Some((&PALETTE, &TRNS)))),
+            ("grayscale", encode_png(png::ColorType::Grayscale, &[0, 85, 170, 255], None)),
+            ("grayscale+alpha", encode_png(png::ColorType::GrayscaleAlpha, &[0, 255, 85, 128, 170, 64, 255, 0], None)),
+            ("RGBA", encode_png(png::ColorType::Rgba, &[255, 0, 0, 255, 0, 255, 0, 128, 0, 0, 255, 64, 9, 9, 9, 0], None)),
+        ];
+        for (name, input) in fixtures {
+            let output = update_png_metadata_bytes(
+                &input, None, selftest::sample_datetime(), &ExifFields::default(), &XmpFields::default(), false,
+            )
+                .unwrap();
+            let decoded = decode_png(&output);
+            assert_eq!(decoded, decode_png(&input), "{}", name);
+            if decoded.color == png::ColorType::Indexed {
+                assert_eq!((decoded.palette.as_deref(), decoded.trns.as_deref()), (Some(&PALETTE[..]), Some(&TRNS[..])));
+            }
+        }
+    }
+
+    fn dms_to_decimal(dms: &[Rational; 3]) -> f64 {
+        dms[0].to_f64() + dms[1].to_f64() / 60.0 + dms[2].to_f64() / 3600.0
+    }
+
+    /// Coordinates across the whole range: a grid, values either side of
+    /// every whole degree, and a fixed pseudo-random sample.
+    fn sample_coordinates() -> Vec<f64> {
+        let mut values: Vec<f64> = (-1800..=1800).map(|tenth| tenth as f64 / 10.0 + 0.012_345).collect();
+        for degree in -180..=180 {
+            for offset in [-1e-12, -1e-9, 0.0, 1e-9, 1e-12, 0.999_999_999_9] {
+                values.push(degree as f64 + offset);
+            }
+        }
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        for _ in 0..10_000 {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            values.push((state >> 11) as f64 / (1u64 << 53) as f64 * 360.0 - 180.0);
+        }
+        values.retain(|value| value.abs() <= 180.0);
+        values
+    }
+
+    /// Half a metre along a meridian, in degrees.
+    const HALF_METRE: f64 = 0.5 / 111_320.0;
+
+    #[test]
+    fn dms_rationals_decode_back_within_half_a_metre() {
+        for value in sample_coordinates() {
+            let dms = decimal_to_dms_rationals(value);
+            let decoded = dms_to_decimal(&dms);
+            assert!((decoded - value.abs()).abs() < HALF_METRE, "{} decoded back as {}", value, decoded);
+        }
+    }
+
+    #[test]
+    fn dms_rationals_are_well_formed() {
+        for value in sample_coordinates() {
+            let dms = decimal_to_dms_rationals(value);
+            assert!(dms[0].num <= 180 && dms[0].denom == 1, "{}: degrees {:?}", value, dms[0]);
+            assert!(dms[1].num < 60 * MINUTES_DENOM && dms[1].denom == MINUTES_DENOM, "{}: minutes {:?}", value, dms[1]);
+            assert_eq!((dms[2].num, dms[2].denom), (0, 1), "{}: seconds", value);
+
+            let mirrored = decimal_to_dms_rationals(-value);
+            assert_eq!(
+                dms.map(|part| (part.num, part.denom)), mirrored.map(|part| (part.num, part.denom)),
+                "{} and {} differ beyond the sign", value, -value,
+            );
+        }
+    }
+
+    #[test]
+    fn dms_minutes_that_round_to_sixty_carry_into_the_degrees() {
+        let dms = decimal_to_dms_rationals(10.999_999_999_99);
+        assert_eq!([dms[0].num, dms[1].num, dms[2].num], [11, 0, 0]);
+        let dms = decimal_to_dms_rationals(37.7749);
+        assert_eq!([dms[0].num, dms[1].num], [37, 464_940_000]);
+    }
+
+    fn app1(payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    #[test]
+    fn segment_end_stays_inside_the_buffer() {
+        let segment = app1(b"Exif\0\0");
+        let jpeg = [&[0xFF, 0xD8][..], &segment].concat();
+
+        assert_eq!(segment_end(&jpeg, 2), Some(jpeg.len()));
+        assert_eq!(segment_end(&jpeg[..jpeg.len() - 1], 2), None);
+        assert_eq!(segment_end(&jpeg[..5], 2), None);
+        assert_eq!(segment_end(&[0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x01], 2), None);
+    }
+
+    /// No scan, no EOI: the file ends with its EXIF segment.
+    #[test]
+    fn exif_segment_last_before_eof() {
+        let old_exif = selftest::camera_exif().unwrap();
+        let input = [&[0xFF, 0xD8][..], &app1(&old_exif)].concat();
+        assert_eq!(existing_exif_payload(&input), Some(&old_exif[..]));
+
+        let output = update_jpeg_metadata_bytes(
+            &input, None, selftest::sample_datetime(),
+            JpegFields { exif: &ExifFields::default(), xmp: &XmpFields::default(), iptc: &IptcFields::default(), placement: App1Placement::First },
+        )
+            .unwrap();
+
+        assert_eq!(count_exif_segments(&output), 1);
+        let end = segment_end(&output, 2).unwrap();
+        assert_eq!(end, output.len(), "anything but our EXIF after SOI");
+        let existing = read_existing_metadata(&mut Cursor::new(&output)).unwrap();
+        assert_eq!(existing.datetime, Some(selftest::sample_datetime()));
+    }
+
+    /// A length running past EOF is copied as it is rather than parsed.
+    #[test]
+    fn truncated_segment_at_eof_is_kept() {
+        let truncated = [0xFF, 0xE2, 0x10, 0x00, b'I', b'C', b'C'];
+        let input = [&selftest::tiny_jpeg()[..2], &truncated].concat();
+
+        let output = update_jpeg_metadata_bytes(
+            &input, None, selftest::sample_datetime(),
+            JpegFields { exif: &ExifFields::default(), xmp: &XmpFields::default(), iptc: &IptcFields::default(), placement: App1Placement::First },
+        )
+            .unwrap();
+
+        assert!(output.ends_with(&truncated));
+        assert_eq!(count_exif_segments(&output), 1);
+    }
+
+    /// Headers the copy loop can't parse: bytes that aren't a marker right
+    /// after SOI, and a JFIF APP0 cut short. Our EXIF is placed before the
+    /// loop runs, so it still lands once, right after SOI, with the rest of
+    /// the file behind it as it was.
+    #[test]
+    fn exif_lands_after_soi_when_the_header_does_not_parse() {
+        let truncated_app0 = [0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F'];
+        for rest in [&b"\x00\x11not a marker"[..], &truncated_app0] {
+            for placement in [App1Placement::First, App1Placement::AfterJfif] {
+                let input = [&[0xFF, 0xD8][..], rest].concat();
+                let output = update_jpeg_metadata_bytes(
+                    &input, None, selftest::sample_datetime(),
+                    JpegFields { exif: &ExifFields::default(), xmp: &XmpFields::default(), iptc: &IptcFields::default(), placement },
+                )
+                    .unwrap();
+
+                assert_eq!(output[2..4], [0xFF, 0xE1], "{:?} {:?}", rest, placement);
+                let end = segment_end(&output, 2).unwrap();
+                assert!(output[6..end].starts_with(EXIF_HEADER));
+                assert_eq!(&output[end..], rest);
+                assert_eq!(count_exif_segments(&output), 1);
+            }
+        }
+    }
+
+    /// A written altitude that is out of range never reaches the file, even
+    /// if the caller didn't filter it out.
+    #[test]
+    fn absurd_altitude_is_not_written() {
+        for altitude in [1e12, -1e9, f64::INFINITY] {
+            let location = GpsLocation { latitude: 1.0, longitude: 2.0, altitude: Some(altitude), accuracy: None, dop: None };
+            let read_back = selftest::jpeg_round_trip(location, selftest::sample_datetime()).unwrap();
+            assert_eq!(read_back.location.unwrap().altitude, None, "{}", altitude);
+        }
+    }
+
+    /// GPSAltitude and GPSAltitudeRef as written for `altitude`.
+    fn written_altitude(altitude: Option<f64>) -> (Option<Rational>, Option<u32>) {
+        let location = GpsLocation { latitude: 1.0, longitude: 2.0, altitude, accuracy: None, dop: None };
+        let output = selftest::write_tiny_jpeg(
+            location, selftest::sample_datetime(), &ExifFields::default(), &XmpFields::default(), App1Placement::First,
+        )
+            .unwrap();
+        let exif = exif::Reader::new().read_from_container(&mut Cursor::new(&output)).unwrap();
+        let value = exif.get_field(Tag::GPSAltitude, In::PRIMARY).map(|field| match &field.value {
+            Value::Rational(values) => values[0],
+            other => panic!("GPSAltitude is {:?}", other),
+        });
+        let reference = exif.get_field(Tag::GPSAltitudeRef, In::PRIMARY).and_then(|field| field.value.get_uint(0));
+        (value, reference)
+    }
+
+    #[test]
+    fn altitude_tags_follow_the_altitude() {
+        let written = |altitude| {
+            let (value, reference) = written_altitude(altitude);
+            (value.map(|value| (value.num, value.denom)), reference)
+        };
+        assert_eq!(written(None), (None, None));
+        assert_eq!(written(Some(0.0)), (Some((0, 1000)), Some(0)));
+        assert_eq!(written(Some(35.5)), (Some((35_500, 1000)), Some(0)));
+        assert_eq!(written(Some(-12.3456)), (Some((12_346, 1000)), Some(1)));
+    }
+
+    /// The first rational of `tag` in the GPS IFD as written for `location`.
+    fn written_gps_rational(location: GpsLocation, tag: Tag) -> Option<(u32, u32)> {
+        let output = selftest::write_tiny_jpeg(
+            location, selftest::sample_datetime(), &ExifFields::default(), &XmpFields::default(), App1Placement::First,
+        )
+            .unwrap();
+        let exif = exif::Reader::new().read_from_container(&mut Cursor::new(&output)).unwrap();
+        exif.get_field(tag, In::PRIMARY).map(|field| match &field.value {
+            Value::Rational(values) => (values[0].num, values[0].denom),
+            other => panic!("{} is {:?}", tag, other),
+        })
+    }
+
+    /// Out-of-range accuracy or DOP never reaches the file, even if the
+    /// caller didn't filter it out.
+    #[test]
+    fn accuracy_is_written_only_when_it_fits() {
+        let written = |accuracy| {
+            let location = GpsLocation { latitude: 1.0, longitude: 2.0, altitude: None, accuracy: Some(accuracy), dop: None };
+            written_gps_rational(location, Tag::GPSHPositioningError)
+        };
+        assert_eq!(written(12.5), Some((12_500, 1000)));
+        assert_eq!(written(4_294_967.0), Some((4_294_967_000, 1000)));
+        for accuracy in [4.3e6, 1e12, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(written(accuracy), None, "{}", accuracy);
+        }
+    }
+
+    #[test]
+    fn dop_is_written_only_when_it_fits() {
+        let written = |dop| {
+            let location = GpsLocation { latitude: 1.0, longitude: 2.0, altitude: None, accuracy: None, dop: Some(dop) };
+            written_gps_rational(location, Tag::GPSDOP)
+        };
+        assert_eq!(written(1.8), Some((1_800, 1000)));
+        for dop in [4.3e6, 1e12, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(written(dop), None, "{}", dop);
+        }
+    }
+
+    fn tiff_u16(tiff: &[u8], at: usize) -> u16 {
+        let bytes = [tiff[at], tiff[at + 1]];
+        if tiff.starts_with(b"II") { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) }
+    }
+
+    fn tiff_u32(tiff: &[u8], at: usize) -> usize {
+        let bytes = [tiff[at], tiff[at + 1], tiff[at + 2], tiff[at + 3]];
+        (if tiff.starts_with(b"II") { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }) as usize
+    }
+
+    /// Tag numbers in the IFD at `offset` of a TIFF, and where the tag
+    /// `pointer` points, if the IFD has it. kamadak-exif reports sub-IFD
+    /// tags as `In::PRIMARY`, so placement is read off the raw TIFF.
+    fn ifd_tags(tiff: &[u8], offset: usize, pointer: Tag) -> (Vec<u16>, Option<usize>) {
+        let entries: Vec<usize> = (0..tiff_u16(tiff, offset) as usize).map(|n| offset + 2 + 12 * n).collect();
+        let tags = entries.iter().map(|&entry| tiff_u16(tiff, entry)).collect();
+        let target = entries.iter()
+            .find(|&&entry| tiff_u16(tiff, entry) == pointer.number())
+            .map(|&entry| tiff_u32(tiff, entry + 8));
+        (tags, target)
+    }
+
+    #[test]
+    fn capture_dates_go_in_the_exif_ifd() {
+        let exif = ExifFields { subsec_and_offset: true, ..ExifFields::default() };
+        let payload = create_exif_data(None, selftest::sample_datetime(), &exif).unwrap();
+        let tiff = &payload[EXIF_HEADER.len()..];
+
+        let (primary, exif_ifd) = ifd_tags(tiff, tiff_u32(tiff, 4), Tag::ExifIFDPointer);
+        let (exif_tags, _) = ifd_tags(tiff, exif_ifd.expect("no Exif IFD"), Tag::ExifIFDPointer);
+
+        assert!(primary.contains(&Tag::DateTime.number()));
+        for tag in [Tag::DateTimeOriginal, Tag::DateTimeDigitized, Tag::SubSecTimeOriginal, Tag::OffsetTimeOriginal] {
+            assert!(exif_tags.contains(&tag.number()), "{} is not in the Exif IFD", tag);
+            assert!(!primary.contains(&tag.number()), "{} is in IFD0", tag);
+        }
+    }
+
+    /// [`selftest::tiny_png`] with an eXIf chunk holding `exif` inserted
+    /// after the chunk at `after` (0 for IHDR).
+    fn png_with_exif(exif: &[u8], after: usize) -> Vec<u8> {
+        let png = selftest::tiny_png().unwrap();
+        let at = png_chunks(&png).unwrap()[after].end;
+        let mut chunk = Vec::new();
+        write_png_chunk(&mut chunk, b"eXIf", exif);
+        [&png[..at], &chunk, &png[at..]].concat()
+    }
+
+    #[test]
+    fn existing_png_exif_is_replaced_not_duplicated() {
+        let camera = selftest::camera_exif().unwrap();
+        let bare = &camera[EXIF_HEADER.len()..];
+        // Bare TIFF as the spec says, or with the APP1 header as earlier
+        // versions wrote it; after IHDR, or later as some tools put it.
+        let inputs = [png_with_exif(bare, 0), png_with_exif(&camera, 0), png_with_exif(bare, 1)];
+        for (n, input) in inputs.iter().enumerate() {
+            let mut output = input.clone();
+            // A second run must leave it single too.
+            for _ in 0..2 {
+                output = update_png_metadata_bytes(
+                    &output, None, selftest::sample_datetime(), &ExifFields::default(), &XmpFields::default(), false,
+                )
+                    .unwrap();
+            }
+
+            let chunks = png_chunks(&output).unwrap();
+            let kinds: Vec<&[u8; 4]> = chunks.iter().map(|chunk| &chunk.kind).collect();
+            assert_eq!(kinds, [b"IHDR", b"eXIf", b"IDAT", b"IEND"], "input {}", n);
+            assert!(!output[chunks[1].data.clone()].starts_with(EXIF_HEADER), "input {}", n);
+
+            let exif = exif::Reader::new().read_from_container(&mut Cursor::new(&output)).unwrap();
+            let make = exif.get_field(Tag::Make, In::PRIMARY).map(|field| field.display_value().to_string());
+            assert_eq!(make.as_deref(), Some("\"Example\""), "input {}", n);
+            let existing = read_existing_metadata(&mut Cursor::new(&output)).unwrap();
+            assert_eq!(existing.datetime, Some(selftest::sample_datetime()), "input {}", n);
+        }
+    }
+
+    /// Characters outside the BMP take a surrogate pair in UTF-16 and four
+    /// bytes in UTF-8; both must come through whole.
+    #[test]
+    fn emoji_caption_round_trips() {
+        const CAPTION: &str = "📷 Hafen 🌊";
+        let exif = ExifFields { description: Some(CAPTION.to_string()), ..ExifFields::default() };
+        let xmp = XmpFields { description: Some(CAPTION.to_string()), ..XmpFields::default() };
+        let output = selftest::write_tiny_jpeg(
+            GpsLocation { latitude: 1.0, longitude: 2.0, altitude: None, accuracy: None, dop: None },
+            selftest::sample_datetime(), &exif, &xmp, App1Placement::First,
+        )
+            .unwrap();
+
+        let read_back = exif::Reader::new().read_from_container(&mut Cursor::new(&output)).unwrap();
+        assert_eq!(selftest::user_comment(&read_back).as_deref(), Some(CAPTION));
+        let description = read_back.get_field(Tag::ImageDescription, In::PRIMARY).map(|field| &field.value);
+        assert!(matches!(description, Some(Value::Ascii(values)) if values[0] == b"? Hafen ?"), "{:?}", description);
+        let packet = String::from_utf8_lossy(&output);
+        assert!(packet.contains(&format!("<rdf:li xml:lang=\"x-default\">{}</rdf:li>", CAPTION)));
+    }
+
+    #[test]
+    fn app1_placement_orders_exif_and_jfif() {
+        assert_eq!(App1Placement::default(), App1Placement::AfterJfif);
+        let orders = [
+            (App1Placement::AfterJfif, [(0xE0, &b"JFIF"[..]), (0xE1, b"Exif")]),
+            (App1Placement::First, [(0xE1, &b"Exif"[..]), (0xE0, b"JFIF")]),
+        ];
+        for (placement, order) in orders {
+            let output = selftest::write_tiny_jpeg(
+                GpsLocation { latitude: 1.0, longitude: 2.0, altitude: None, accuracy: None, dop: None },
+                selftest::sample_datetime(), &ExifFields::default(), &XmpFields::default(), placement,
+            )
+                .unwrap();
+
+            assert_eq!(app_segments(&output), order, "{:?}", placement);
+            jpeg_decoder::Decoder::new(Cursor::new(&output)).decode().unwrap();
+            assert_eq!(read_existing_metadata(&mut Cursor::new(&output)).unwrap().datetime, Some(selftest::sample_datetime()));
+        }
+    }
+
+    fn irb_resource(id: u16, data: &[u8]) -> Vec<u8> {
+        let mut resource = b"8BIM".to_vec();
+        resource.extend_from_slice(&id.to_be_bytes());
+        resource.extend_from_slice(&[0, 0]);
+        resource.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        resource.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            resource.push(0);
+        }
+        resource
+    }
+
+    fn iptc_dataset((record, dataset): (u8, u8), value: &[u8]) -> Vec<u8> {
+        [&[0x1C, record, dataset][..], &(value.len() as u16).to_be_bytes(), value].concat()
+    }
+
+    const IPTC_CAPTION: (u8, u8) = (2, 120);
+
+    #[test]
+    fn existing_iptc_keywords_are_merged_with_ours() {
+        let iptc = [
+            iptc_dataset((2, 0), &[0, 4]),
+            iptc_dataset(IPTC_KEYWORDS, b"Beach"),
+            iptc_dataset(IPTC_KEYWORDS, b"Family"),
+            iptc_dataset(IPTC_CAPTION, b"Sunset"),
+        ].concat();
+        let other_resource = irb_resource(0x0425, b"digest");
+        let irb = [other_resource.clone(), irb_resource(IPTC_RESOURCE_ID, &iptc)].concat();
+        // Split across two segments, as writers do with large blocks.
+        let (first, second) = irb.split_at(10);
+        let mut input = selftest::tiny_jpeg();
+        for part in [second, first] {
+            let payload = [PHOTOSHOP_HEADER, part].concat();
+            let mut segment = vec![0xFF, 0xED];
+            segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+            segment.extend_from_slice(&payload);
+            input.splice(20..20, segment);
+        }
+
+        let keywords = ["Family", "Paris", "Zürich"].map(String::from).to_vec();
+        let output = update_jpeg_metadata_bytes(
+            &input, None, selftest::sample_datetime(),
+            JpegFields { exif: &ExifFields::default(), xmp: &XmpFields::default(), iptc: &IptcFields { keywords }, placement: App1Placement::AfterJfif },
+        )
+            .unwrap();
+
+        assert_eq!(app_segments(&output).iter().filter(|(marker, _)| *marker == 0xED).count(), 1);
+        let resources = parse_irb(&photoshop_irb(&output)).unwrap();
+        assert_eq!(resources.len(), 2);
+        assert_eq!(irb_resource(resources[0].id, &resources[0].data), other_resource);
+
+        let datasets = parse_iptc(&resources[1].data).unwrap();
+        let values = |tag| datasets.iter().filter(|dataset| dataset.tag == tag).map(|dataset| dataset.value.as_slice()).collect::<Vec<_>>();
+        assert_eq!(values(IPTC_KEYWORDS), [&b"Beach"[..], b"Family", b"Paris", "Zürich".as_bytes()]);
+        assert_eq!(values(IPTC_CAPTION), [&b"Sunset"[..]]);
+        // A non-ASCII keyword marks the record UTF-8.
+        assert_eq!(values(IPTC_CODED_CHARACTER_SET), [&b"\x1b%G"[..]]);
+        jpeg_decoder::Decoder::new(Cursor::new(&output)).decode().unwrap();
+    }
+
+    #[test]
+    fn jpeg_after_jfif() {
+        selftest::check_jpeg(selftest::sample_datetime(), App1Placement::AfterJfif).unwrap();
+    }
+
+    #[test]
+    fn jpeg_exif_first() {
+        selftest::check_jpeg(selftest::sample_datetime(), App1Placement::First).unwrap();
+    }
+
+    #[test]
+    fn jpeg_with_existing_exif_and_xmp() {
+        selftest::check_jpeg_with_app1(selftest::sample_datetime()).unwrap();
+    }
+
+    #[test]
+    fn png() {
+        selftest::check_png(selftest::sample_datetime()).unwrap();
+    }
+
+    #[test]
+    fn interlaced_palette_png_is_lossless() {
+        selftest::check_png_lossless(selftest::sample_datetime()).unwrap();
+    }
+
+    #[test]
+    fn mp4() {
+        selftest::check_mp4(selftest::sample_datetime()).unwrap();
+    }
+
+    #[test]
+    fn gps_coordinates_round_trip() {
+        selftest::check_coordinates(selftest::sample_datetime()).unwrap();
+    }
+
+    #[test]
+    fn gps_altitudes_round_trip() {
+        selftest::check_altitudes(selftest::sample_datetime()).unwrap();
+    }
+
+    #[test]
+    fn utf8_caption() {
+        selftest::check_caption(selftest::sample_datetime()).unwrap();
+    }
+
+    /// An EXIF APP1 payload holding `fields`.
+    fn exif_payload(fields: &[Field]) -> Vec<u8> {
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        [EXIF_HEADER, &tiff.into_inner()].concat()
+    }
+
+    fn jpeg_with_exif(payload: &[u8]) -> Vec<u8> {
+        let tiny = selftest::tiny_jpeg();
+        [&tiny[..2], &app1(payload), &tiny[2..]].concat()
+    }
+
+    fn rewrite(jpeg: &[u8], xmp: &XmpFields) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let location = GpsLocation { latitude: 1.0, longitude: 2.0, altitude: Some(3.0), accuracy: None, dop: None };
+        update_jpeg_metadata_bytes(
+            jpeg, Some(location), selftest::sample_datetime(),
+            JpegFields { exif: &ExifFields::default(), xmp, iptc: &IptcFields::default(), placement: App1Placement::First },
+        )
+    }
+
+    /// Its offsets point into the old TIFF, so a copy would be garbage.
+    #[test]
+    fn maker_note_is_not_carried_over() {
+        let camera = exif_payload(&[
+            Field { tag: Tag::Make, ifd_num: In::PRIMARY, value: Value::Ascii(vec![b"Example".to_vec()]) },
+            Field { tag: Tag::MakerNote, ifd_num: In::PRIMARY, value: Value::Undefined(b"Nikon\0\x02\x10\0\0MM".to_vec(), 0) },
+        ]);
+
+        let output = rewrite(&jpeg_with_exif(&camera), &XmpFields::default()).unwrap();
+        let exif = exif::Reader::new().read_from_container(&mut Cursor::new(&output)).unwrap();
+        assert!(exif.get_field(Tag::Make, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::MakerNote, In::PRIMARY).is_none());
+    }
+
+    /// A file's own EXIF that nearly fills its APP1 leaves no room for ours;
+    /// the 16-bit length must not wrap.
+    #[test]
+    fn exif_that_outgrows_one_segment_is_refused() {
+        let comment = Field {
+            tag: Tag::ImageDescription,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![vec![b'x'; MAX_SEGMENT_PAYLOAD - 200]]),
+        };
+        let camera = exif_payload(&[comment]);
+        assert!(camera.len() <= MAX_SEGMENT_PAYLOAD);
+
+        let error = rewrite(&jpeg_with_exif(&camera), &XmpFields::default()).unwrap_err();
+        assert_eq!(error.to_string(), "EXIF with the file's own fields doesn't fit in one segment");
+    }
+
+    #[test]
+    fn xmp_that_outgrows_one_segment_is_refused() {
+        let xmp = XmpFields { description: Some("x".repeat(MAX_SEGMENT_PAYLOAD)), ..XmpFields::default() };
+        let error = rewrite(&selftest::tiny_jpeg(), &xmp).unwrap_err();
+        assert!(error.to_string().ends_with("bytes of metadata don't fit in one JPEG segment"), "{}", error);
+    }
+
+    #[test]
+    fn insert_segment_refuses_a_payload_its_length_cannot_hold() {
+        let mut output = Vec::new();
+        assert!(insert_segment(&mut output, 0xE2, &vec![0; MAX_SEGMENT_PAYLOAD]).is_ok());
+        assert_eq!(output[2..4], [0xFF, 0xFF]);
+        let mut output = Vec::new();
+        assert!(insert_segment(&mut output, 0xE2, &vec![0; MAX_SEGMENT_PAYLOAD + 1]).is_err());
+        assert!(output.is_empty());
+    }
+
+    fn iso_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&(8 + body.len() as u32).to_be_bytes()[..], kind, body].concat()
+    }
+
+    fn full_box(kind: &[u8; 4], version: u8, body: &[u8]) -> Vec<u8> {
+        iso_box(kind, &[&[version, 0, 0, 0][..], body].concat())
+    }
+
+    /// `value` as a big-endian field of `size` bytes.
+    fn be(value: u64, size: usize) -> Vec<u8> {
+        value.to_be_bytes()[8 - size..].to_vec()
+    }
+
+    /// How a [`heif`] fixture lays out its `meta` box and `mdat`.
+    #[derive(Clone, Copy)]
+    struct HeifLayout {
+        iinf_version: u8,
+        infe_version: u8,
+        iloc_version: u8,
+        offset_size: usize,
+        length_size: usize,
+        base_offset_size: usize,
+        construction_method: u64,
+        exif_extents: usize,
+        exif_item: bool,
+        /// Otherwise the `mdat` has size 0, running to the end of the file.
+        sized_mdat: bool,
+    }
+
+    impl Default for HeifLayout {
+        fn default() -> Self {
+            Self {
+                iinf_version: 0,
+                infe_version: 2,
+                iloc_version: 1,
+                offset_size: 4,
+                length_size: 4,
+                base_offset_size: 4,
+                construction_method: 0,
+                exif_extents: 1,
+                exif_item: true,
+                sized_mdat: true,
+            }
+        }
+    }
+
+    /// A fixture, with the EXIF item's `iloc` base offset and where its
+    /// offset and length fields are.
+    struct Heif {
+        data: Vec<u8>,
+        base_offset: u64,
+        offset_field: (usize, usize),
+        length_field: (usize, usize),
+        mdat_start: usize,
+    }
+
+    const HEIF_IMAGE: &[u8] = b"coded image";
+
+    /// `ftyp`, then `meta` with `hdlr`, `iinf` and `iloc`, then an `mdat`
+    /// holding an image item and, after it, an EXIF item with camera fields.
+    fn heif(layout: HeifLayout) -> Heif {
+        let exif_item = [&(EXIF_HEADER.len() as u32).to_be_bytes()[..], &selftest::camera_exif().unwrap()].concat();
+        let ftyp = iso_box(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let hdlr = full_box(b"hdlr", 0, &[&[0; 4][..], b"pict", &[0; 13]].concat());
+
+        let id_size = |version| if version == 2 { 2 } else { 4 };
+        let infe = |id, kind: &[u8; 4]| {
+            let version = layout.infe_version;
+            full_box(b"infe", version, &[be(id, id_size(version)), be(0, 2), kind.to_vec(), vec![0]].concat())
+        };
+        let count_size = if layout.iinf_version == 0 { 2 } else { 4 };
+        let exif_kind = if layout.exif_item { b"Exif" } else { b"mime" };
+        let iinf = full_box(b"iinf", layout.iinf_version, &[be(2, count_size), infe(1, b"hvc1"), infe(2, exif_kind)].concat());
+
+        // The iloc's size doesn't depend on where the mdat is, so it is
+        // built once to place the mdat and again to point into it.
+        let iloc = |mdat_body: usize| {
+            let version = layout.iloc_version;
+            let id_size = if version < 2 { 2 } else { 4 };
+            let mut body = vec![(layout.offset_size << 4 | layout.length_size) as u8, (layout.base_offset_size << 4) as u8];
+            body.extend(be(2, id_size));
+            let mut exif_fields = (0, (0, 0), (0, 0));
+            let items = [
+                (1, mdat_body, HEIF_IMAGE.len(), 0, 1),
+                (2, mdat_body + HEIF_IMAGE.len(), exif_item.len(), layout.construction_method, layout.exif_extents),
+            ];
+            for (id, start, length, method, extents) in items {
+                body.extend(be(id, id_size));
+                if version > 0 {
+                    body.extend(be(method, 2));
+                }
+                body.extend(be(0, 2));
+                let base = match (layout.base_offset_size, layout.offset_size) {
+                    (0, _) => 0,
+                    (_, 0) => start,
+                    _ => mdat_body,
+                };
+                body.extend(be(base as u64, layout.base_offset_size));
+                body.extend(be(extents as u64, 2));
+                let part = length / extents;
+                for n in 0..extents {
+                    let offset_field = (12 + body.len(), layout.offset_size);
+                    body.extend(be((start + part * n - base) as u64, layout.offset_size));
+                    let length_field = (12 + body.len(), layout.length_size);
+                    let part_length = if n + 1 == extents { length - part * n } else { part };
+                    body.extend(be(part_length as u64, layout.length_size));
+                    exif_fields = (base as u64, offset_field, length_field);
+                }
+            }
+            (full_box(b"iloc", version, &body), exif_fields)
+        };
+
+        let iloc_start = ftyp.len() + 12 + hdlr.len() + iinf.len();
+        let mdat_start = iloc_start + iloc(0).0.len();
+        let (iloc, (base_offset, offset_field, length_field)) = iloc(mdat_start + 8);
+        let meta = full_box(b"meta", 0, &[hdlr, iinf, iloc].concat());
+        let mut mdat = iso_box(b"mdat", &[HEIF_IMAGE, &exif_item].concat());
+        if !layout.sized_mdat {
+            mdat[..4].fill(0);
+        }
+
+        Heif {
+            data: [ftyp, meta, mdat].concat(),
+            base_offset,
+            offset_field: (iloc_start + offset_field.0, offset_field.1),
+            length_field: (iloc_start + length_field.0, length_field.1),
+            mdat_start,
+        }
+    }
+
+    #[test]
+    fn heif_exif_item_is_repointed_at_the_merged_exif() {
+        let location = GpsLocation { latitude: 48.85837, longitude: 2.294481, altitude: Some(35.0), accuracy: None, dop: None };
+        let layouts = [
+            HeifLayout { iloc_version: 0, base_offset_size: 0, ..HeifLayout::default() },
+            HeifLayout { iinf_version: 1, infe_version: 3, length_size: 8, sized_mdat: false, ..HeifLayout::default() },
+            HeifLayout { iinf_version: 1, iloc_version: 2, offset_size: 8, base_offset_size: 8, ..HeifLayout::default() },
+            HeifLayout { length_size: 0, ..HeifLayout::default() },
+        ];
+        for (n, layout) in layouts.into_iter().enumerate() {
+            let heif = heif(layout);
+            let input = &heif.data;
+            let output = update_heic_metadata_bytes(input, Some(location), selftest::sample_datetime(), &ExifFields::default())
+                .unwrap();
+
+            let existing = read_existing_metadata(&mut Cursor::new(&output)).unwrap();
+            assert_eq!(existing.datetime, Some(selftest::sample_datetime()), "layout {}", n);
+            let read_back = existing.location.unwrap();
+            assert!((read_back.latitude - location.latitude).abs() < 1e-6, "layout {}", n);
+            assert!((read_back.longitude - location.longitude).abs() < 1e-6, "layout {}", n);
+            let exif = exif::Reader::new().read_from_container(&mut Cursor::new(&output)).unwrap();
+            assert!(exif.get_field(Tag::Make, In::PRIMARY).is_some(), "layout {}", n);
+
+            // Nothing before the new mdat moves: only the item's iloc
+            // fields, and a size-0 mdat's size, are patched.
+            let new_item = output.len() - input.len() - 8;
+            let mut expected = input.clone();
+            patch_be(&mut expected, heif.offset_field, (input.len() + 8) as u64 - heif.base_offset).unwrap();
+            if layout.length_size > 0 {
+                patch_be(&mut expected, heif.length_field, new_item as u64).unwrap();
+            }
+            if !layout.sized_mdat {
+                patch_be(&mut expected, (heif.mdat_start, 4), (input.len() - heif.mdat_start) as u64).unwrap();
+            }
+            assert!(output[..input.len()] == expected[..], "layout {}", n);
+            assert_eq!(read_be(&output[input.len()..input.len() + 4]) as usize, new_item + 8);
+            assert_eq!(&output[input.len() + 4..input.len() + 8], b"mdat");
+
+            // The item now in the new mdat must be found again next time.
+            let again = update_heic_metadata_bytes(&output, Some(location), selftest::sample_datetime(), &ExifFields::default())
+                .unwrap();
+            let existing = read_existing_metadata(&mut Cursor::new(&again)).unwrap();
+            assert_eq!(existing.datetime, Some(selftest::sample_datetime()), "layout {}", n);
+        }
+    }
+
+    /// Entries before version 2 carry no item type and are passed over.
+    #[test]
+    fn heif_exif_item_is_found_among_any_entry_versions() {
+        let untyped = |version| full_box(b"infe", version, &[be(1, 2), be(0, 2), vec![0, 0]].concat());
+        for iinf_version in [0, 1] {
+            for (version, id) in [(2, 7), (3, 70_000)] {
+                let exif = full_box(b"infe", version, &[be(id, if version == 2 { 2 } else { 4 }), be(0, 2), b"Exif\0".to_vec()].concat());
+                let count = be(3, if iinf_version == 0 { 2 } else { 4 });
+                let data = full_box(b"iinf", iinf_version, &[count, untyped(0), untyped(1), exif].concat());
+                let iinf = &iso_boxes(&data, 0, data.len())[0];
+                assert_eq!(heif_exif_item_id(&data, iinf).unwrap(), Some(id), "iinf {} infe {}", iinf_version, version);
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_heif_exif_items_are_refused() {
+        let cases = [
+            (HeifLayout { exif_item: false, ..HeifLayout::default() }, "HEIF file has no EXIF item to update"),
+            (
+                HeifLayout { construction_method: 1, ..HeifLayout::default() },
+                "HEIF EXIF item is stored inside the meta box, which isn't supported",
+            ),
+            (HeifLayout { exif_extents: 2, ..HeifLayout::default() }, "HEIF EXIF item is in 2 extents, which isn't supported"),
+            (HeifLayout { offset_size: 0, ..HeifLayout::default() }, "HEIF EXIF item has no offset field to point at the new item"),
+        ];
+        for (layout, message) in cases {
+            let input = heif(layout).data;
+            let error = update_heic_metadata_bytes(&input, None, selftest::sample_datetime(), &ExifFields::default())
+                .unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+    }
 }
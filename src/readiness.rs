@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+use crate::schema::read_json;
+use crate::{extract, sanitize_media_name};
+
+/// Whether each sidecar has what a run needs, checked from the JSON alone
+/// so it works before the media has arrived.
+#[derive(Default)]
+pub struct ReadinessReport {
+    pub files: usize,
+    /// Have a usable title and timestamp.
+    pub ready: usize,
+    /// Ready, and have valid coordinates too.
+    pub with_location: usize,
+    pub unreadable: Vec<(PathBuf, String)>,
+    /// Sidecars a run would fail on, with every reason.
+    pub not_ready: Vec<(PathBuf, Vec<String>)>,
+}
+
+pub fn check(json_files: &[PathBuf]) -> ReadinessReport {
+    let mut report = ReadinessReport::default();
+
+    for json_file in json_files {
+        let json_data = match read_json(json_file) {
+            Ok(json_data) => json_data,
+            Err(message) => {
+                report.unreadable.push((json_file.clone(), message));
+                continue;
+            }
+        };
+        report.files += 1;
+
+        let mut problems = Vec::new();
+        match json_data["title"].as_str() {
+            Some(title) => {
+                if let Err(e) = sanitize_media_name(title) {
+                    problems.push(e);
+                }
+            }
+            None => problems.push("No title found in JSON".to_string()),
+        }
+        if let Err(e) = extract::read_timestamp(&json_data) {
+            problems.push(e);
+        }
+
+        if !problems.is_empty() {
+            report.not_ready.push((json_file.clone(), problems));
+            continue;
+        }
+        report.ready += 1;
+        if extract::read_geo(&json_data).is_some_and(|(_, location)| location.is_valid()) {
+            report.with_location += 1;
+        }
+    }
+
+    report
+}
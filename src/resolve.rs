@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{media, Note};
+
+/// Finds the media file a sidecar describes. The exact `dir/title` path is
+/// tried first; anything else that matches is reported in `notes`.
+///
+/// The returned path is always `dir` joined with the name the file really
+/// has on disk, so it shares `dir`'s prefix and carries the true casing.
+pub fn resolve_media_path(dir: &Path, media_name: &str, notes: &mut Vec<Note>) -> Option<PathBuf> {
+    let exact = dir.join(media_name);
+    if exact.exists() {
+        // Case-insensitive filesystems (APFS, NTFS) also say yes for a title
+        // whose casing differs from the file; the directory listing has the
+        // real name. Canonicalizing would follow a symlinked file to its
+        // target's name instead.
+        let names: Vec<_> = std::fs::read_dir(dir).into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.file_name())
+            .collect();
+        if names.iter().any(|name| name == media_name) {
+            return Some(exact);
+        }
+        return match names.into_iter().find(|name| name.to_string_lossy().eq_ignore_ascii_case(media_name)) {
+            Some(name) => {
+                notes.push(Note::warning(format!("matched {} ignoring case", name.to_string_lossy())));
+                Some(dir.join(name))
+            }
+            None => Some(exact),
+        };
+    }
+
+    let files: Vec<_> = std::fs::read_dir(dir).ok()?
+        .flatten()
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| entry.file_name())
+        .collect();
+    resolve_among(&files, media_name, notes).map(|name| dir.join(name))
+}
+
+/// The name among `names`, the files next to a sidecar, that `media_name`
+/// refers to: itself, else the first that matches loosely or with its
+/// extension doubled or undoubled. Anything but an exact match is reported
+/// in `notes`. Archives, which have no directory to read, resolve this way.
+pub fn resolve_among<'a, N: AsRef<OsStr>>(names: &'a [N], media_name: &str, notes: &mut Vec<Note>) -> Option<&'a N> {
+    if let Some(exact) = names.iter().find(|name| name.as_ref() == media_name) {
+        return Some(exact);
+    }
+
+    let Some(found) = find_loosely(names, media_name) else {
+        let found = find_loosely(names, &extension_variant(media_name)?)?;
+        notes.push(Note::warning(format!(
+            "matched {} despite a doubled extension",
+            found.as_ref().to_string_lossy()
+        )));
+        return Some(found);
+    };
+    let found_name = found.as_ref().to_string_lossy();
+    if found_name.to_lowercase() == media_name.to_lowercase() {
+        notes.push(Note::warning(format!("matched {} ignoring case", found_name)));
+    } else {
+        notes.push(Note::warning(format!("matched {} ignoring spacing or unicode form", found_name)));
+    }
+    Some(found)
+}
+
+/// Botched exports double an extension on either side: a title of
+/// `IMG.jpg` for `IMG.jpg.jpg`, or the reverse. Returns the other spelling.
+fn extension_variant(media_name: &str) -> Option<String> {
+    let (stem, extension) = media_name.rsplit_once('.')?;
+    let dotted = format!(".{}", extension);
+    if stem.to_lowercase().ends_with(&dotted.to_lowercase()) {
+        Some(stem.to_string())
+    } else {
+        Some(format!("{}{}", media_name, dotted))
+    }
+}
+
+/// Case-sensitive filesystems won't find `IMG.JPG` for a title of `IMG.jpg`.
+/// Titles can also carry trailing spaces, or accents composed differently
+/// from the file name (macOS stores names decomposed), so names are compared
+/// trimmed, NFC-normalized and lowercased.
+fn find_loosely<'a, N: AsRef<OsStr>>(names: &'a [N], media_name: &str) -> Option<&'a N> {
+    let wanted = loose(media_name);
+    names.iter().find(|name| loose(&name.as_ref().to_string_lossy()) == wanted)
+}
+
+fn loose(name: &str) -> String {
+    name.trim().nfc().collect::<String>().to_lowercase()
+}
+
+/// Every media file in the input tree by name, compared as in
+/// [`find_loosely`], for sidecars whose media sits in another folder (an
+/// album's JSON next to a "Photos from 2019" image).
+#[derive(Default)]
+pub struct MediaIndex {
+    by_name: HashMap<String, Vec<PathBuf>>,
+}
+
+impl MediaIndex {
+    pub fn insert(&mut self, path: PathBuf) {
+        if let Some(name) = path.file_name() {
+            self.by_name.entry(loose(&name.to_string_lossy())).or_default().push(path);
+        }
+    }
+
+    /// The file named `media_name` anywhere in the tree. With several, the
+    /// first by path is used and the rest are listed in `notes`.
+    pub fn find(&self, media_name: &str, notes: &mut Vec<Note>) -> Option<PathBuf> {
+        let mut candidates = self.by_name.get(&loose(media_name))
+            .or_else(|| self.by_name.get(&loose(&extension_variant(media_name)?)))?
+            .clone();
+        candidates.sort();
+
+        let found = candidates.remove(0);
+        notes.push(Note::warning(format!("not next to its JSON, found {}", found.display())));
+        if !candidates.is_empty() {
+            let others: Vec<String> = candidates.iter().map(|path| path.display().to_string()).collect();
+            notes.push(Note::warning(format!("ambiguous, also matches {}", others.join(", "))));
+        }
+        Some(found)
+    }
+}
+
+/// Exports since 2024 name sidecars `IMG.jpg.supplemental-metadata.json`,
+/// cutting the suffix short when the whole name would pass 51 characters.
+const SUPPLEMENTAL_SUFFIX: &str = ".supplemental-metadata";
+
+/// The media file name a sidecar's own name points at, for the
+/// `.supplemental-metadata` naming: `IMG.jpg.supplemental-metadata.json`,
+/// truncated forms like `IMG.jpg.supplemen.json`, and duplicates like
+/// `IMG.jpg.supplemental-metadata(1).json` for `IMG(1).jpg`.
+pub fn media_name_from_sidecar(sidecar_name: &str) -> Option<String> {
+    let base = sidecar_name.strip_suffix(".json")?;
+    let (base, counter) = match base.rsplit_once('(') {
+        Some((rest, number))
+            if number.strip_suffix(')').is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())) =>
+        {
+            (rest, Some(&base[rest.len()..]))
+        }
+        _ => (base, None),
+    };
+
+    let dot = base.rfind('.')?;
+    let suffix = &base[dot..];
+    if suffix.len() < 2 || !SUPPLEMENTAL_SUFFIX.starts_with(suffix) {
+        return None;
+    }
+    let media_name = &base[..dot];
+    Some(match (counter, media_name.rsplit_once('.')) {
+        (Some(counter), Some((stem, extension))) => format!("{}{}.{}", stem, counter, extension),
+        (Some(counter), None) => format!("{}{}", media_name, counter),
+        (None, _) => media_name.to_string(),
+    })
+}
+
+/// The sidecar Takeout wrote for `media_path`: `IMG.jpg.json` next to it,
+/// `IMG.json` in older exports, or a `.supplemental-metadata` name in newer
+/// ones.
+pub fn find_sidecar(media_path: &Path) -> Option<PathBuf> {
+    let name = media_path.file_name()?.to_string_lossy();
+    let stem = media_path.file_stem()?.to_string_lossy();
+    let exact = [
+        format!("{}{}.json", name, SUPPLEMENTAL_SUFFIX),
+        format!("{}.json", name),
+        format!("{}.json", stem),
+    ];
+    exact.into_iter()
+        .map(|sidecar_name| media_path.with_file_name(sidecar_name))
+        .find(|sidecar| sidecar.is_file())
+        .or_else(|| {
+            std::fs::read_dir(media_path.parent()?).ok()?
+                .flatten()
+                .map(|entry| entry.path())
+                .find(|path| {
+                    path.file_name()
+                        .and_then(|sidecar_name| media_name_from_sidecar(&sidecar_name.to_string_lossy()))
+                        .is_some_and(|media_name| media_name == name)
+                })
+        })
+}
+
+/// Deepest directory containing every path in `paths`.
+pub fn common_dir(paths: &[PathBuf]) -> Option<PathBuf> {
+    let mut dir = paths.first()?.parent()?.to_path_buf();
+    while !paths.iter().all(|path| path.starts_with(&dir)) {
+        dir = dir.parent()?.to_path_buf();
+    }
+    Some(dir)
+}
+
+/// Google names burst frames `<base>_BURST<n>...`; every frame shares the
+/// base, as do other exports of the same shot (`IMG_1.jpg`, `IMG_1.HEIC`).
+fn sibling_base(stem: &str) -> &str {
+    stem.find("_BURST").map_or(stem, |i| &stem[..i])
+}
+
+/// DateTimeOriginal from another file in `dir` with the same base name,
+/// trying them in name order so a burst's first frame wins. Returns the
+/// sibling's name along with its date.
+pub fn find_sibling_timestamp(dir: &Path, media_name: &str) -> Option<(String, DateTime<Utc>)> {
+    let stem = Path::new(media_name).file_stem()?.to_string_lossy();
+    let base = sibling_base(&stem);
+
+    let mut siblings: Vec<PathBuf> = std::fs::read_dir(dir).ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path.file_name().is_some_and(|name| name.to_string_lossy() != media_name)
+                && !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+                && path.file_stem().is_some_and(|other| sibling_base(&other.to_string_lossy()) == base)
+        })
+        .collect();
+    siblings.sort();
+
+    siblings.into_iter().find_map(|path| {
+        let file = std::fs::File::open(&path).ok()?;
+        let existing = media::read_existing_metadata(&mut std::io::BufReader::new(file)).ok()?;
+        let name = path.file_name()?.to_string_lossy().into_owned();
+        Some((name, existing.datetime?))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory holding empty files with the given names.
+    fn dir_with(test: &str, names: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("metadata_fix_resolve_{}_{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in names {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        dir
+    }
+
+    fn note_texts(notes: &[Note]) -> Vec<&str> {
+        notes.iter().map(|note| note.text.as_str()).collect()
+    }
+
+    #[test]
+    fn exact_title_is_used_without_a_note() {
+        let dir = dir_with("exact", &["IMG_0001.jpg"]);
+        let mut notes = Vec::new();
+
+        assert_eq!(resolve_media_path(&dir, "IMG_0001.jpg", &mut notes), Some(dir.join("IMG_0001.jpg")));
+        assert!(notes.is_empty());
+        assert_eq!(resolve_media_path(&dir, "IMG_0002.jpg", &mut notes), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// On a case-sensitive filesystem the loose lookup finds the file; on a
+    /// case-insensitive one the exact path exists and canonicalizing gives
+    /// the real name. Either way the result carries the casing on disk.
+    #[test]
+    fn title_with_other_casing_finds_the_file() {
+        let dir = dir_with("casing", &["IMG_0001.JPG"]);
+        let mut notes = Vec::new();
+
+        assert_eq!(resolve_media_path(&dir, "IMG_0001.jpg", &mut notes), Some(dir.join("IMG_0001.JPG")));
+        assert_eq!(note_texts(&notes), ["matched IMG_0001.JPG ignoring case"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// The result is `dir` joined with the real name, never a canonical
+    /// path, so it still strips against the directory the walk came from.
+    #[cfg(unix)]
+    #[test]
+    fn mismatched_casing_keeps_the_walked_prefix() {
+        let dir = dir_with("walked_prefix", &["IMG_0001.JPG"]);
+        let link = dir.with_file_name(format!("{}_link", dir.file_name().unwrap().to_string_lossy()));
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+
+        let found = resolve_media_path(&link, "img_0001.jpg", &mut Vec::new()).unwrap();
+        assert_eq!(found.strip_prefix(&link).ok(), Some(Path::new("IMG_0001.JPG")));
+        std::fs::remove_file(&link).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Deduplicated Takeout copies link one album's file to another's, often
+    /// under a different name.
+    #[test]
+    fn symlinked_media_resolves_under_its_own_name() {
+        let dir = dir_with("symlinked_media", &["IMG_0001.jpg"]);
+        std::os::unix::fs::symlink(dir.join("IMG_0001.jpg"), dir.join("IMG_0002.jpg")).unwrap();
+        let mut notes = Vec::new();
+
+        let found = resolve_media_path(&dir, "IMG_0002.jpg", &mut notes).unwrap();
+        assert_eq!(found, dir.join("IMG_0002.jpg"));
+        assert!(notes.is_empty(), "{:?}", note_texts(&notes));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn accented_titles_match_either_normalization_form() {
+        let composed = "Caf\u{e9} de Flore.jpg";
+        let decomposed = "Cafe\u{301} de Flore.jpg";
+        for (on_disk, title) in [(composed, decomposed), (decomposed, composed), (composed, "Caf\u{e9} de Flore.jpg  ")] {
+            let dir = dir_with("unicode", &[on_disk]);
+            let mut notes = Vec::new();
+
+            let found = resolve_media_path(&dir, title, &mut notes).unwrap();
+            assert_eq!(found.file_name().unwrap().to_string_lossy(), on_disk, "title {:?}", title);
+            // A filesystem that normalizes names itself finds the exact path.
+            if !notes.is_empty() {
+                assert_eq!(note_texts(&notes), [format!("matched {} ignoring spacing or unicode form", on_disk)]);
+            }
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn extension_variant_adds_or_drops_the_doubled_extension() {
+        assert_eq!(extension_variant("IMG_1234.jpg").as_deref(), Some("IMG_1234.jpg.jpg"));
+        assert_eq!(extension_variant("IMG_1234.jpg.jpg").as_deref(), Some("IMG_1234.jpg"));
+        assert_eq!(extension_variant("IMG_1234.JPG.jpg").as_deref(), Some("IMG_1234.JPG"));
+        assert_eq!(extension_variant("IMG_1234.heic.jpg").as_deref(), Some("IMG_1234.heic.jpg.jpg"));
+        assert_eq!(extension_variant("IMG_1234"), None);
+    }
+
+    #[test]
+    fn doubled_extensions_are_matched_either_way() {
+        for (on_disk, title) in [("IMG_1234.jpg.jpg", "IMG_1234.jpg"), ("IMG_1234.jpg", "IMG_1234.jpg.jpg")] {
+            let dir = dir_with("doubled", &[on_disk]);
+            let mut notes = Vec::new();
+
+            assert_eq!(resolve_media_path(&dir, title, &mut notes), Some(dir.join(on_disk)), "title {}", title);
+            assert_eq!(note_texts(&notes), [format!("matched {} despite a doubled extension", on_disk)]);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        let mut index = MediaIndex::default();
+        index.insert(PathBuf::from("/takeout/Photos from 2019/IMG_1234.jpg.jpg"));
+        assert_eq!(
+            index.find("IMG_1234.jpg", &mut Vec::new()),
+            Some(PathBuf::from("/takeout/Photos from 2019/IMG_1234.jpg.jpg")),
+        );
+    }
+
+    #[test]
+    fn supplemental_metadata_sidecars_name_their_media() {
+        let fixtures = [
+            ("IMG_0001.jpg.supplemental-metadata.json", Some("IMG_0001.jpg")),
+            // Cut short to keep the whole name within 51 characters.
+            ("PXL_20230514_101112345.MP.jpg.supplemental-metad.json", Some("PXL_20230514_101112345.MP.jpg")),
+            ("IMG_0001.jpg.supplemen.json", Some("IMG_0001.jpg")),
+            ("IMG_0001.jpg.s.json", Some("IMG_0001.jpg")),
+            // A duplicate's counter moves in front of the media extension.
+            ("IMG_0001.jpg.supplemental-metadata(1).json", Some("IMG_0001(1).jpg")),
+            ("IMG_0001.jpg.supplemen(12).json", Some("IMG_0001(12).jpg")),
+            ("IMG_0001.supplemental-metadata(2).json", Some("IMG_0001(2)")),
+            // Not this naming.
+            ("IMG_0001.jpg.json", None),
+            ("IMG_0001.jpg.supplemental-metadata", None),
+            ("IMG_0001.jpg.metadata.json", None),
+            ("IMG_0001.jpg.supplemental-metadata().json", None),
+            ("IMG_0001.jpg.supplemental-metadata-extra.json", None),
+        ];
+        for (sidecar, media) in fixtures {
+            assert_eq!(media_name_from_sidecar(sidecar).as_deref(), media, "{}", sidecar);
+        }
+    }
+
+    #[test]
+    fn truncated_supplemental_sidecar_is_found_for_its_media() {
+        let dir = dir_with("supplemental", &["IMG_0001.jpg", "IMG_0001.jpg.supplemen.json", "IMG_0001(1).jpg"]);
+        std::fs::write(dir.join("IMG_0001.jpg.supplemental-metadata(1).json"), b"").unwrap();
+
+        assert_eq!(find_sidecar(&dir.join("IMG_0001.jpg")), Some(dir.join("IMG_0001.jpg.supplemen.json")));
+        assert_eq!(
+            find_sidecar(&dir.join("IMG_0001(1).jpg")),
+            Some(dir.join("IMG_0001.jpg.supplemental-metadata(1).json")),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+pub const RUN_LOG_FILE_NAME: &str = "metadata_fix_run.log";
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Done,
+    Skipped,
+    Error,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    outcome: Outcome,
+    sidecar: PathBuf,
+    /// The error for failures; the image hash, if requested, for done files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+/// Append-only record of every file's outcome, one JSON object per line.
+/// Lines are flushed as they are written so a killed run still leaves a
+/// usable log behind.
+pub struct RunLog {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl RunLog {
+    /// The file is only created on the first [`RunLog::record`].
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            path: dir.join(RUN_LOG_FILE_NAME),
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, outcome: Outcome, sidecar: &Path, detail: Option<&str>) -> std::io::Result<()> {
+        let entry = Entry {
+            outcome,
+            sidecar: sidecar.to_path_buf(),
+            detail: detail.map(str::to_string),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        file.as_mut().unwrap().write_all(line.as_bytes())
+    }
+
+    /// Sidecars a previous run finished, either written or deliberately
+    /// skipped. Errors are left out so they get retried. A missing log means
+    /// nothing is done yet; unreadable lines (e.g. one cut off by a crash)
+    /// are ignored.
+    pub fn load_finished(&self) -> std::io::Result<HashSet<PathBuf>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut finished = HashSet::new();
+        for line in BufReader::new(file).lines() {
+            let Ok(entry) = serde_json::from_str::<Entry>(&line?) else {
+                continue;
+            };
+            match entry.outcome {
+                Outcome::Done | Outcome::Skipped => {
+                    finished.insert(entry.sidecar);
+                }
+                Outcome::Error => {
+                    finished.remove(&entry.sidecar);
+                }
+            }
+        }
+        Ok(finished)
+    }
+}
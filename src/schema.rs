@@ -0,0 +1,61 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::decode_json_text;
+
+/// How often each key path appears across a set of sidecars. Nested keys are
+/// joined with `.`, array elements are written as `[]`, so
+/// `people[].name` counts sidecars with at least one named person.
+#[derive(Default)]
+pub struct SchemaReport {
+    pub files: usize,
+    pub unreadable: Vec<(PathBuf, String)>,
+    /// Key path to the number of files it appears in.
+    pub fields: BTreeMap<String, usize>,
+}
+
+pub fn analyze(json_files: &[PathBuf]) -> SchemaReport {
+    let mut report = SchemaReport::default();
+
+    for json_file in json_files {
+        match read_json(json_file) {
+            Ok(json_data) => {
+                report.files += 1;
+                let mut paths = BTreeSet::new();
+                collect_paths(&json_data, String::new(), &mut paths);
+                for path in paths {
+                    *report.fields.entry(path).or_default() += 1;
+                }
+            }
+            Err(message) => report.unreadable.push((json_file.clone(), message)),
+        }
+    }
+
+    report
+}
+
+pub fn read_json(path: &Path) -> Result<Value, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Error reading JSON: {}", e))?;
+    let text = decode_json_text(&bytes)?;
+    serde_json::from_str(&text).map_err(|e| format!("Error parsing JSON: {}", e))
+}
+
+fn collect_paths(value: &Value, prefix: String, paths: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map {
+                let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                paths.insert(path.clone());
+                collect_paths(child, path, paths);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect_paths(item, format!("{}[]", prefix), paths);
+            }
+        }
+        _ => {}
+    }
+}
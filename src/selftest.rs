@@ -0,0 +1,432 @@
+use std::io::{Cursor, Write};
+
+use chrono::{DateTime, TimeZone, Utc};
+use exif::{Field, In, Tag, Value};
+
+use crate::media::{self, App1Placement, ExifFields, GpsLocation, IptcFields, JpegFields, XmpFields};
+
+const LOCATION: GpsLocation = GpsLocation {
+    latitude: 48.858_37,
+    longitude: -122.294_5,
+    altitude: Some(35.5),
+    accuracy: None,
+    dop: None,
+};
+
+/// Coordinates are stored as 1e-7 minute rationals, well inside this.
+const COORDINATE_TOLERANCE: f64 = 1e-6;
+
+/// Writes one kind of file at the given date and reads it back.
+type Check = fn(DateTime<Utc>) -> Result<(), String>;
+
+/// The date every check writes; unit tests use it too.
+pub(crate) fn sample_datetime() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2019, 7, 14, 9, 30, 15).unwrap()
+}
+
+/// Writes known metadata into synthetic JPEG, PNG and MP4 files with the
+/// same functions a real run uses, then reads it back and decodes the
+/// images. Prints PASS or FAIL per check and returns whether everything
+/// passed.
+pub fn run() -> bool {
+    let datetime = sample_datetime();
+    let checks: [(&str, Check); 10] = [
+        ("JPEG, EXIF after JFIF", |datetime| check_jpeg(datetime, App1Placement::AfterJfif)),
+        ("JPEG, EXIF first", |datetime| check_jpeg(datetime, App1Placement::First)),
+        ("JPEG with EXIF and XMP already", check_jpeg_with_app1),
+        ("PNG", check_png),
+        ("PNG, interlaced with palette", check_png_lossless),
+        ("MP4", check_mp4),
+        ("GPS coordinates", check_coordinates),
+        ("GPS altitude", check_altitudes),
+        ("UTF-8 caption", check_caption),
+        ("File date", check_file_time),
+    ];
+
+    let mut passed = true;
+    for (format, check) in checks {
+        match check(datetime) {
+            Ok(()) => println!("PASS {}", format),
+            Err(e) => {
+                println!("FAIL {}: {}", format, e);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+pub(crate) fn check_jpeg(datetime: DateTime<Utc>, placement: App1Placement) -> Result<(), String> {
+    let output = write_tiny_jpeg(LOCATION, datetime, &ExifFields::default(), &XmpFields::default(), placement)?;
+    jpeg_decoder::Decoder::new(Cursor::new(&output)).decode()
+        .map_err(|e| format!("output does not decode: {}", e))?;
+    check_read_back(&output, datetime)
+}
+
+/// The file's EXIF is merged with ours, its XMP kept, and nothing after
+/// them lost.
+pub(crate) fn check_jpeg_with_app1(datetime: DateTime<Utc>) -> Result<(), String> {
+    let old_exif = camera_exif().map_err(|e| format!("could not build test EXIF: {}", e))?;
+    let mut old_xmp = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+    old_xmp.extend_from_slice(OLD_XMP_PACKET.as_bytes());
+
+    let mut input = tiny_jpeg();
+    let mut app1s = Vec::new();
+    for payload in [&old_exif, &old_xmp] {
+        app1s.extend_from_slice(&[0xFF, 0xE1]);
+        app1s.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        app1s.extend_from_slice(payload);
+    }
+    // After the JFIF APP0, where cameras put them.
+    input.splice(20..20, app1s);
+
+    let output = media::update_jpeg_metadata_bytes(
+        &input, Some(LOCATION), datetime,
+        JpegFields { exif: &ExifFields::default(), xmp: &XmpFields::default(), iptc: &IptcFields::default(), placement: App1Placement::AfterJfif },
+    )
+        .map_err(|e| format!("write failed: {}", e))?;
+    jpeg_decoder::Decoder::new(Cursor::new(&output)).decode()
+        .map_err(|e| format!("output does not decode: {}", e))?;
+    if !output.windows(OLD_XMP_PACKET.len()).any(|window| window == OLD_XMP_PACKET.as_bytes()) {
+        return Err("the file's XMP was dropped".to_string());
+    }
+
+    let merged = exif::Reader::new().read_from_container(&mut Cursor::new(&output))
+        .map_err(|e| format!("EXIF does not read back: {}", e))?;
+    for (tag, _) in CAMERA_FIELDS {
+        if merged.get_field(tag, In::PRIMARY).is_none() {
+            return Err(format!("the file's {} was dropped", tag));
+        }
+    }
+    let orientation = merged.get_field(Tag::Orientation, In::PRIMARY).and_then(|field| field.value.get_uint(0));
+    if orientation != Some(6) {
+        return Err(format!("Orientation read back as {:?}, expected 6", orientation));
+    }
+    check_read_back(&output, datetime)
+}
+
+/// Written by the camera and not by us, so they must survive a rewrite.
+const CAMERA_FIELDS: [(Tag, &str); 2] = [(Tag::Make, "Example"), (Tag::Model, "Camera 1")];
+
+/// An EXIF APP1 payload with camera fields, Orientation and an old date.
+pub(crate) fn camera_exif() -> Result<Vec<u8>, exif::Error> {
+    let mut fields: Vec<Field> = CAMERA_FIELDS.iter()
+        .map(|(tag, value)| Field { tag: *tag, ifd_num: In::PRIMARY, value: Value::Ascii(vec![value.as_bytes().to_vec()]) })
+        .collect();
+    fields.push(Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![6]) });
+    fields.push(Field {
+        tag: Tag::DateTimeOriginal,
+        ifd_num: In::PRIMARY,
+        value: Value::Ascii(vec![b"2001:01:01 00:00:00".to_vec()]),
+    });
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in &fields {
+        writer.push_field(field);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, false)?;
+
+    let mut payload = b"Exif\0\0".to_vec();
+    payload.extend(tiff.into_inner());
+    Ok(payload)
+}
+
+const OLD_XMP_PACKET: &str = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>";
+
+pub(crate) fn check_png(datetime: DateTime<Utc>) -> Result<(), String> {
+    let output = media::update_png_metadata_bytes(
+        &tiny_png()?, Some(LOCATION), datetime, &ExifFields::default(), &XmpFields::default(), true,
+    )
+        .map_err(|e| format!("write failed: {}", e))?;
+    let mut reader = png::Decoder::new(Cursor::new(&output)).read_info()
+        .map_err(|e| format!("output does not decode: {}", e))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buf)
+        .map_err(|e| format!("output does not decode: {}", e))?;
+    check_read_back(&output, datetime)
+}
+
+/// Every chunk of the input comes through byte for byte; the only change is
+/// the eXIf chunk inserted after IHDR.
+pub(crate) fn check_png_lossless(datetime: DateTime<Utc>) -> Result<(), String> {
+    let input = interlaced_palette_png()?;
+    let output = media::update_png_metadata_bytes(
+        &input, Some(LOCATION), datetime, &ExifFields::default(), &XmpFields::default(), false,
+    )
+        .map_err(|e| format!("write failed: {}", e))?;
+
+    let exif_start = PNG_IHDR_END;
+    if output.get(exif_start + 4..exif_start + 8) != Some(b"eXIf") {
+        return Err("eXIf is not right after IHDR".to_string());
+    }
+    let exif_len = u32::from_be_bytes(output[exif_start..exif_start + 4].try_into().unwrap()) as usize;
+    let exif_end = exif_start + 12 + exif_len;
+    if [&output[..exif_start], &output[exif_end..]].concat() != input {
+        return Err("chunks other than eXIf changed".to_string());
+    }
+
+    let mut reader = png::Decoder::new(Cursor::new(&output)).read_info()
+        .map_err(|e| format!("output does not decode: {}", e))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buf)
+        .map_err(|e| format!("output does not decode: {}", e))?;
+    check_read_back(&output, datetime)
+}
+
+/// The date and location read back, and the chunk offset still finds the
+/// media data although `moov`, ahead of it, grew.
+pub(crate) fn check_mp4(datetime: DateTime<Utc>) -> Result<(), String> {
+    let output = media::update_video_metadata_bytes(&tiny_mp4(), Some(LOCATION), datetime)
+        .map_err(|e| format!("write failed: {}", e))?;
+
+    let existing = read_back(&output)?;
+    if existing.datetime != Some(datetime) {
+        return Err(format!("creation_time read back as {:?}, expected {}", existing.datetime, datetime));
+    }
+    let location = existing.location.ok_or("©xyz did not read back")?;
+    if (location.latitude - LOCATION.latitude).abs() > ISO6709_TOLERANCE
+        || (location.longitude - LOCATION.longitude).abs() > ISO6709_TOLERANCE
+    {
+        return Err(format!("©xyz read back as {:?}, expected {:?}", location, LOCATION));
+    }
+
+    let stco = output.windows(4).position(|window| window == b"stco").ok_or("stco is gone")?;
+    let entry = stco + 12;
+    let offset = u32::from_be_bytes(output[entry..entry + 4].try_into().unwrap()) as usize;
+    if output.get(offset..offset + MP4_MEDIA.len()) != Some(MP4_MEDIA) {
+        return Err("the chunk offset no longer points at the media data".to_string());
+    }
+    Ok(())
+}
+
+/// Coordinates that are easy to get wrong read back where they were written:
+/// whole degrees, and minutes that round up to the next degree.
+pub(crate) fn check_coordinates(datetime: DateTime<Utc>) -> Result<(), String> {
+    let coordinates = [(37.7749, -122.4194), (45.0, -3.0), (0.0, 0.0), (10.999_999_999_9, -179.999_999_999_9)];
+    for (latitude, longitude) in coordinates {
+        let location = GpsLocation { latitude, longitude, ..LOCATION };
+        let existing = jpeg_round_trip(location, datetime)?;
+        let read_back = existing.location.ok_or("GPS did not read back")?;
+        if (read_back.latitude - latitude).abs() > COORDINATE_TOLERANCE
+            || (read_back.longitude - longitude).abs() > COORDINATE_TOLERANCE
+        {
+            return Err(format!("({}, {}) read back as ({}, {})", latitude, longitude, read_back.latitude, read_back.longitude));
+        }
+    }
+    Ok(())
+}
+
+/// Fractional altitudes round rather than truncate, below sea level sets
+/// GPSAltitudeRef, and a non-finite altitude is left out.
+pub(crate) fn check_altitudes(datetime: DateTime<Utc>) -> Result<(), String> {
+    let altitudes = [(123.456, Some(123.456)), (-12.3456, Some(-12.346)), (0.0, Some(0.0)), (f64::NAN, None)];
+    for (altitude, expected) in altitudes {
+        let location = GpsLocation { altitude: Some(altitude), ..LOCATION };
+        let existing = jpeg_round_trip(location, datetime)?;
+        let read_back = existing.location.ok_or("GPS did not read back")?.altitude;
+        let matches = match (read_back, expected) {
+            (Some(read_back), Some(expected)) => (read_back - expected).abs() < 1e-9,
+            (read_back, expected) => read_back.is_none() && expected.is_none(),
+        };
+        if !matches {
+            return Err(format!("altitude {} read back as {:?}, expected {:?}", altitude, read_back, expected));
+        }
+    }
+    Ok(())
+}
+
+/// A non-ASCII caption reaches ImageDescription as its ASCII fallback and
+/// comes through whole in UserComment and XMP.
+pub(crate) fn check_caption(datetime: DateTime<Utc>) -> Result<(), String> {
+    const CAPTION: &str = "Café am Zürichsee ☀";
+    let exif = ExifFields { description: Some(CAPTION.to_string()), ..ExifFields::default() };
+    let xmp = XmpFields { description: Some(CAPTION.to_string()), ..XmpFields::default() };
+    let output = write_tiny_jpeg(LOCATION, datetime, &exif, &xmp, App1Placement::AfterJfif)?;
+
+    let read_back = exif::Reader::new().read_from_container(&mut Cursor::new(&output))
+        .map_err(|e| format!("EXIF does not read back: {}", e))?;
+    let description = read_back.get_field(Tag::ImageDescription, In::PRIMARY).map(|field| &field.value);
+    if !matches!(description, Some(Value::Ascii(values)) if values.first().is_some_and(|v| v == b"Cafe am Zurichsee ?")) {
+        return Err(format!("ImageDescription read back as {:?}", description));
+    }
+    let comment = user_comment(&read_back);
+    if comment.as_deref() != Some(CAPTION) {
+        return Err(format!("UserComment read back as {:?}", comment));
+    }
+    let packet = String::from_utf8_lossy(&output);
+    if !packet.contains(&format!("<rdf:li xml:lang=\"x-default\">{}</rdf:li>", CAPTION)) {
+        return Err("dc:description is missing from the XMP".to_string());
+    }
+    Ok(())
+}
+
+/// UserComment decoded from the UTF-16 we write it as.
+pub(crate) fn user_comment(exif: &exif::Exif) -> Option<String> {
+    exif.get_field(Tag::UserComment, In::PRIMARY).and_then(|field| match &field.value {
+        Value::Undefined(bytes, _) => bytes.strip_prefix(b"UNICODE\0").map(|utf16| {
+            let units: Vec<u16> = utf16.chunks_exact(2).map(|unit| u16::from_be_bytes([unit[0], unit[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }),
+        _ => None,
+    })
+}
+
+/// A written file's modified time becomes the photo's date.
+pub(crate) fn check_file_time(datetime: DateTime<Utc>) -> Result<(), String> {
+    let path = std::env::temp_dir().join(format!("metadata_fix_selftest_{}.jpg", std::process::id()));
+    let result = std::fs::write(&path, tiny_jpeg())
+        .and_then(|()| crate::set_file_time(&path, datetime))
+        .and_then(|()| std::fs::metadata(&path)?.modified())
+        .map_err(|e| format!("could not set the file's date: {}", e));
+    let _ = std::fs::remove_file(&path);
+
+    let modified = DateTime::<Utc>::from(result?);
+    if modified != datetime {
+        return Err(format!("modified time is {}, expected {}", modified, datetime));
+    }
+    Ok(())
+}
+
+/// ISO 6709 strings carry four decimal places.
+const ISO6709_TOLERANCE: f64 = 1e-4;
+
+/// [`tiny_jpeg`] with `location`, `datetime` and the given fields written.
+pub(crate) fn write_tiny_jpeg(
+    location: GpsLocation,
+    datetime: DateTime<Utc>,
+    exif: &ExifFields,
+    xmp: &XmpFields,
+    placement: App1Placement,
+) -> Result<Vec<u8>, String> {
+    media::update_jpeg_metadata_bytes(
+        &tiny_jpeg(), Some(location), datetime,
+        JpegFields { exif, xmp, iptc: &IptcFields::default(), placement },
+    )
+        .map_err(|e| format!("write failed: {}", e))
+}
+
+fn read_back(output: &[u8]) -> Result<media::ExistingMetadata, String> {
+    media::read_existing_metadata(&mut Cursor::new(output))
+        .map_err(|e| format!("metadata does not read back: {}", e))
+}
+
+/// What a reader finds after only the date and `location` are written.
+pub(crate) fn jpeg_round_trip(location: GpsLocation, datetime: DateTime<Utc>) -> Result<media::ExistingMetadata, String> {
+    read_back(&write_tiny_jpeg(location, datetime, &ExifFields::default(), &XmpFields::default(), App1Placement::AfterJfif)?)
+}
+
+fn check_read_back(output: &[u8], datetime: DateTime<Utc>) -> Result<(), String> {
+    let existing = read_back(output)?;
+
+    if existing.datetime != Some(datetime) {
+        return Err(format!("date read back as {:?}, expected {}", existing.datetime, datetime));
+    }
+    let location = existing.location.ok_or("GPS did not read back")?;
+    if (location.latitude - LOCATION.latitude).abs() > COORDINATE_TOLERANCE
+        || (location.longitude - LOCATION.longitude).abs() > COORDINATE_TOLERANCE
+        || location.altitude != LOCATION.altitude
+    {
+        return Err(format!("GPS read back as {:?}, expected {:?}", location, LOCATION));
+    }
+    Ok(())
+}
+
+/// A 1x1 grey baseline JFIF. Both Huffman tables hold a single one-bit code
+/// for symbol 0, so the only block is "DC difference 0, end of block": two
+/// zero bits, padded with ones.
+pub fn tiny_jpeg() -> Vec<u8> {
+    let mut jpeg = vec![0xFF, 0xD8];
+
+    jpeg.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x10]);
+    jpeg.extend_from_slice(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+
+    jpeg.extend_from_slice(&[0xFF, 0xDB, 0x00, 0x43, 0x00]);
+    jpeg.extend_from_slice(&[1; 64]);
+
+    jpeg.extend_from_slice(&[0xFF, 0xC0, 0x00, 0x0B, 0x08, 0x00, 0x01, 0x00, 0x01, 0x01, 0x01, 0x11, 0x00]);
+
+    for class in [0x00, 0x10] {
+        jpeg.extend_from_slice(&[0xFF, 0xC4, 0x00, 0x14, class, 1]);
+        jpeg.extend_from_slice(&[0; 15]);
+        jpeg.push(0);
+    }
+
+    jpeg.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00]);
+    jpeg.extend_from_slice(&[0x3F, 0xFF, 0xD9]);
+    jpeg
+}
+
+const MP4_MEDIA: &[u8] = b"media data";
+
+/// `ftyp`, then a `moov` with an `mvhd` and one track whose single chunk is
+/// [`MP4_MEDIA`], then the `mdat` holding it.
+fn tiny_mp4() -> Vec<u8> {
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut mp4_box = ((8 + body.len()) as u32).to_be_bytes().to_vec();
+        mp4_box.extend_from_slice(kind);
+        mp4_box.extend_from_slice(body);
+        mp4_box
+    }
+
+    let ftyp = mp4_box(b"ftyp", b"isom\0\0\x02\0isommp41");
+    let mut mvhd = vec![0; 100];
+    mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+    // stco: version and flags, one entry, then the offset, filled in below
+    // once the layout is known.
+    let stco = [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+    let build_moov = |stco: &[u8]| {
+        let stbl = mp4_box(b"stbl", &mp4_box(b"stco", stco));
+        let trak = mp4_box(b"trak", &mp4_box(b"mdia", &mp4_box(b"minf", &stbl)));
+        mp4_box(b"moov", &[mp4_box(b"mvhd", &mvhd), trak].concat())
+    };
+
+    let media_offset = (ftyp.len() + build_moov(&stco).len() + 8) as u32;
+    let mut stco = stco;
+    stco[8..].copy_from_slice(&media_offset.to_be_bytes());
+    [ftyp, build_moov(&stco), mp4_box(b"mdat", MP4_MEDIA)].concat()
+}
+
+/// Signature plus the 13-byte IHDR chunk.
+const PNG_IHDR_END: usize = 8 + 12 + 13;
+
+/// A 1x1 Adam7-interlaced indexed PNG with a palette, tRNS and gAMA. Built
+/// by hand since the encoder doesn't write interlaced images.
+fn interlaced_palette_png() -> Result<Vec<u8>, String> {
+    fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        crc.update(data);
+        [&(data.len() as u32).to_be_bytes()[..], kind, data, &crc.finalize().to_be_bytes()].concat()
+    }
+
+    // Only the first Adam7 pass has a pixel: filter byte 0, then index 1.
+    let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    zlib.write_all(&[0, 1]).map_err(|e| format!("could not build test PNG: {}", e))?;
+    let idat = zlib.finish().map_err(|e| format!("could not build test PNG: {}", e))?;
+
+    Ok([
+        &b"\x89PNG\r\n\x1a\n"[..],
+        &chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 3, 0, 0, 1]),
+        &chunk(b"gAMA", &45_455u32.to_be_bytes()),
+        &chunk(b"PLTE", &[0, 0, 0, 255, 128, 0]),
+        &chunk(b"tRNS", &[255, 128]),
+        &chunk(b"IDAT", &idat),
+        &chunk(b"IEND", &[]),
+    ].concat())
+}
+
+/// A 1x1 black 8-bit greyscale PNG.
+pub(crate) fn tiny_png() -> Result<Vec<u8>, String> {
+    let mut png_data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_data, 1, 1);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()
+        .map_err(|e| format!("could not build test PNG: {}", e))?;
+    writer.write_image_data(&[0])
+        .map_err(|e| format!("could not build test PNG: {}", e))?;
+    writer.finish()
+        .map_err(|e| format!("could not build test PNG: {}", e))?;
+    Ok(png_data)
+}
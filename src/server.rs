@@ -0,0 +1,149 @@
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use metadata_fix::control::{RunControl, RunState};
+use metadata_fix::{process_photos, ProcessMessage, ProcessOptions};
+
+#[derive(Deserialize)]
+struct ProcessRequest {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RunStatus {
+    #[default]
+    Idle,
+    Running,
+    Finished,
+    Failed,
+}
+
+/// What `GET /status` returns, kept up to date from the run's messages.
+#[derive(Clone, Default, Serialize)]
+struct Status {
+    state: RunStatus,
+    phase: Option<&'static str>,
+    progress: f32,
+    processed: usize,
+    errors: usize,
+    skipped: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Default)]
+struct Shared {
+    status: Mutex<Status>,
+    control: Mutex<Option<Arc<RunControl>>>,
+}
+
+/// Serves until the process is killed. Requests are not authenticated and
+/// name paths on this machine, so `addr` should be a loopback address.
+///
+/// - `POST /process` with `{"input": ..., "output": ...}` starts a run with
+///   default options, unless one is already going.
+/// - `GET /status` returns the current run's counts and progress.
+/// - `POST /cancel` stops the current run after the files in progress.
+pub fn serve(addr: &str) -> Result<(), String> {
+    let server = Server::http(addr).map_err(|e| format!("Could not listen on {}: {}", addr, e))?;
+    println!("Listening on http://{}", addr);
+
+    let shared = Arc::new(Shared::default());
+    for mut request in server.incoming_requests() {
+        let (code, body) = match (request.method(), request.url()) {
+            (Method::Get, "/status") => (200, to_json(&*shared.status.lock().unwrap())),
+            (Method::Post, "/process") => start_run(&shared, &mut request),
+            (Method::Post, "/cancel") => match &*shared.control.lock().unwrap() {
+                Some(control) => {
+                    control.set(RunState::Cancelled);
+                    (202, error_json("cancelling"))
+                }
+                None => (409, error_json("nothing is running")),
+            },
+            _ => (404, error_json("not found")),
+        };
+
+        let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+        let response = Response::from_string(body).with_status_code(code).with_header(content_type);
+        if let Err(e) = request.respond(response) {
+            eprintln!("⚠️ Could not send response: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn start_run(shared: &Arc<Shared>, request: &mut Request) -> (u16, String) {
+    let run: ProcessRequest = match serde_json::from_reader(request.as_reader()) {
+        Ok(run) => run,
+        Err(e) => return (400, error_json(&format!("expected {{\"input\", \"output\"}}: {}", e))),
+    };
+
+    let mut status = shared.status.lock().unwrap();
+    if status.state == RunStatus::Running {
+        return (409, error_json("a run is already in progress"));
+    }
+    *status = Status { state: RunStatus::Running, ..Status::default() };
+
+    let control = Arc::new(RunControl::new());
+    *shared.control.lock().unwrap() = Some(control.clone());
+    let installed = control.clone();
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || process_photos(run.input, run.output, ProcessOptions::default(), &control, sender));
+
+    let shared = shared.clone();
+    thread::spawn(move || {
+        for msg in receiver {
+            let mut status = shared.status.lock().unwrap();
+            match msg {
+                ProcessMessage::Phase(phase) => {
+                    status.phase = Some(phase.label());
+                    status.progress = 0.0;
+                }
+                ProcessMessage::Progress(progress) => status.progress = progress,
+                ProcessMessage::FileProcessed { .. } => status.processed += 1,
+                ProcessMessage::FileFailed { .. } => status.errors += 1,
+                ProcessMessage::FileSkipped { .. } => status.skipped += 1,
+                ProcessMessage::Completed(summary) => {
+                    status.state = RunStatus::Finished;
+                    status.processed = summary.processed;
+                    status.errors = summary.errors;
+                    status.skipped = summary.skipped;
+                }
+                ProcessMessage::Error(e) => {
+                    status.state = RunStatus::Failed;
+                    status.error = Some(e);
+                }
+                ProcessMessage::Status(_) | ProcessMessage::FilesDiscovered(_) => {}
+            }
+        }
+
+        let mut status = shared.status.lock().unwrap();
+        if status.state == RunStatus::Running {
+            // The worker hung up without reporting an outcome.
+            status.state = RunStatus::Failed;
+            status.error = Some("processing stopped unexpectedly".to_string());
+        }
+        // A run started after ours finished has installed its own control.
+        let mut current = shared.control.lock().unwrap();
+        if current.as_ref().is_some_and(|control| Arc::ptr_eq(control, &installed)) {
+            *current = None;
+        }
+    });
+
+    (202, to_json(&*status))
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+fn error_json(message: &str) -> String {
+    to_json(&serde_json::json!({ "message": message }))
+}
@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::{media, MediaFormat};
+
+/// Re-opens a written file and decodes it completely, so an output with a
+/// valid header but broken image data is still caught. Formats we don't
+/// rewrite are not checked.
+pub fn verify_output(path: &Path) -> Result<(), String> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some(format) = MediaFormat::detect(&name) else {
+        return Ok(());
+    };
+
+    let file = File::open(path).map_err(|e| format!("Could not open output: {}", e))?;
+    let mut reader = BufReader::new(file);
+
+    match format {
+        MediaFormat::Jpeg => {
+            jpeg_decoder::Decoder::new(reader).decode()
+                .map_err(|e| format!("JPEG does not decode: {}", e))?;
+        }
+        MediaFormat::Png => {
+            let mut reader = png::Decoder::new(reader).read_info()
+                .map_err(|e| format!("PNG header does not decode: {}", e))?;
+            let mut buf = vec![0; reader.output_buffer_size()];
+            reader.next_frame(&mut buf)
+                .map_err(|e| format!("PNG image data does not decode: {}", e))?;
+            reader.finish()
+                .map_err(|e| format!("PNG trailing chunks do not decode: {}", e))?;
+        }
+        // We can't decode these, but can check the container still leads
+        // to the metadata we wrote.
+        MediaFormat::Heif | MediaFormat::Video => {
+            media::read_existing_metadata(&mut reader)
+                .map_err(|e| format!("{} metadata does not read back: {}", format.label(), e))?;
+        }
+    }
+
+    Ok(())
+}
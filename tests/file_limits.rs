@@ -0,0 +1,71 @@
+//! Runs a batch with the process's open-file limit set below what the
+//! workers would hold at once without the limiter. This is its own test
+//! binary because the limit applies to the whole process.
+#![cfg(target_os = "linux")]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use metadata_fix::control::RunControl;
+use metadata_fix::selftest::tiny_jpeg;
+use metadata_fix::{process_photos, ProcessMessage, ProcessOptions};
+
+const FILES: usize = 300;
+const WORKERS: usize = 16;
+const MAX_OPEN_FILES: usize = 2;
+
+/// Descriptors beyond those already open when the batch starts: enough
+/// for [`MAX_OPEN_FILES`] files, far short of one per worker.
+const SPARE_DESCRIPTORS: u64 = 12;
+
+fn write_takeout(dir: &Path) {
+    fs::create_dir_all(dir).unwrap();
+    let jpeg = tiny_jpeg();
+    for i in 0..FILES {
+        let name = format!("IMG_{:04}.jpg", i);
+        fs::write(dir.join(&name), &jpeg).unwrap();
+        let sidecar = format!(r#"{{"title": "{}", "photoTakenTime": {{"timestamp": "1563096615"}}}}"#, name);
+        fs::write(dir.join(format!("{}.json", name)), sidecar).unwrap();
+    }
+}
+
+fn open_descriptors() -> u64 {
+    fs::read_dir("/proc/self/fd").unwrap().count() as u64
+}
+
+fn set_open_file_limit(soft: libc::rlim_t) -> libc::rlim_t {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) }, 0);
+    let previous = limit.rlim_cur;
+    limit.rlim_cur = soft;
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+    previous
+}
+
+#[test]
+fn batch_stays_under_the_open_file_limit() {
+    let root: PathBuf = std::env::temp_dir().join(format!("metadata_fix_file_limits_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let (input, output) = (root.join("input"), root.join("output"));
+    write_takeout(&input);
+
+    let options = ProcessOptions { jobs: Some(WORKERS), max_open_files: MAX_OPEN_FILES, ..ProcessOptions::default() };
+    let control = RunControl::new();
+    let (sender, receiver) = mpsc::channel();
+
+    let previous = set_open_file_limit(open_descriptors() + SPARE_DESCRIPTORS);
+    let result = process_photos(input, output, options, &control, sender);
+    set_open_file_limit(previous);
+
+    let errors: Vec<String> = receiver.try_iter()
+        .filter_map(|message| match message {
+            ProcessMessage::FileFailed { message, .. } => Some(message),
+            _ => None,
+        })
+        .collect();
+    let summary = result.unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!((summary.processed, summary.errors), (FILES, 0));
+    fs::remove_dir_all(&root).unwrap();
+}
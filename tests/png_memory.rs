@@ -0,0 +1,78 @@
+//! Rewrites the metadata of a PNG whose decoded image is far larger than
+//! the file, under an allocator that records the peak, to show that memory
+//! follows the file size and never the pixel count. This is its own test
+//! binary so the allocator sees nothing else.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::DateTime;
+use metadata_fix::media::{self, ExifFields, GpsLocation, XmpFields};
+
+struct PeakAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAlloc = PeakAlloc;
+
+/// 4096x4096 RGBA: 64 MiB of pixels, a few hundred KiB compressed.
+const SIDE: u32 = 4096;
+
+/// Encoded a row at a time, so building the fixture doesn't need the
+/// whole image in memory either.
+fn large_png() -> Vec<u8> {
+    let mut png_data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_data, SIDE, SIDE);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    let mut stream = writer.stream_writer().unwrap();
+    let row: Vec<u8> = (0..SIDE * 4).map(|i| (i % 251) as u8).collect();
+    for _ in 0..SIDE {
+        stream.write_all(&row).unwrap();
+    }
+    stream.finish().unwrap();
+    writer.finish().unwrap();
+    png_data
+}
+
+#[test]
+fn large_png_rewrite_stays_within_the_file_size() {
+    let input = large_png();
+    let decoded_size = (SIDE * SIDE * 4) as usize;
+    assert!(input.len() * 16 < decoded_size, "fixture compresses too poorly: {} bytes", input.len());
+
+    let location = GpsLocation { latitude: 48.85837, longitude: 2.294481, altitude: None, accuracy: None, dop: None };
+    let datetime = DateTime::from_timestamp(1_563_096_615, 0).unwrap();
+    let before = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+
+    let output = media::update_png_metadata_bytes(
+        &input, Some(location), datetime, &ExifFields::default(), &XmpFields::default(), true,
+    )
+        .unwrap();
+
+    // The output itself plus bookkeeping; a decoded frame would be 64 MiB.
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    assert!(peak < 2 * input.len() + 1024 * 1024, "peak {} bytes for a {} byte PNG", peak, input.len());
+    assert!(output.len() > input.len());
+}